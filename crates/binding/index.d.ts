@@ -1,22 +1,140 @@
 /* auto-generated by NAPI-RS */
 /* eslint-disable */
+export type CompilerType = 'client'|
+'server'|
+'edge-server';
+
+export interface NapiEdgeRuntimeOptions {
+  /**
+   * Node.js modules provided by the edge runtime, which stay external as `node:<module>`.
+   * Replaces the defaults: `buffer`, `events`, `assert`, `util` and `async_hooks`.
+   */
+  polyfilledModules?: Array<string>
+  /**
+   * Requests replaced with the given expression in edge bundles.
+   * Replaces the defaults, which stub `@builder.io/partytown` and `next/dist/compiled/etag` with `{}`.
+   */
+  stubs?: Record<string, string>
+  /** Fail the build, instead of warning, when a middleware or edge API route imports a Node.js module the edge runtime doesn't support. */
+  strictUnsupportedModules?: boolean
+}
+
 export interface NapiExperimentalConfig {
   esmExternals?: string | boolean
+  /** Packages resolved in loose ESM externals mode regardless of `esmExternals`, even when it is `false`, so ESM only packages can be required and stay external. */
+  looseEsmExternalsPackages?: Array<string>
+  /** Packages never resolved in loose ESM externals mode, even when `esmExternals` is `"loose"`. */
+  strictEsmExternalsPackages?: Array<string>
+}
+
+export interface NapiExternalsFallback {
+  /** Globs or RegExps matched like the `request` of rules. Only matching requests are passed to the callback. */
+  prefilter: Array<string | RegExp>
+  /** Resolves to an `external` value like the one of rules overriding the decision, or `undefined` to keep it. */
+  callback: (request: NapiExternalsFallbackRequest) => Promise<'bundle' | 'commonjs' | 'module' | (string & {}) | undefined>
+}
+
+/** A request passed to the `fallback` callback. */
+export interface NapiExternalsFallbackRequest {
+  context: string
+  request: string
+  /** The layer of the issuing module. */
+  layer?: string
+  /** The external chosen by the built-in externals, or `undefined` if they bundle the request. */
+  decision?: string
+}
+
+export interface NapiExternalsRule {
+  /** A glob, where `*` doesn't match `/` and `**` matches anything, or a RegExp tested against the request. */
+  request: string | RegExp
+  /** The layer of the issuing module, with `""` matching modules without a layer. */
+  issuerLayer?: string | RegExp
+  /** The compilers the rule applies to, all of them by default. */
+  compilerTypes?: CompilerType[]
+  /** Dependency categories such as `"esm"`, `"commonjs"` or `"url"`, all of them by default. */
+  dependencyTypes?: Array<string>
+  /** `"bundle"` to bundle the request, `"commonjs"` or `"module"` to keep it external with that type, or any other externals value used as is. */
+  external: 'bundle' | 'commonjs' | 'module' | (string & {})
 }
 
 export interface NapiNextConfigComplete {
   experimental: NapiExperimentalConfig
   bundlePagesRouterDependencies?: boolean
+  /** Packages never bundled on the server, on top of `defaultServerExternalPackages`. */
+  serverExternalPackages?: Array<string>
 }
 
 export interface NapiNextExternalsPluginOptions {
-  compilerType: string
+  compilerType: CompilerType
   config: NapiNextConfigComplete
+  /** Node.js builtin modules such as `require('module').builtinModules`, with or without the `node:` scheme. Subpaths are only recognized when listed, such as `fs/promises`, and `node:` scheme only modules such as `node:test` either way. */
   builtinModules: Array<string>
-  optOutBundlingPackageRegex: RegExp
+  /** Matched against resolved paths, for opt-outs that can't be expressed as package names. */
+  optOutBundlingPackageRegex?: RegExp
+  /** Packages never bundled on the server. Replaces the list built into Next.js. */
+  defaultServerExternalPackages?: Array<string>
   finalTranspilePackages: Array<string>
   dir: string
   defaultOverrides: Record<string, string>
+  /** The version of Next.js, e.g. `"14.2.3"`, selecting the rules of its major: 14 or 15, the default. */
+  nextVersion?: string
+  /** The layer groups of the Next.js release, defaulting to the ones of `nextVersion`. */
+  layers?: NapiWebpackLayers
+  /** Resolve packages with the Yarn Plug'n'Play manifest (`.pnp.cjs`) of the project, for projects without `node_modules` directories. */
+  pnp?: boolean
+  /** Directories packages are resolved from at runtime, such as `dir`, the workspace root or the root of a deployment. A package is only externalized if it resolves to the same file from one of them. Relative ones are relative to `dir`, defaults to `[dir]`. */
+  runtimeRoots?: Array<string>
+  /** Emit a `next-externals-trace-<compilerType>.json` asset recording the outcome of each request, and whether a user rule, the builtin modules, the built-in externals or the fallback decided it. */
+  trace?: boolean
+  /** Log the externals counters of `getNextExternalsStats` after each compilation, shown in the stats output. */
+  logStats?: boolean
+  /** Polyfills, stubs and diagnostics of the edge runtime, used when `compilerType` is `"edge-server"`. */
+  edgeRuntime?: NapiEdgeRuntimeOptions
+  /** Plugins created with the same id, `dir` and configuration, and applied to compilers with the same `resolve` options, share resolved packages and externals decisions, e.g. the server compilers of one build. Only server compilers benefit, as the client and edge compilers don't resolve externals. */
+  sharedContextId?: string
+  /** Evaluated in order before the built-in externals, without calling back into JavaScript. The first matching rule decides the request. */
+  rules?: Array<NapiExternalsRule>
+  /** Called for requests matching its prefilter that no rule matched, for logic that can't be expressed as rules. */
+  fallback?: NapiExternalsFallback
+}
+
+export interface NapiNextExternalsStats {
+  /** Calls of the externals function. */
+  calls: number
+  /** Calls per issuer layer, with `""` for modules without a layer. */
+  callsPerLayer: Record<string, number>
+  /** Calls per rule that decided the request, including cached decisions. */
+  decisionsPerRule: Record<string, number>
+  /** Requests passed to the resolver. */
+  resolverCalls: number
+  /** Requests bundled because they resolve to something else from the project `dir`. */
+  baseResolveMismatches: number
+  /** Requests passed to the `fallback` callback. */
+  fallbackCalls: number
+  totalTimeMs: number
+  /** Upper bound of the 99th percentile of the time spent per call, within 12.5%. */
+  p99TimeMs: number
+}
+
+export interface NapiWebpackLayerGroups {
+  bundled?: Array<string>
+  serverOnly?: Array<string>
+}
+
+/** The layer groups of Next.js, given directly or as its `WEBPACK_LAYERS` object. */
+export interface NapiWebpackLayers {
+  /** Layers of the app directory, whose React packages are bundled. */
+  bundled?: Array<string>
+  /** Layers resolved with the `react-server` condition. Each of them has to be a bundled layer. */
+  serverOnly?: Array<string>
+  /** The groups of `WEBPACK_LAYERS`, used for the groups not given directly. */
+  GROUP?: NapiWebpackLayerGroups
 }
 
+/**
+ * Returns the externals counters of the compiler with the given name, or the
+ * compiler type if it has no name, accumulated since its plugin was created.
+ */
+export declare function getNextExternalsStats(compilerName: string): NapiNextExternalsStats | null
+
 export declare function registerNextExternalsPlugin(): void
@@ -393,4 +393,5 @@ if (!nativeBinding) {
 }
 
 module.exports = nativeBinding
+module.exports.getNextExternalsStats = nativeBinding.getNextExternalsStats
 module.exports.registerNextExternalsPlugin = nativeBinding.registerNextExternalsPlugin
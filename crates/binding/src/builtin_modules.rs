@@ -0,0 +1,88 @@
+//! Classifies requests of Node.js builtin modules as listed by
+//! `builtinModules`, with or without the `node:` scheme: `fs`, `node:fs` and
+//! `node:fs/promises` are all builtins given `fs` and `fs/promises`, while
+//! modules listed with the scheme, or such as `node:test`, only exist with it.
+
+use std::sync::Arc;
+
+use rspack_core::{ExternalItem, ExternalItemFnResult, ExternalItemValue};
+use rustc_hash::FxHashSet;
+
+const NODE_SCHEME: &str = "node:";
+
+/// Modules that can only be loaded with the `node:` scheme, as their bare
+/// names belong to npm packages. Newer Node.js versions list them in
+/// `builtinModules` with the scheme.
+const NODE_SCHEME_ONLY_MODULES: &[&str] = &["sea", "sqlite", "test", "test/reporters"];
+
+#[derive(Debug, Default)]
+pub struct BuiltinModules {
+  modules: Vec<String>,
+  names: FxHashSet<String>,
+  scheme_only_names: FxHashSet<String>,
+}
+
+impl BuiltinModules {
+  pub fn new(modules: Vec<String>) -> Self {
+    let mut names = FxHashSet::default();
+    let mut scheme_only_names = NODE_SCHEME_ONLY_MODULES
+      .iter()
+      .map(|module| module.to_string())
+      .collect::<FxHashSet<_>>();
+    for module in &modules {
+      match module.strip_prefix(NODE_SCHEME) {
+        Some(name) => scheme_only_names.insert(name.to_string()),
+        None => names.insert(module.clone()),
+      };
+    }
+    Self {
+      modules,
+      names,
+      scheme_only_names,
+    }
+  }
+
+  /// The modules as given, for fingerprinting.
+  pub fn as_slice(&self) -> &[String] {
+    &self.modules
+  }
+
+  /// Returns the name of the builtin module a request loads without the
+  /// `node:` scheme, e.g. `fs/promises` for `node:fs/promises`, or `None` if
+  /// it isn't a builtin module. Only the listed subpaths are builtins, as
+  /// Node.js loads other ones, such as `buffer/` or `util/`, from
+  /// `node_modules`.
+  pub fn canonical_name<'a>(&self, request: &'a str) -> Option<&'a str> {
+    let (name, has_scheme) = match request.strip_prefix(NODE_SCHEME) {
+      Some(name) => (name, true),
+      None => (request, false),
+    };
+    let is_builtin =
+      self.names.contains(name) || (has_scheme && self.scheme_only_names.contains(name));
+    is_builtin.then_some(name)
+  }
+
+  pub fn is_builtin(&self, request: &str) -> bool {
+    self.canonical_name(request).is_some()
+  }
+
+  /// Returns an externals function keeping builtin requests external as
+  /// written, so `node:` scheme only modules keep their scheme.
+  pub fn external_item(self: Arc<Self>) -> ExternalItem {
+    let builtin_modules = self;
+    ExternalItem::Fn(Box::new(move |ctx| {
+      let result = builtin_modules
+        .is_builtin(&ctx.request)
+        .then_some(ExternalItemValue::Bool(true));
+      Box::pin(async move {
+        Ok(ExternalItemFnResult {
+          external_type: None,
+          result,
+        })
+      })
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests;
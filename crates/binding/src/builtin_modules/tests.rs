@@ -0,0 +1,88 @@
+use super::*;
+
+fn builtin_modules(modules: &[&str]) -> BuiltinModules {
+  BuiltinModules::new(modules.iter().map(|module| module.to_string()).collect())
+}
+
+#[test]
+fn node_scheme_is_optional() {
+  for modules in [&["fs", "buffer"], &["node:fs", "node:buffer"]] {
+    let builtin_modules = builtin_modules(modules);
+    for (request, expected) in [
+      ("fs", Some("fs")),
+      ("node:fs", Some("fs")),
+      ("node:buffer", Some("buffer")),
+      ("fs-extra", None),
+      ("node:fs-extra", None),
+    ] {
+      // A `node:` prefixed entry is only a builtin with the scheme.
+      let expected = expected.filter(|_| modules[0] == "fs" || request.starts_with("node:"));
+      assert_eq!(
+        builtin_modules.canonical_name(request),
+        expected,
+        "{request} with {modules:?}"
+      );
+    }
+  }
+}
+
+#[test]
+fn subpaths() {
+  let builtin_modules = builtin_modules(&[
+    "fs",
+    "fs/promises",
+    "path",
+    "path/posix",
+    "stream",
+    "stream/web",
+  ]);
+  assert_eq!(
+    builtin_modules.canonical_name("fs/promises"),
+    Some("fs/promises")
+  );
+  assert_eq!(
+    builtin_modules.canonical_name("node:path/posix"),
+    Some("path/posix")
+  );
+  assert_eq!(
+    builtin_modules.canonical_name("stream/web"),
+    Some("stream/web")
+  );
+  assert_eq!(builtin_modules.canonical_name("streams/web"), None);
+  assert_eq!(builtin_modules.canonical_name("fs/unlisted"), None);
+  assert_eq!(builtin_modules.canonical_name("node:path/unlisted"), None);
+}
+
+#[test]
+fn trailing_slash_loads_packages() {
+  // `require('buffer/')` skips the builtin for the npm package of that name,
+  // which is how polyfills such as `buffer` and `util` are imported.
+  let builtin_modules = builtin_modules(&["buffer", "events", "punycode", "util"]);
+  for request in ["buffer/", "events/", "punycode/", "util/", "node:util/"] {
+    assert_eq!(builtin_modules.canonical_name(request), None, "{request}");
+  }
+  assert_eq!(builtin_modules.canonical_name("buffer"), Some("buffer"));
+  assert_eq!(builtin_modules.canonical_name("util"), Some("util"));
+}
+
+#[test]
+fn node_scheme_only_modules() {
+  let builtin_modules = builtin_modules(&["fs", "node:custom"]);
+  for (request, expected) in [
+    ("node:test", Some("test")),
+    ("node:test/reporters", Some("test/reporters")),
+    ("node:sqlite", Some("sqlite")),
+    ("node:sea", Some("sea")),
+    ("node:custom", Some("custom")),
+    // Packages published to npm under the same names.
+    ("test", None),
+    ("sqlite", None),
+    ("custom", None),
+  ] {
+    assert_eq!(
+      builtin_modules.canonical_name(request),
+      expected,
+      "{request}"
+    );
+  }
+}
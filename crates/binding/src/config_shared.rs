@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub enum EsmExternalsConfig {
   #[default]
   None,
@@ -6,13 +6,25 @@ pub enum EsmExternalsConfig {
   Strict,
 }
 
-#[derive(Debug, Clone, Default)]
+impl EsmExternalsConfig {
+  /// The values accepted for `experimental.esmExternals`.
+  pub const VALID_VALUES: &[&str] = &["true", "false", "\"loose\""];
+}
+
+#[derive(Debug, Clone, Default, Hash)]
 pub struct ExperimentalConfig {
   pub esm_externals: EsmExternalsConfig,
+  /// Packages resolved in loose ESM externals mode regardless of `esm_externals`,
+  /// even when ESM externals are disabled.
+  pub loose_esm_externals_packages: Vec<String>,
+  /// Packages never resolved in loose ESM externals mode, even when `esm_externals` is loose.
+  pub strict_esm_externals_packages: Vec<String>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Hash)]
 pub struct NextConfigComplete {
   pub experimental: ExperimentalConfig,
   pub bundle_pages_router_dependencies: Option<bool>,
+  /// Packages never bundled on the server, on top of the default list.
+  pub server_external_packages: Vec<String>,
 }
@@ -0,0 +1,152 @@
+use std::{
+  fmt::{self, Display},
+  fs,
+  path::Path,
+};
+
+use regex::Regex;
+use rspack_error::miette::{self, LabeledSpan, NamedSource, SourceCode, SourceSpan};
+use serde_json::Value;
+
+/// Raised when a CommonJS request resolves to an ES module package outside of
+/// loose ESM externals mode, as such a package can only be imported.
+///
+/// `handle_externals` only knows the request and where it resolved to. The
+/// externals function adds the issuer and the package details, which are
+/// read from the file system, before the error is reported.
+#[derive(Debug)]
+pub struct EsmExternalsError {
+  request: String,
+  resolved: String,
+  issuer: Option<String>,
+  require_call: Option<RequireCall>,
+  package_json: Option<PackageJson>,
+}
+
+/// The source of the module making the request, with the span of the
+/// `require` call.
+#[derive(Debug)]
+struct RequireCall {
+  source: NamedSource<String>,
+  span: SourceSpan,
+}
+
+/// The nearest `package.json` of the resolved file, which decides whether a
+/// `.js` file is an ES module.
+#[derive(Debug)]
+struct PackageJson {
+  path: String,
+  r#type: Option<Value>,
+  exports: Option<Value>,
+}
+
+impl EsmExternalsError {
+  pub fn new(request: String, resolved: String) -> Self {
+    Self {
+      request,
+      resolved,
+      issuer: None,
+      require_call: None,
+      package_json: None,
+    }
+  }
+
+  /// Adds the module making the request, with a code frame of the `require`
+  /// call if it can be found in the file.
+  pub fn with_issuer(mut self, issuer: &str) -> Self {
+    if issuer.is_empty() {
+      return self;
+    }
+    self.require_call = fs::read_to_string(issuer).ok().and_then(|source| {
+      let span = find_require_call(&source, &self.request)?;
+      Some(RequireCall {
+        source: NamedSource::new(issuer, source),
+        span,
+      })
+    });
+    self.issuer = Some(issuer.to_string());
+    self
+  }
+
+  /// Adds the `type` and `exports` fields of the nearest `package.json` of the
+  /// resolved file.
+  pub fn with_package_json(mut self) -> Self {
+    self.package_json = Path::new(&self.resolved)
+      .ancestors()
+      .skip(1)
+      .map(|dir| dir.join("package.json"))
+      .find_map(|path| {
+        let package_json: Value = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+        Some(PackageJson {
+          path: path.to_string_lossy().into_owned(),
+          r#type: package_json.get("type").cloned(),
+          exports: package_json.get("exports").cloned(),
+        })
+      });
+    self
+  }
+}
+
+fn find_require_call(source: &str, request: &str) -> Option<SourceSpan> {
+  let pattern = format!(
+    r#"require\s*\(\s*(?:'{request}'|"{request}"|`{request}`)\s*\)"#,
+    request = regex::escape(request)
+  );
+  let found = Regex::new(&pattern).ok()?.find(source)?;
+  Some(SourceSpan::new(found.start().into(), found.len()))
+}
+
+impl Display for EsmExternalsError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "ESM packages ({}) need to be imported. Use 'import' to reference the package instead. https://nextjs.org/docs/messages/import-esm-externals",
+      self.request
+    )?;
+    writeln!(f)?;
+    if let Some(issuer) = &self.issuer {
+      write!(f, "\nRequired by: {issuer}")?;
+    }
+    write!(f, "\nResolved to: {}", self.resolved)?;
+    if let Some(package_json) = &self.package_json {
+      write!(f, "\nPackage:     {}", package_json.path)?;
+      if let Some(r#type) = &package_json.r#type {
+        write!(f, "\n  \"type\": {type}")?;
+      }
+      if let Some(exports) = &package_json.exports {
+        write!(f, "\n  \"exports\": {exports}")?;
+      }
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for EsmExternalsError {}
+
+impl miette::Diagnostic for EsmExternalsError {
+  fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+    Some(Box::new("NextExternalsPlugin"))
+  }
+
+  fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+    Some(Box::new(format!(
+      "Load the package with a dynamic `import(\"{}\")` instead, or set `experimental.esmExternals` to \"loose\" to allow requiring ESM packages.",
+      self.request
+    )))
+  }
+
+  fn source_code(&self) -> Option<&dyn SourceCode> {
+    self
+      .require_call
+      .as_ref()
+      .map(|require_call| &require_call.source as &dyn SourceCode)
+  }
+
+  fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+    let require_call = self.require_call.as_ref()?;
+    Some(Box::new(std::iter::once(LabeledSpan::new_with_span(
+      Some("required here".to_string()),
+      require_call.span,
+    ))))
+  }
+}
@@ -0,0 +1,136 @@
+//! A JavaScript callback for externals logic that can't be expressed as
+//! rules. It is only called for requests matching its prefilter, so few
+//! requests cross into JavaScript.
+
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+
+use rspack_core::{ExternalItem, ExternalItemFnResult, ExternalItemValue};
+
+use crate::{
+  externals_rules::{RequestMatcher, RuleExternal},
+  externals_stats::ExternalsStats,
+};
+
+/// A request left undecided by the rules, along with the built-in decision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalsFallbackRequest {
+  pub context: String,
+  pub request: String,
+  pub layer: Option<String>,
+  /// The external chosen by the built-in logic, `None` if it bundles the
+  /// request.
+  pub decision: Option<String>,
+}
+
+/// Returns the external overriding the built-in decision, or `None` to keep
+/// it.
+pub type ExternalsFallbackFn = Arc<
+  dyn Fn(
+      ExternalsFallbackRequest,
+    )
+      -> Pin<Box<dyn Future<Output = rspack_error::Result<Option<RuleExternal>>> + Send + 'static>>
+    + Send
+    + Sync
+    + 'static,
+>;
+
+pub struct ExternalsFallback {
+  prefilter: Vec<RequestMatcher>,
+  callback: ExternalsFallbackFn,
+}
+
+impl fmt::Debug for ExternalsFallback {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ExternalsFallback")
+      .field("prefilter", &self.prefilter)
+      .finish_non_exhaustive()
+  }
+}
+
+impl ExternalsFallback {
+  pub fn new(prefilter: Vec<RequestMatcher>, callback: ExternalsFallbackFn) -> Self {
+    Self {
+      prefilter,
+      callback,
+    }
+  }
+
+  /// Returns the external of a request, calling back to override the
+  /// built-in `decision` if the request matches the prefilter.
+  pub async fn decide(
+    &self,
+    context: &str,
+    request: &str,
+    layer: Option<&str>,
+    decision: Option<String>,
+    stats: &ExternalsStats,
+  ) -> rspack_error::Result<Option<ExternalItemValue>> {
+    Ok(
+      match self
+        .override_decision(context, request, layer, decision.as_deref(), stats)
+        .await?
+      {
+        Some(external) => Some(external),
+        None => decision.map(ExternalItemValue::String),
+      },
+    )
+  }
+
+  /// Returns the external overriding the built-in `decision`, or `None` if
+  /// the request doesn't match the prefilter or the callback keeps it.
+  pub async fn override_decision(
+    &self,
+    context: &str,
+    request: &str,
+    layer: Option<&str>,
+    decision: Option<&str>,
+    stats: &ExternalsStats,
+  ) -> rspack_error::Result<Option<ExternalItemValue>> {
+    if !self
+      .prefilter
+      .iter()
+      .any(|matcher| matcher.is_match(request))
+    {
+      return Ok(None);
+    }
+
+    stats.record_fallback_call();
+    let external = (self.callback)(ExternalsFallbackRequest {
+      context: context.to_string(),
+      request: request.to_string(),
+      layer: layer.map(str::to_string),
+      decision: decision.map(str::to_string),
+    })
+    .await?;
+    Ok(external.map(|external| external.to_value(request)))
+  }
+
+  /// Returns an externals function for compilers whose built-in externals
+  /// are not decided by a function, to be placed after them so it only sees
+  /// the requests they bundle.
+  pub fn external_item(self: Arc<Self>, stats: Arc<ExternalsStats>) -> ExternalItem {
+    let fallback = self;
+    ExternalItem::Fn(Box::new(move |ctx| {
+      let fallback = fallback.clone();
+      let stats = stats.clone();
+      Box::pin(async move {
+        let result = fallback
+          .decide(
+            &ctx.context,
+            &ctx.request,
+            ctx.context_info.issuer_layer.as_deref(),
+            None,
+            &stats,
+          )
+          .await?;
+        Ok(ExternalItemFnResult {
+          external_type: None,
+          result,
+        })
+      })
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,121 @@
+use std::sync::Mutex;
+
+use super::*;
+
+/// A fallback recording its requests, and externalizing `override-me` with
+/// a custom external.
+fn recording_fallback() -> (ExternalsFallback, Arc<Mutex<Vec<ExternalsFallbackRequest>>>) {
+  let requests = Arc::new(Mutex::new(vec![]));
+  let recorded = requests.clone();
+  let fallback = ExternalsFallback::new(
+    vec![
+      RequestMatcher::glob("@company/**"),
+      RequestMatcher::glob("override-me"),
+    ],
+    Arc::new(move |request| {
+      let external = match request.request.as_str() {
+        "override-me" => Some(RuleExternal::Custom("commonjs overridden".to_string())),
+        "@company/bundled" => Some(RuleExternal::Bundle),
+        _ => None,
+      };
+      recorded.lock().unwrap().push(request);
+      Box::pin(async move { Ok(external) })
+    }),
+  );
+  (fallback, requests)
+}
+
+fn external(value: Option<ExternalItemValue>) -> Option<String> {
+  match value {
+    Some(ExternalItemValue::String(external)) => Some(external),
+    Some(ExternalItemValue::Bool(false)) => Some("bundle".to_string()),
+    None => None,
+    Some(value) => panic!("unexpected external {value:?}"),
+  }
+}
+
+#[tokio::test]
+async fn only_prefiltered_requests_are_passed() {
+  let (fallback, requests) = recording_fallback();
+  let stats = ExternalsStats::default();
+
+  let result = fallback
+    .decide(
+      "/app",
+      "react",
+      None,
+      Some("commonjs react".to_string()),
+      &stats,
+    )
+    .await
+    .unwrap();
+  assert_eq!(external(result), Some("commonjs react".to_string()));
+  let result = fallback
+    .decide("/app", "lodash", None, None, &stats)
+    .await
+    .unwrap();
+  assert_eq!(external(result), None);
+  assert!(requests.lock().unwrap().is_empty());
+  assert_eq!(stats.snapshot().fallback_calls, 0);
+
+  let result = fallback
+    .decide(
+      "/app/pages",
+      "@company/ui/button",
+      Some("rsc"),
+      Some("commonjs @company/ui/button".to_string()),
+      &stats,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    external(result),
+    Some("commonjs @company/ui/button".to_string())
+  );
+  assert_eq!(
+    *requests.lock().unwrap(),
+    [ExternalsFallbackRequest {
+      context: "/app/pages".to_string(),
+      request: "@company/ui/button".to_string(),
+      layer: Some("rsc".to_string()),
+      decision: Some("commonjs @company/ui/button".to_string()),
+    }]
+  );
+  assert_eq!(stats.snapshot().fallback_calls, 1);
+}
+
+#[tokio::test]
+async fn callback_overrides_the_decision() {
+  let (fallback, _) = recording_fallback();
+  let stats = ExternalsStats::default();
+
+  let result = fallback
+    .decide("/app", "override-me", None, None, &stats)
+    .await
+    .unwrap();
+  assert_eq!(external(result), Some("commonjs overridden".to_string()));
+  let result = fallback
+    .decide(
+      "/app",
+      "@company/bundled",
+      None,
+      Some("commonjs @company/bundled".to_string()),
+      &stats,
+    )
+    .await
+    .unwrap();
+  assert_eq!(external(result), Some("bundle".to_string()));
+}
+
+#[tokio::test]
+async fn callback_errors_fail_the_request() {
+  let fallback = ExternalsFallback::new(
+    vec![RequestMatcher::glob("*")],
+    Arc::new(|_| Box::pin(async { Err(rspack_error::error!("callback failed")) })),
+  );
+  let error = fallback
+    .decide("/app", "pkg", None, None, &ExternalsStats::default())
+    .await
+    .unwrap_err();
+  assert!(error.to_string().contains("callback failed"), "{error}");
+}
@@ -0,0 +1,193 @@
+//! Declarative externals rules from the plugin options, evaluated before the
+//! built-in externals logic so projects don't need a JavaScript `externals`
+//! function for their own packages.
+
+use std::{sync::Arc, time::Instant};
+
+use regex::Regex;
+use rspack_core::{ExternalItem, ExternalItemFnResult, ExternalItemValue};
+use rspack_regex::RspackRegex;
+
+use crate::{externals_stats::ExternalsStats, next_externals_plugin::CompilerType};
+
+/// The rule of requests decided by a user rule in stats.
+pub const USER_RULE: &str = "user-rule";
+
+/// Matches a request by glob, where `*` and `?` don't match `/` and `**`
+/// matches anything, or by regular expression.
+#[derive(Debug, Clone)]
+pub enum RequestMatcher {
+  Glob(Regex),
+  Regex(RspackRegex),
+}
+
+impl RequestMatcher {
+  pub fn glob(glob: &str) -> Self {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+      match c {
+        '*' if chars.peek() == Some(&'*') => {
+          chars.next();
+          pattern.push_str(".*");
+        }
+        '*' => pattern.push_str("[^/]*"),
+        '?' => pattern.push_str("[^/]"),
+        c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+      }
+    }
+    pattern.push('$');
+    Self::Glob(Regex::new(&pattern).expect("escaped globs are valid regexes"))
+  }
+
+  pub(crate) fn is_match(&self, request: &str) -> bool {
+    match self {
+      Self::Glob(regex) => regex.is_match(request),
+      Self::Regex(regex) => regex.test(request),
+    }
+  }
+}
+
+/// Matches the layer of the issuer by name, where `""` matches modules
+/// without a layer, or by regular expression.
+#[derive(Debug, Clone)]
+pub enum LayerMatcher {
+  Name(String),
+  Regex(RspackRegex),
+}
+
+impl LayerMatcher {
+  fn is_match(&self, layer: Option<&str>) -> bool {
+    match self {
+      Self::Name(name) => layer.unwrap_or_default() == name,
+      Self::Regex(regex) => layer.is_some_and(|layer| regex.test(layer)),
+    }
+  }
+}
+
+/// What a matching rule does with a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleExternal {
+  /// Bundle the request, skipping the built-in externals.
+  Bundle,
+  /// `require()` the request at runtime.
+  Commonjs,
+  /// `import()` the request at runtime.
+  Module,
+  /// Used verbatim as the external, e.g. `"commonjs @company/ui/server"`.
+  Custom(String),
+}
+
+impl RuleExternal {
+  pub fn parse(value: String) -> Self {
+    match value.as_str() {
+      "bundle" => Self::Bundle,
+      "commonjs" => Self::Commonjs,
+      "module" => Self::Module,
+      _ => Self::Custom(value),
+    }
+  }
+
+  pub(crate) fn to_value(&self, request: &str) -> ExternalItemValue {
+    match self {
+      // Stops the externals plugin at this item without creating a module.
+      Self::Bundle => ExternalItemValue::Bool(false),
+      Self::Commonjs => ExternalItemValue::String(format!("commonjs {request}")),
+      Self::Module => ExternalItemValue::String(format!("module {request}")),
+      Self::Custom(external) => ExternalItemValue::String(external.clone()),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExternalsRule {
+  pub request: RequestMatcher,
+  pub issuer_layer: Option<LayerMatcher>,
+  /// The compilers the rule applies to, all of them if `None`.
+  pub compiler_types: Option<Vec<CompilerType>>,
+  /// Dependency categories such as `"esm"`, `"commonjs"` or `"url"`.
+  pub dependency_types: Option<Vec<String>>,
+  pub external: RuleExternal,
+}
+
+impl ExternalsRule {
+  fn is_match(&self, request: &str, dependency_type: &str, layer: Option<&str>) -> bool {
+    self.request.is_match(request)
+      && self
+        .issuer_layer
+        .as_ref()
+        .is_none_or(|issuer_layer| issuer_layer.is_match(layer))
+      && self
+        .dependency_types
+        .as_ref()
+        .is_none_or(|dependency_types| dependency_types.iter().any(|t| t == dependency_type))
+  }
+}
+
+/// The rules of one compiler, where the first matching rule wins.
+#[derive(Debug, Default)]
+pub struct ExternalsRules {
+  rules: Vec<ExternalsRule>,
+}
+
+impl ExternalsRules {
+  pub fn new(rules: &[ExternalsRule], compiler_type: CompilerType) -> Self {
+    Self {
+      rules: rules
+        .iter()
+        .filter(|rule| {
+          rule
+            .compiler_types
+            .as_ref()
+            .is_none_or(|compiler_types| compiler_types.contains(&compiler_type))
+        })
+        .cloned()
+        .collect(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.rules.is_empty()
+  }
+
+  pub fn evaluate(
+    &self,
+    request: &str,
+    dependency_type: &str,
+    layer: Option<&str>,
+  ) -> Option<&RuleExternal> {
+    self
+      .rules
+      .iter()
+      .find(|rule| rule.is_match(request, dependency_type, layer))
+      .map(|rule| &rule.external)
+  }
+
+  /// Returns an externals function evaluating the rules, to be placed before
+  /// the built-in externals.
+  pub fn external_item(self: Arc<Self>, stats: Arc<ExternalsStats>) -> ExternalItem {
+    let rules = self;
+    ExternalItem::Fn(Box::new(move |ctx| {
+      let rules = rules.clone();
+      let stats = stats.clone();
+      Box::pin(async move {
+        let start = Instant::now();
+        let layer = ctx.context_info.issuer_layer.as_deref();
+        let result = rules
+          .evaluate(&ctx.request, &ctx.dependency_type, layer)
+          .map(|external| external.to_value(&ctx.request));
+        // Unmatched requests are counted by the built-in externals.
+        if result.is_some() {
+          stats.record_call(layer, USER_RULE, start.elapsed());
+        }
+        Ok(ExternalItemFnResult {
+          external_type: None,
+          result,
+        })
+      })
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests;
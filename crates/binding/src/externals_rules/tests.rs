@@ -0,0 +1,119 @@
+use super::*;
+
+fn rule(request: RequestMatcher, external: &str) -> ExternalsRule {
+  ExternalsRule {
+    request,
+    issuer_layer: None,
+    compiler_types: None,
+    dependency_types: None,
+    external: RuleExternal::parse(external.to_string()),
+  }
+}
+
+#[test]
+fn globs() {
+  let scoped = RequestMatcher::glob("@company/*");
+  assert!(scoped.is_match("@company/ui"));
+  assert!(!scoped.is_match("@company/ui/server"));
+  assert!(!scoped.is_match("@company"));
+
+  let deep = RequestMatcher::glob("@company/**");
+  assert!(deep.is_match("@company/ui/server"));
+  assert!(!deep.is_match("@company-ui"));
+
+  let single = RequestMatcher::glob("lib?.js");
+  assert!(single.is_match("lib1.js"));
+  assert!(!single.is_match("lib/.js"));
+  assert!(!single.is_match("lib1xjs"));
+}
+
+#[test]
+fn first_matching_rule_wins() {
+  let rules = ExternalsRules::new(
+    &[
+      rule(RequestMatcher::glob("@company/ui"), "bundle"),
+      rule(RequestMatcher::glob("@company/*"), "commonjs"),
+      rule(
+        RequestMatcher::Regex(RspackRegex::new("^legacy-").unwrap()),
+        "var Legacy",
+      ),
+    ],
+    CompilerType::Server,
+  );
+  assert_eq!(
+    rules.evaluate("@company/ui", "esm", None),
+    Some(&RuleExternal::Bundle)
+  );
+  assert_eq!(
+    rules.evaluate("@company/db", "esm", None),
+    Some(&RuleExternal::Commonjs)
+  );
+  assert_eq!(
+    rules.evaluate("legacy-sdk", "esm", None),
+    Some(&RuleExternal::Custom("var Legacy".to_string()))
+  );
+  assert_eq!(rules.evaluate("react", "esm", None), None);
+}
+
+#[test]
+fn conditions() {
+  let rules = ExternalsRules::new(
+    &[
+      ExternalsRule {
+        issuer_layer: Some(LayerMatcher::Name("rsc".to_string())),
+        ..rule(RequestMatcher::glob("in-rsc"), "module")
+      },
+      ExternalsRule {
+        issuer_layer: Some(LayerMatcher::Name(String::new())),
+        ..rule(RequestMatcher::glob("no-layer"), "commonjs")
+      },
+      ExternalsRule {
+        issuer_layer: Some(LayerMatcher::Regex(RspackRegex::new("^app-").unwrap())),
+        ..rule(RequestMatcher::glob("app-layers"), "commonjs")
+      },
+      ExternalsRule {
+        dependency_types: Some(vec!["commonjs".to_string()]),
+        ..rule(RequestMatcher::glob("required"), "commonjs")
+      },
+      ExternalsRule {
+        compiler_types: Some(vec![CompilerType::EdgeServer]),
+        ..rule(RequestMatcher::glob("edge-only"), "commonjs")
+      },
+    ],
+    CompilerType::Server,
+  );
+  assert!(rules.evaluate("in-rsc", "esm", Some("rsc")).is_some());
+  assert!(rules.evaluate("in-rsc", "esm", Some("ssr")).is_none());
+  assert!(rules.evaluate("in-rsc", "esm", None).is_none());
+
+  assert!(rules.evaluate("no-layer", "esm", None).is_some());
+  assert!(rules.evaluate("no-layer", "esm", Some("rsc")).is_none());
+
+  assert!(rules
+    .evaluate("app-layers", "esm", Some("app-pages-browser"))
+    .is_some());
+  assert!(rules.evaluate("app-layers", "esm", None).is_none());
+
+  assert!(rules.evaluate("required", "commonjs", None).is_some());
+  assert!(rules.evaluate("required", "esm", None).is_none());
+
+  assert!(rules.evaluate("edge-only", "esm", None).is_none());
+}
+
+#[test]
+fn externals_values() {
+  assert!(matches!(
+    RuleExternal::Bundle.to_value("pkg"),
+    ExternalItemValue::Bool(false)
+  ));
+  for (external, expected) in [
+    (RuleExternal::Commonjs, "commonjs pkg"),
+    (RuleExternal::Module, "module pkg"),
+    (RuleExternal::Custom("root Pkg".to_string()), "root Pkg"),
+  ] {
+    assert!(
+      matches!(&external.to_value("pkg"), ExternalItemValue::String(value) if value == expected),
+      "{external:?}"
+    );
+  }
+}
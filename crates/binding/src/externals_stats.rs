@@ -0,0 +1,192 @@
+//! Counters of the work done by the externals functions, so their cost can be
+//! measured from JavaScript with `getNextExternalsStats`.
+
+use std::{
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, LazyLock, Mutex, PoisonError, Weak,
+  },
+  time::Duration,
+};
+
+use dashmap::DashMap;
+use rustc_hash::{FxBuildHasher, FxHashMap};
+
+/// Durations below `2^SUB_BUCKET_BITS` nanoseconds get a bucket each. Longer
+/// ones are bucketed by their highest set bit, each power of two split into
+/// `2^SUB_BUCKET_BITS` linear buckets, which bounds the error to 12.5%.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (u64::BITS - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS;
+
+/// A histogram of durations in nanoseconds, for percentiles without keeping
+/// every sample.
+#[derive(Debug)]
+struct DurationHistogram {
+  buckets: Box<[AtomicU64]>,
+  total_nanos: AtomicU64,
+}
+
+impl Default for DurationHistogram {
+  fn default() -> Self {
+    Self {
+      buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+      total_nanos: AtomicU64::new(0),
+    }
+  }
+}
+
+impl DurationHistogram {
+  fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+      return nanos as usize;
+    }
+    let exponent = u64::BITS - 1 - nanos.leading_zeros();
+    let sub_bucket = (nanos >> (exponent - SUB_BUCKET_BITS)) as usize - SUB_BUCKETS;
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub_bucket
+  }
+
+  /// The largest duration that falls into a bucket.
+  fn bucket_max(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+      return bucket as u64;
+    }
+    let exponent = (bucket / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub_bucket = (bucket % SUB_BUCKETS) as u64;
+    let width = 1u64 << (exponent - SUB_BUCKET_BITS);
+    ((SUB_BUCKETS as u64 + sub_bucket) * width).saturating_add(width - 1)
+  }
+
+  fn record(&self, elapsed: Duration) {
+    let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+    self.buckets[Self::bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+    self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+  }
+
+  fn total(&self) -> Duration {
+    Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed))
+  }
+
+  /// Returns the upper bound of the bucket holding the given percentile.
+  fn percentile(&self, percentile: f64) -> Duration {
+    let counts = self
+      .buckets
+      .iter()
+      .map(|bucket| bucket.load(Ordering::Relaxed))
+      .collect::<Vec<_>>();
+    let total = counts.iter().sum::<u64>();
+    if total == 0 {
+      return Duration::ZERO;
+    }
+    let rank = ((total as f64 * percentile / 100.0).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (bucket, count) in counts.into_iter().enumerate() {
+      seen += count;
+      if seen >= rank {
+        return Duration::from_nanos(Self::bucket_max(bucket));
+      }
+    }
+    unreachable!("the rank is at most the number of samples")
+  }
+}
+
+/// The counters of one plugin, updated by the externals functions.
+#[derive(Debug, Default)]
+pub struct ExternalsStats {
+  calls_per_layer: DashMap<Option<String>, u64, FxBuildHasher>,
+  decisions_per_rule: DashMap<&'static str, u64, FxBuildHasher>,
+  resolver_calls: AtomicU64,
+  base_resolve_mismatches: AtomicU64,
+  fallback_calls: AtomicU64,
+  time: DurationHistogram,
+}
+
+impl ExternalsStats {
+  /// Records a call of an externals function, with the rule that decided it.
+  pub fn record_call(&self, layer: Option<&str>, rule: &'static str, elapsed: Duration) {
+    *self
+      .calls_per_layer
+      .entry(layer.map(str::to_string))
+      .or_default() += 1;
+    *self.decisions_per_rule.entry(rule).or_default() += 1;
+    self.time.record(elapsed);
+  }
+
+  pub fn record_resolver_call(&self) {
+    self.resolver_calls.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_fallback_call(&self) {
+    self.fallback_calls.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Records requests that resolved differently from the project `dir`.
+  pub fn record_base_resolve_mismatches(&self, mismatches: u32) {
+    self
+      .base_resolve_mismatches
+      .fetch_add(mismatches.into(), Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> ExternalsStatsSnapshot {
+    let calls_per_layer = self
+      .calls_per_layer
+      .iter()
+      .map(|entry| (entry.key().clone(), *entry.value()))
+      .collect::<FxHashMap<_, _>>();
+    ExternalsStatsSnapshot {
+      calls: calls_per_layer.values().sum(),
+      calls_per_layer,
+      decisions_per_rule: self
+        .decisions_per_rule
+        .iter()
+        .map(|entry| (*entry.key(), *entry.value()))
+        .collect(),
+      resolver_calls: self.resolver_calls.load(Ordering::Relaxed),
+      base_resolve_mismatches: self.base_resolve_mismatches.load(Ordering::Relaxed),
+      fallback_calls: self.fallback_calls.load(Ordering::Relaxed),
+      total_time: self.time.total(),
+      p99_time: self.time.percentile(99.0),
+    }
+  }
+}
+
+/// The counters of a plugin at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalsStatsSnapshot {
+  pub calls: u64,
+  /// Calls per issuer layer, `None` for modules without a layer.
+  pub calls_per_layer: FxHashMap<Option<String>, u64>,
+  pub decisions_per_rule: FxHashMap<&'static str, u64>,
+  pub resolver_calls: u64,
+  pub base_resolve_mismatches: u64,
+  /// Requests passed to the JavaScript fallback callback.
+  pub fallback_calls: u64,
+  pub total_time: Duration,
+  pub p99_time: Duration,
+}
+
+/// The counters of the plugins in this process by compiler name. A plugin
+/// created for a compiler replaces the previous one of the same name.
+static EXTERNALS_STATS: LazyLock<Mutex<FxHashMap<String, Weak<ExternalsStats>>>> =
+  LazyLock::new(Default::default);
+
+pub fn register_externals_stats(compiler_name: &str, stats: &Arc<ExternalsStats>) {
+  let mut registry = EXTERNALS_STATS
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner);
+  registry.retain(|_, stats| stats.strong_count() != 0);
+  registry.insert(compiler_name.to_string(), Arc::downgrade(stats));
+}
+
+/// Returns the counters of the plugin of the named compiler, if it is alive.
+pub fn get_externals_stats(compiler_name: &str) -> Option<ExternalsStatsSnapshot> {
+  EXTERNALS_STATS
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner)
+    .get(compiler_name)
+    .and_then(Weak::upgrade)
+    .map(|stats| stats.snapshot())
+}
+
+#[cfg(test)]
+mod tests;
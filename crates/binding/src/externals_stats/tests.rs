@@ -0,0 +1,82 @@
+use super::*;
+
+#[test]
+fn histogram_buckets_are_contiguous() {
+  for bucket in 0..BUCKETS - 1 {
+    let max = DurationHistogram::bucket_max(bucket);
+    assert_eq!(DurationHistogram::bucket(max), bucket, "{bucket}");
+    assert_eq!(DurationHistogram::bucket(max + 1), bucket + 1, "{bucket}");
+  }
+  assert_eq!(DurationHistogram::bucket(u64::MAX), BUCKETS - 1);
+  assert_eq!(DurationHistogram::bucket_max(BUCKETS - 1), u64::MAX);
+}
+
+#[test]
+fn histogram_percentiles() {
+  let histogram = DurationHistogram::default();
+  assert_eq!(histogram.percentile(99.0), Duration::ZERO);
+
+  for _ in 0..99 {
+    histogram.record(Duration::from_micros(10));
+  }
+  histogram.record(Duration::from_millis(5));
+  let p99 = histogram.percentile(99.0);
+  assert!(
+    p99 >= Duration::from_micros(10) && p99 <= Duration::from_nanos(11_250),
+    "{p99:?}"
+  );
+  assert_eq!(
+    histogram.total(),
+    Duration::from_micros(990) + Duration::from_millis(5)
+  );
+
+  histogram.record(Duration::from_millis(5));
+  let p99 = histogram.percentile(99.0);
+  assert!(
+    p99 >= Duration::from_millis(5) && p99 <= Duration::from_micros(5_625),
+    "{p99:?}"
+  );
+}
+
+#[test]
+fn snapshot() {
+  let stats = ExternalsStats::default();
+  stats.record_call(None, "node-modules-external", Duration::from_micros(20));
+  stats.record_call(Some("rsc"), "bundled-layer", Duration::from_micros(10));
+  stats.record_call(Some("rsc"), "bundled-layer", Duration::from_micros(10));
+  stats.record_resolver_call();
+  stats.record_base_resolve_mismatches(2);
+
+  let snapshot = stats.snapshot();
+  assert_eq!(snapshot.calls, 3);
+  assert_eq!(
+    snapshot.calls_per_layer,
+    FxHashMap::from_iter([(None, 1), (Some("rsc".to_string()), 2)])
+  );
+  assert_eq!(
+    snapshot.decisions_per_rule,
+    FxHashMap::from_iter([("node-modules-external", 1), ("bundled-layer", 2)])
+  );
+  assert_eq!(snapshot.resolver_calls, 1);
+  assert_eq!(snapshot.base_resolve_mismatches, 2);
+  assert_eq!(snapshot.total_time, Duration::from_micros(40));
+}
+
+#[test]
+fn registry() {
+  const NAME: &str = "externals-stats-registry-test";
+  assert!(get_externals_stats(NAME).is_none());
+
+  let stats = Arc::new(ExternalsStats::default());
+  register_externals_stats(NAME, &stats);
+  stats.record_resolver_call();
+  assert_eq!(get_externals_stats(NAME).unwrap().resolver_calls, 1);
+
+  // A new plugin for the same compiler replaces the previous one.
+  let next_stats = Arc::new(ExternalsStats::default());
+  register_externals_stats(NAME, &next_stats);
+  assert_eq!(get_externals_stats(NAME).unwrap().resolver_calls, 0);
+
+  drop(next_stats);
+  assert!(get_externals_stats(NAME).is_none());
+}
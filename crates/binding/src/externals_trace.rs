@@ -0,0 +1,133 @@
+//! The `trace` asset: the final outcome of each request that reached the
+//! externals functions, and which of them decided it.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use rspack_core::{ExternalItem, ExternalItemValue};
+use rustc_hash::FxBuildHasher;
+use serde::Serialize;
+
+use crate::handle_externals::DecisionRule;
+
+/// The externals function that decided a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceSource {
+  UserRule,
+  Builtin,
+  EdgeRuntime,
+  Handler,
+  Fallback,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalsTraceEntry {
+  pub request: String,
+  pub context: String,
+  pub dependency_type: String,
+  pub layer: Option<String>,
+  pub source: TraceSource,
+  /// The built-in rule, for requests that reached the server externals
+  /// handler.
+  pub rule: Option<DecisionRule>,
+  pub resolved: Option<String>,
+  /// The external the request was replaced with, `None` if it is bundled.
+  pub external: Option<serde_json::Value>,
+}
+
+type TraceKey = (String, String, String, Option<String>);
+
+#[derive(Debug, Default)]
+pub struct ExternalsTrace {
+  entries: DashMap<TraceKey, ExternalsTraceEntry, FxBuildHasher>,
+}
+
+impl ExternalsTrace {
+  pub fn record(&self, entry: ExternalsTraceEntry) {
+    self.entries.insert(
+      (
+        entry.context.clone(),
+        entry.request.clone(),
+        entry.dependency_type.clone(),
+        entry.layer.clone(),
+      ),
+      entry,
+    );
+  }
+
+  pub fn clear(&self) {
+    self.entries.clear();
+  }
+
+  pub fn entries(&self) -> Vec<ExternalsTraceEntry> {
+    let mut entries = self
+      .entries
+      .iter()
+      .map(|entry| entry.value().clone())
+      .collect::<Vec<_>>();
+    // Sort so traces of two builds can be diffed.
+    entries.sort_unstable_by(|a, b| {
+      (&a.request, &a.context, &a.dependency_type, &a.layer).cmp(&(
+        &b.request,
+        &b.context,
+        &b.dependency_type,
+        &b.layer,
+      ))
+    });
+    entries
+  }
+
+  /// Records the requests an externals function decides. Other items are
+  /// returned as is, as they don't see the requests they don't match.
+  pub fn wrap(self: &Arc<Self>, item: ExternalItem, source: TraceSource) -> ExternalItem {
+    let ExternalItem::Fn(item) = item else {
+      return item;
+    };
+    let trace = self.clone();
+    ExternalItem::Fn(Box::new(move |ctx| {
+      let trace = trace.clone();
+      let request = ctx.request.clone();
+      let context = ctx.context.clone();
+      let dependency_type = ctx.dependency_type.clone();
+      let layer = ctx.context_info.issuer_layer.clone();
+      let result = item(ctx);
+      Box::pin(async move {
+        let result = result.await?;
+        if let Some(value) = &result.result {
+          trace.record(ExternalsTraceEntry {
+            request,
+            context,
+            dependency_type,
+            layer,
+            source,
+            rule: None,
+            resolved: None,
+            external: external_json(Some(value)),
+          });
+        }
+        Ok(result)
+      })
+    }))
+  }
+}
+
+/// Returns the JSON form of an external, `None` if it bundles the request.
+pub fn external_json(value: Option<&ExternalItemValue>) -> Option<serde_json::Value> {
+  Some(match value? {
+    ExternalItemValue::Bool(false) => return None,
+    ExternalItemValue::Bool(true) => serde_json::Value::Bool(true),
+    ExternalItemValue::String(external) => serde_json::Value::from(external.as_str()),
+    ExternalItemValue::Array(external) => serde_json::Value::from(external.clone()),
+    ExternalItemValue::Object(external) => serde_json::Value::Object(
+      external
+        .iter()
+        .map(|(key, value)| (key.clone(), serde_json::Value::from(value.clone())))
+        .collect(),
+    ),
+  })
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,80 @@
+use rustc_hash::FxHashMap;
+use serde_json::json;
+
+use super::*;
+
+fn entry(request: &str, context: &str, source: TraceSource) -> ExternalsTraceEntry {
+  ExternalsTraceEntry {
+    request: request.to_string(),
+    context: context.to_string(),
+    dependency_type: "commonjs".to_string(),
+    layer: None,
+    source,
+    rule: None,
+    resolved: None,
+    external: None,
+  }
+}
+
+#[test]
+fn externals_as_json() {
+  assert_eq!(external_json(None), None);
+  assert_eq!(external_json(Some(&ExternalItemValue::Bool(false))), None);
+  assert_eq!(
+    external_json(Some(&ExternalItemValue::Bool(true))),
+    Some(json!(true))
+  );
+  assert_eq!(
+    external_json(Some(&ExternalItemValue::String("commonjs fs".to_string()))),
+    Some(json!("commonjs fs"))
+  );
+  assert_eq!(
+    external_json(Some(&ExternalItemValue::Array(vec![
+      "global".to_string(),
+      "fs".to_string()
+    ]))),
+    Some(json!(["global", "fs"]))
+  );
+  assert_eq!(
+    external_json(Some(&ExternalItemValue::Object(FxHashMap::from_iter([(
+      "commonjs".to_string(),
+      vec!["fs".to_string()]
+    )])))),
+    Some(json!({ "commonjs": ["fs"] }))
+  );
+}
+
+#[test]
+fn entries_are_sorted_and_replaced() {
+  let trace = ExternalsTrace::default();
+  trace.record(entry("b", "/app", TraceSource::Handler));
+  trace.record(entry("a", "/app/pages", TraceSource::Handler));
+  trace.record(entry("a", "/app", TraceSource::Handler));
+  // A later decision of the same request replaces the earlier one.
+  trace.record(entry("b", "/app", TraceSource::Fallback));
+
+  assert_eq!(
+    trace.entries(),
+    [
+      entry("a", "/app", TraceSource::Handler),
+      entry("a", "/app/pages", TraceSource::Handler),
+      entry("b", "/app", TraceSource::Fallback),
+    ]
+  );
+  assert_eq!(
+    serde_json::to_value(&trace.entries()[2]).unwrap(),
+    json!({
+      "request": "b",
+      "context": "/app",
+      "dependencyType": "commonjs",
+      "layer": null,
+      "source": "fallback",
+      "rule": null,
+      "resolved": null,
+      "external": null,
+    })
+  );
+
+  trace.clear();
+  assert_eq!(trace.entries(), []);
+}
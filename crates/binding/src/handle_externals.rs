@@ -1,75 +1,69 @@
 use std::{
+  borrow::Cow,
   future::Future,
-  path::Path,
+  hash::{BuildHasherDefault, Hash, Hasher},
+  path::{Component, Path, PathBuf},
   pin::Pin,
-  sync::{Arc, LazyLock},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, LazyLock, Mutex, PoisonError, RwLock, Weak,
+  },
+  time::Instant,
 };
 
-use once_cell::sync::OnceCell;
+use dashmap::DashMap;
 use regex::Regex;
 use rspack_core::{Alias, DependencyCategory, Resolve, ResolveOptionsWithDependencyType};
 use rspack_regex::RspackRegex;
-use rustc_hash::FxHashMap;
-
-use crate::config_shared::{EsmExternalsConfig, NextConfigComplete};
-
-const WEBPACK_BUNDLED_LAYERS: &[&str] = &[
-  "rsc",
-  "action-browser",
-  "ssr",
-  "app-pages-browser",
-  "shared",
-  "instrument",
-  "middleware",
-];
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  config_shared::{EsmExternalsConfig, NextConfigComplete},
+  esm_externals_error::EsmExternalsError,
+  externals_stats::ExternalsStats,
+  native_addons,
+  next_version::NextVersion,
+  package_identity::{self, PackageIdentity},
+  webpack_layers::WebpackLayers,
+  yarn_pnp,
+};
 
-fn is_webpack_bundled_layer(layer: Option<&str>) -> bool {
-  layer.is_some_and(|layer| WEBPACK_BUNDLED_LAYERS.contains(&layer))
-}
+// Requests and resolved paths are matched with `/` as the only separator.
+// Resolved paths, package directories and `resolve_next_external` inputs go
+// through `normalize_path_sep` first, as the resolver may return either form.
 
 static REACT_PACKAGES_REGEX: LazyLock<Regex> =
   LazyLock::new(|| Regex::new(r"^(react|react-dom|react-server-dom-webpack)($|/)").unwrap());
 
-static NOT_EXTERNAL_MODULES_REGEX: LazyLock<Regex> = LazyLock::new(|| {
-  Regex::new(
-        r"^(?:private-next-pages/|next/(?:dist/pages/|(?:app|cache|document|link|form|head|image|legacy/image|constants|dynamic|script|navigation|headers|router|compat/router|server)$)|string-hash|private-next-rsc-action-validate|private-next-rsc-action-client-wrapper|private-next-rsc-server-reference|private-next-rsc-cache-wrapper|private-next-rsc-track-dynamic-import$)"
-    ).unwrap()
-});
-
 static NEXT_IMAGE_LOADER_REGEX: LazyLock<Regex> =
-  LazyLock::new(|| Regex::new(r"^next[/\\]dist[/\\]shared[/\\]lib[/\\]image-loader").unwrap());
+  LazyLock::new(|| Regex::new(r"^next/dist/shared/lib/image-loader").unwrap());
 
 static NEXT_SERVER_REGEX: LazyLock<Regex> =
-  LazyLock::new(|| Regex::new(r"^next[/\\]dist[/\\]compiled[/\\]next-server").unwrap());
+  LazyLock::new(|| Regex::new(r"^next/dist/compiled/next-server").unwrap());
 
-static NEXT_SHARED_CJS_REGEX: LazyLock<RspackRegex> = LazyLock::new(|| {
-  RspackRegex::new(r"^next[/\\]dist[/\\]shared[/\\](?!lib[/\\]router[/\\]router)").unwrap()
-});
+// The `next/dist` layout below and `EXTERNAL_PATTERN` are the same in every
+// `NextVersion`, see the `next_dist_layout_across_versions` test.
+static NEXT_SHARED_CJS_REGEX: LazyLock<RspackRegex> =
+  LazyLock::new(|| RspackRegex::new(r"^next/dist/shared/(?!lib/router/router)").unwrap());
 
 static NEXT_COMPILED_CJS_REGEX: LazyLock<Regex> =
-  LazyLock::new(|| Regex::new(r"^next[/\\]dist[/\\]compiled[/\\].*\.c?js$").unwrap());
+  LazyLock::new(|| Regex::new(r"^next/dist/compiled/.*\.c?js$").unwrap());
 
-static NEXT_SHARED_ESM_REGEX: LazyLock<RspackRegex> = LazyLock::new(|| {
-  RspackRegex::new(r"^next[/\\]dist[/\\]esm[/\\]shared[/\\](?!lib[/\\]router[/\\]router)").unwrap()
-});
+static NEXT_SHARED_ESM_REGEX: LazyLock<RspackRegex> =
+  LazyLock::new(|| RspackRegex::new(r"^next/dist/esm/shared/(?!lib/router/router)").unwrap());
 
 static NEXT_COMPILED_MJS_REGEX: LazyLock<Regex> =
-  LazyLock::new(|| Regex::new(r"^next[/\\]dist[/\\]compiled[/\\].*\.mjs$").unwrap());
+  LazyLock::new(|| Regex::new(r"^next/dist/compiled/.*\.mjs$").unwrap());
 
 static BABEL_RUNTIME_REGEX: LazyLock<Regex> =
-  LazyLock::new(|| Regex::new(r"node_modules[/\\]@babel[/\\]runtime[/\\]").unwrap());
+  LazyLock::new(|| Regex::new(r"node_modules/@babel/runtime/").unwrap());
 
 static WEBPACK_CSS_LOADER_REGEX: LazyLock<Regex> =
-  LazyLock::new(|| Regex::new(r"node_modules[/\\]webpack|node_modules[/\\]css-loader").unwrap());
+  LazyLock::new(|| Regex::new(r"node_modules/webpack|node_modules/css-loader").unwrap());
 
 const BARREL_OPTIMIZATION_PREFIX: &str = "__barrel_optimize__";
 
-const WEBPACK_SERVER_ONLY_LAYERS: &[&str] = &["rsc", "action-browser", "instrument", "middleware"];
-
-fn should_use_react_server_condition(layer: Option<&str>) -> bool {
-  layer.is_some_and(|layer| WEBPACK_SERVER_ONLY_LAYERS.contains(&layer))
-}
-
 static NODE_RESOLVE_OPTIONS: LazyLock<ResolveOptionsWithDependencyType> =
   LazyLock::new(|| ResolveOptionsWithDependencyType {
     resolve_options: Some(Box::new(Resolve {
@@ -145,8 +139,168 @@ static NODE_BASE_ESM_RESOLVE_OPTIONS: LazyLock<ResolveOptionsWithDependencyType>
     dependency_category: NODE_ESM_RESOLVE_OPTIONS.dependency_category,
   });
 
+/// The condition of the exports packages provide for React Server
+/// Components, which the server-only layers are resolved with.
+const REACT_SERVER_CONDITION: &str = "react-server";
+
+/// Prepends the `react-server` condition to the condition names of `options`.
+fn with_react_server_condition(
+  options: &ResolveOptionsWithDependencyType,
+) -> ResolveOptionsWithDependencyType {
+  ResolveOptionsWithDependencyType {
+    resolve_options: options.resolve_options.clone().map(|mut options| {
+      options.condition_names = Some(
+        [REACT_SERVER_CONDITION.to_string()]
+          .into_iter()
+          .chain(options.condition_names.take().unwrap_or_default())
+          .collect(),
+      );
+      options
+    }),
+    resolve_to_context: options.resolve_to_context,
+    dependency_category: options.dependency_category,
+  }
+}
+
+/// The options `resolve_external` resolves the requests of a layer with.
+#[derive(Debug, Clone, Hash)]
+struct ExternalsResolveOptions {
+  esm: ResolveOptionsWithDependencyType,
+  node: ResolveOptionsWithDependencyType,
+  base_esm: ResolveOptionsWithDependencyType,
+  base: ResolveOptionsWithDependencyType,
+}
+
+impl ExternalsResolveOptions {
+  /// The node resolve options, with the `react-server` condition of the
+  /// server-only layers, and resolving with the Yarn Plug'n'Play manifest if
+  /// `pnp` is set.
+  fn new(react_server: bool, pnp: bool) -> Self {
+    let options = |options: &ResolveOptionsWithDependencyType| {
+      let mut options = if react_server {
+        with_react_server_condition(options)
+      } else {
+        options.clone()
+      };
+      if pnp {
+        if let Some(resolve_options) = &mut options.resolve_options {
+          resolve_options.pnp = Some(true);
+        }
+      }
+      options
+    };
+    Self {
+      esm: options(&NODE_ESM_RESOLVE_OPTIONS),
+      node: options(&NODE_RESOLVE_OPTIONS),
+      base_esm: options(&NODE_BASE_ESM_RESOLVE_OPTIONS),
+      base: options(&NODE_BASE_RESOLVE_OPTIONS),
+    }
+  }
+}
+
 static NODE_MODULES_REGEX: LazyLock<Regex> =
-  LazyLock::new(|| Regex::new(r"node_modules[/\\].*\.[mc]?js$").unwrap());
+  LazyLock::new(|| Regex::new(r"node_modules/.*\.[mc]?js$").unwrap());
+
+/// Packages never bundled on the server, from Next.js'
+/// `server-external-packages.json`.
+static DEFAULT_SERVER_EXTERNAL_PACKAGES: &[&str] = &[
+  "@appsignal/nodejs",
+  "@aws-sdk/client-s3",
+  "@aws-sdk/s3-presigned-post",
+  "@blockfrost/blockfrost-js",
+  "@highlight-run/node",
+  "@huggingface/transformers",
+  "@jpg-store/lucid-cardano",
+  "@libsql/client",
+  "@mikro-orm/core",
+  "@mikro-orm/knex",
+  "@node-rs/argon2",
+  "@node-rs/bcrypt",
+  "@prisma/client",
+  "@react-pdf/renderer",
+  "@sentry/profiling-node",
+  "@sparticuz/chromium",
+  "@swc/core",
+  "@xenova/transformers",
+  "argon2",
+  "autoprefixer",
+  "aws-crt",
+  "bcrypt",
+  "better-sqlite3",
+  "canvas",
+  "chromadb-default-embed",
+  "config",
+  "cpu-features",
+  "cypress",
+  "dd-trace",
+  "eslint",
+  "express",
+  "firebase-admin",
+  "htmlrewriter",
+  "import-in-the-middle",
+  "isolated-vm",
+  "jest",
+  "jsdom",
+  "keyv",
+  "libsql",
+  "mdx-bundler",
+  "mongodb",
+  "mongoose",
+  "newrelic",
+  "next-mdx-remote",
+  "next-seo",
+  "node-cron",
+  "node-pty",
+  "node-web-audio-api",
+  "onnxruntime-node",
+  "oslo",
+  "pg",
+  "playwright",
+  "playwright-core",
+  "postcss",
+  "prettier",
+  "prisma",
+  "puppeteer-core",
+  "puppeteer",
+  "ravendb",
+  "require-in-the-middle",
+  "rimraf",
+  "sharp",
+  "shiki",
+  "sqlite3",
+  "ts-morph",
+  "ts-node",
+  "typescript",
+  "vscode-oniguruma",
+  "webpack",
+  "websocket",
+  "zeromq",
+];
+
+pub fn default_server_external_packages() -> Vec<String> {
+  DEFAULT_SERVER_EXTERNAL_PACKAGES
+    .iter()
+    .map(|package| package.to_string())
+    .collect()
+}
+
+/// Returns the name of the package following each `node_modules` segment of
+/// a resolved path, so that packages in the pnpm store, e.g.
+/// `node_modules/.pnpm/sharp@0.33.0/node_modules/sharp/lib/index.js`, are
+/// matched by name as well.
+fn node_modules_package_names(resource: &str) -> impl Iterator<Item = Cow<'_, str>> {
+  let segments = resource.split(['/', '\\']).collect::<Vec<_>>();
+  (0..segments.len())
+    .filter(|&index| segments[index] == "node_modules")
+    .filter_map(|index| match segments.get(index + 1..)? {
+      [scope, name, _, ..] if scope.starts_with('@') => Some(format!("{scope}/{name}").into()),
+      [scope, ..] if scope.starts_with('@') => None,
+      [name, _, ..] => Some(Cow::Borrowed(*name)),
+      _ => None,
+    })
+    .collect::<Vec<_>>()
+    .into_iter()
+}
 
 fn is_resource_in_packages(
   resource: &str,
@@ -156,51 +310,819 @@ fn is_resource_in_packages(
   if package_names.is_empty() {
     return false;
   }
+  let resource = normalize_path_sep(resource);
   package_names.iter().any(|pkg| {
     if let Some(dirs) = package_dir_mapping {
       if let Some(dir) = dirs.get(pkg) {
-        return resource.starts_with(&format!("{dir}/"));
+        return is_path_in_dir(&resource, &normalize_path_sep(dir));
       }
     }
-    resource.contains(&format!(
-      "/node_modules/{}/",
-      pkg.replace('/', std::path::MAIN_SEPARATOR_STR)
-    ))
+    node_modules_package_names(&resource).any(|name| name == pkg.as_str())
   })
 }
 
+/// Identifies the branch of `handle_externals` that decided a request.
+///
+/// Rules are named by `as_str` in traces, persisted decisions and stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "&'static str", try_from = "String")]
+pub enum DecisionRule {
+  ImportNextWarning,
+  Next,
+  ReactPackage,
+  NotExternalModule,
+  SwcHelpers,
+  BarrelOptimization,
+  VercelOg,
+  NextImageLoader,
+  NextServer,
+  NextSharedCjs,
+  NextSharedEsm,
+  NextDistExternal,
+  LocalNextExternal,
+  LocalFile,
+  Unresolved,
+  BaseResolveMismatch,
+  BundledLayer,
+  BabelRuntime,
+  WebpackCssLoader,
+  BundlePagesRouterDependencies,
+  TranspilePackage,
+  NodeModulesExternal,
+  NativeAddon,
+  Default,
+}
+
+impl DecisionRule {
+  pub const VALUES: &[DecisionRule] = &[
+    Self::ImportNextWarning,
+    Self::Next,
+    Self::ReactPackage,
+    Self::NotExternalModule,
+    Self::SwcHelpers,
+    Self::BarrelOptimization,
+    Self::VercelOg,
+    Self::NextImageLoader,
+    Self::NextServer,
+    Self::NextSharedCjs,
+    Self::NextSharedEsm,
+    Self::NextDistExternal,
+    Self::LocalNextExternal,
+    Self::LocalFile,
+    Self::Unresolved,
+    Self::BaseResolveMismatch,
+    Self::BundledLayer,
+    Self::BabelRuntime,
+    Self::WebpackCssLoader,
+    Self::BundlePagesRouterDependencies,
+    Self::TranspilePackage,
+    Self::NodeModulesExternal,
+    Self::NativeAddon,
+    Self::Default,
+  ];
+
+  /// The name of the rule in traces and stats.
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::ImportNextWarning => "import-next-warning",
+      Self::Next => "next",
+      Self::ReactPackage => "react-package",
+      Self::NotExternalModule => "not-external-module",
+      Self::SwcHelpers => "swc-helpers",
+      Self::BarrelOptimization => "barrel-optimization",
+      Self::VercelOg => "vercel-og",
+      Self::NextImageLoader => "next-image-loader",
+      Self::NextServer => "next-server",
+      Self::NextSharedCjs => "next-shared-cjs",
+      Self::NextSharedEsm => "next-shared-esm",
+      Self::NextDistExternal => "next-dist-external",
+      Self::LocalNextExternal => "local-next-external",
+      Self::LocalFile => "local-file",
+      Self::Unresolved => "unresolved",
+      Self::BaseResolveMismatch => "base-resolve-mismatch",
+      Self::BundledLayer => "bundled-layer",
+      Self::BabelRuntime => "babel-runtime",
+      Self::WebpackCssLoader => "webpack-css-loader",
+      Self::BundlePagesRouterDependencies => "bundle-pages-router-dependencies",
+      Self::TranspilePackage => "transpile-package",
+      Self::NodeModulesExternal => "node-modules-external",
+      Self::NativeAddon => "native-addon",
+      Self::Default => "default",
+    }
+  }
+}
+
+impl From<DecisionRule> for &'static str {
+  fn from(rule: DecisionRule) -> Self {
+    rule.as_str()
+  }
+}
+
+impl TryFrom<String> for DecisionRule {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    Self::VALUES
+      .iter()
+      .find(|rule| rule.as_str() == value)
+      .copied()
+      .ok_or_else(|| format!("Unknown externals rule \"{value}\""))
+  }
+}
+
+/// The outcome of `handle_externals` for a single request, together with
+/// the rule that produced it.
+#[derive(Debug, Clone)]
+pub struct ExternalsDecision {
+  pub rule: DecisionRule,
+  pub resolved: Option<String>,
+  pub external: Option<String>,
+}
+
+impl ExternalsDecision {
+  fn new(rule: DecisionRule, external: Option<String>) -> Self {
+    Self {
+      rule,
+      resolved: None,
+      external,
+    }
+  }
+
+  fn with_resolved(mut self, resolved: impl Into<String>) -> Self {
+    self.resolved = Some(resolved.into());
+    self
+  }
+}
+
+/// A recorded externals decision, persisted across builds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceEntry {
+  pub request: String,
+  pub context: String,
+  pub dependency_type: String,
+  pub layer: Option<String>,
+  pub rule: DecisionRule,
+  pub resolved: Option<String>,
+  pub external: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DecisionKey {
+  context: String,
+  request: String,
+  dependency_type: String,
+  layer: Option<String>,
+}
+
+/// Hit/miss counters of the externals decision cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecisionCacheStats {
+  pub hits: u64,
+  pub misses: u64,
+}
+
+/// Hit/miss counters of one handler, kept apart from the cache entries as
+/// those may be shared with other handlers.
+#[derive(Debug, Default)]
+struct DecisionCacheCounters {
+  hits: AtomicU64,
+  misses: AtomicU64,
+}
+
+impl DecisionCacheCounters {
+  fn take(&self) -> DecisionCacheStats {
+    DecisionCacheStats {
+      hits: self.hits.swap(0, Ordering::Relaxed),
+      misses: self.misses.swap(0, Ordering::Relaxed),
+    }
+  }
+}
+
+/// A memoized decision and the generation of the latest build started before
+/// it was made.
+#[derive(Debug)]
+struct CachedDecision {
+  generation: u64,
+  cell: Arc<tokio::sync::OnceCell<ExternalsDecision>>,
+}
+
+impl CachedDecision {
+  fn new(generation: u64, decision: Option<ExternalsDecision>) -> Self {
+    Self {
+      generation,
+      cell: Arc::new(tokio::sync::OnceCell::new_with(decision)),
+    }
+  }
+}
+
+/// Memoizes the result of `handle_externals` per
+/// `(context, request, dependency_type, layer)`.
+///
+/// Decisions are reused by builds of their generation or an older one, and
+/// made again by builds started after them. Concurrent lookups of the same
+/// key share a single in-flight computation, so the resolver runs at most
+/// once per key and build. Failed computations are not cached.
+#[derive(Debug, Default)]
+struct DecisionCache {
+  entries: DashMap<DecisionKey, CachedDecision, BuildHasherDefault<FxHasher>>,
+}
+
+impl DecisionCache {
+  /// Returns the decision of `key` made since the build of `generation`
+  /// started, or makes it with `init` as part of the `latest` generation.
+  async fn get_or_try_init<F>(
+    &self,
+    key: DecisionKey,
+    generation: u64,
+    latest: u64,
+    counters: &DecisionCacheCounters,
+    init: F,
+  ) -> rspack_error::Result<ExternalsDecision>
+  where
+    F: Future<Output = rspack_error::Result<ExternalsDecision>>,
+  {
+    // Clone the cell out of the map so the shard lock is not held across the await.
+    let cell = {
+      let mut cached = self
+        .entries
+        .entry(key)
+        .or_insert_with(|| CachedDecision::new(latest, None));
+      if cached.generation < generation {
+        *cached = CachedDecision::new(latest, None);
+      }
+      cached.cell.clone()
+    };
+    let mut computed = false;
+    let decision = cell
+      .get_or_try_init(|| {
+        computed = true;
+        init
+      })
+      .await?;
+    if computed {
+      counters.misses.fetch_add(1, Ordering::Relaxed);
+    } else {
+      counters.hits.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(decision.clone())
+  }
+
+  /// Drops the decisions made before `generation`.
+  fn retain_since(&self, generation: u64) {
+    self
+      .entries
+      .retain(|_, cached| cached.generation >= generation);
+  }
+
+  /// Adds a decision made by an earlier build, unless the key is decided.
+  fn restore(&self, key: DecisionKey, generation: u64, decision: ExternalsDecision) {
+    self
+      .entries
+      .entry(key)
+      .or_insert_with(|| CachedDecision::new(generation, Some(decision)));
+  }
+}
+
+/// The directories of the `transpilePackages`, resolved from the project `dir`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TranspilePackageDirs {
+  /// Package name to the directory containing its `package.json`.
+  dirs: FxHashMap<String, String>,
+  /// The `package.json` files the directories were resolved from, and the
+  /// Yarn Plug'n'Play manifest they were resolved with.
+  package_jsons: Vec<String>,
+  /// `node_modules/<package>/package.json` in the project `dir` for packages
+  /// resolved elsewhere or not at all, which appear once the package is
+  /// installed or linked there.
+  missing_package_jsons: Vec<String>,
+}
+
+impl TranspilePackageDirs {
+  pub fn package_jsons(&self) -> &[String] {
+    &self.package_jsons
+  }
+
+  pub fn missing_package_jsons(&self) -> &[String] {
+    &self.missing_package_jsons
+  }
+
+  fn is_affected_by(&self, changed_file: &Path) -> bool {
+    let changed_file = normalize_path_sep(&changed_file.to_string_lossy());
+    self
+      .package_jsons
+      .iter()
+      .chain(&self.missing_package_jsons)
+      .any(|file| file == &changed_file || is_path_in_dir(file, &changed_file))
+  }
+}
+
+/// The memoized decisions and `transpilePackages` directories of a handler.
+///
+/// Handlers created with the same shared context id, project `dir` and
+/// configuration share one context, so the compilers of a Next.js build
+/// running in one process resolve each request and package once.
+#[derive(Debug, Default)]
+struct SharedExternalsContext {
+  resolved_external_package_dirs: RwLock<Arc<tokio::sync::OnceCell<Arc<TranspilePackageDirs>>>>,
+  decision_cache: DecisionCache,
+  /// The generation of the latest build any handler sharing the context
+  /// has started.
+  generation: AtomicU64,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct SharedExternalsContextKey {
+  id: String,
+  dir: String,
+  fingerprint: u64,
+  /// Hashes the `resolve` options of the compiler, which the requests are
+  /// resolved with besides the options of the handler.
+  compiler_resolve: u64,
+}
+
+/// The shared contexts in use in this process. Entries are dropped with the
+/// last handler using them.
+static SHARED_EXTERNALS_CONTEXTS: LazyLock<
+  Mutex<FxHashMap<SharedExternalsContextKey, Weak<SharedExternalsContext>>>,
+> = LazyLock::new(Default::default);
+
 #[derive(Debug)]
 pub struct ExternalHandler {
   config: NextConfigComplete,
-  opt_out_bundling_package_regex: RspackRegex,
+  opt_out_bundling_package_regex: Option<RspackRegex>,
+  server_external_packages: FxHashSet<String>,
   transpiled_packages: Vec<String>,
   dir: String,
-  resolved_external_package_dirs: OnceCell<FxHashMap<String, String>>,
-  loose_esm_externals: bool,
   default_overrides: FxHashMap<String, String>,
+  next_version: NextVersion,
+  layers: WebpackLayers,
+  pnp: bool,
+  runtime_roots: Vec<String>,
+  resolve_options: ExternalsResolveOptions,
+  react_server_resolve_options: ExternalsResolveOptions,
+  shared_context_id: Option<String>,
+  shared: RwLock<Arc<SharedExternalsContext>>,
+  /// The decisions this handler made or reused in its current build, as the
+  /// shared cache also holds the decisions of the other handlers.
+  decisions: DashMap<DecisionKey, ExternalsDecision, BuildHasherDefault<FxHasher>>,
+  /// The generation of the current build of this handler.
+  generation: AtomicU64,
+  decision_cache_counters: DecisionCacheCounters,
+  stats: Arc<ExternalsStats>,
 }
 
 impl ExternalHandler {
   pub fn new(
     config: NextConfigComplete,
-    opt_out_bundling_package_regex: RspackRegex,
+    opt_out_bundling_package_regex: Option<RspackRegex>,
+    default_server_external_packages: Vec<String>,
     transpiled_packages: Vec<String>,
     dir: String,
     default_overrides: FxHashMap<String, String>,
   ) -> Self {
-    let loose_esm_externals = config.experimental.esm_externals == EsmExternalsConfig::Loose;
-
+    // Transpiled packages are bundled, even when listed by default.
+    let server_external_packages = default_server_external_packages
+      .into_iter()
+      .chain(config.server_external_packages.iter().cloned())
+      .filter(|package| !transpiled_packages.contains(package))
+      .collect();
     Self {
       config,
       opt_out_bundling_package_regex,
+      server_external_packages,
       transpiled_packages,
+      runtime_roots: vec![dir.clone()],
       dir,
-      resolved_external_package_dirs: OnceCell::default(),
-      loose_esm_externals,
       default_overrides,
+      next_version: NextVersion::default(),
+      layers: WebpackLayers::default(),
+      pnp: false,
+      resolve_options: ExternalsResolveOptions::new(false, false),
+      react_server_resolve_options: ExternalsResolveOptions::new(true, false),
+      shared_context_id: None,
+      shared: Default::default(),
+      decisions: Default::default(),
+      generation: AtomicU64::new(0),
+      decision_cache_counters: DecisionCacheCounters::default(),
+      stats: Default::default(),
     }
   }
 
+  /// Applies the rules of a Next.js release, along with its layers. Has to
+  /// be called before `with_layers` and `with_shared_context`.
+  pub fn with_next_version(mut self, next_version: NextVersion) -> Self {
+    self.next_version = next_version;
+    self.layers = WebpackLayers::for_version(next_version);
+    self
+  }
+
+  /// Replaces the layers of the Next.js release. Has to be called
+  /// before `with_shared_context`, as the layers are part of the configuration.
+  pub fn with_layers(mut self, layers: WebpackLayers) -> Self {
+    self.layers = layers;
+    self
+  }
+
+  /// Resolves packages with the Yarn Plug'n'Play manifest of the project
+  /// instead of `node_modules` directories. Has to be called before
+  /// `with_shared_context`, as it is part of the configuration.
+  pub fn with_pnp(mut self, pnp: bool) -> Self {
+    self.pnp = pnp;
+    self.resolve_options = ExternalsResolveOptions::new(false, pnp);
+    self.react_server_resolve_options = ExternalsResolveOptions::new(true, pnp);
+    self
+  }
+
+  /// Externalizes packages that resolve identically from any of the
+  /// directories the server resolves packages from at runtime, such as the
+  /// workspace root or the root of a deployment, rather than from the
+  /// project `dir` only. Relative roots are relative to `dir`, and no roots
+  /// keep `dir`. Has to be called before `with_shared_context`, as the roots
+  /// are part of the configuration.
+  pub fn with_runtime_roots(mut self, runtime_roots: Vec<String>) -> Self {
+    if !runtime_roots.is_empty() {
+      self.runtime_roots = runtime_roots
+        .into_iter()
+        .map(|root| {
+          // Resolve `..` lexically, as the roots may not exist at build time.
+          let mut path = PathBuf::new();
+          for component in Path::new(&self.dir).join(root).components() {
+            match component {
+              Component::CurDir => {}
+              Component::ParentDir => {
+                path.pop();
+              }
+              component => path.push(component),
+            }
+          }
+          normalize_path_sep(&path.to_string_lossy())
+        })
+        .collect();
+    }
+    self
+  }
+
+  /// Shares the memoized decisions and `transpilePackages` directories with
+  /// the other handlers created with the same `id`, `dir` and configuration,
+  /// and applied to compilers with the same `resolve` options.
+  ///
+  /// Handlers sharing a context must resolve requests the same way, as a
+  /// decision made with one handler's resolver is reused by the others.
+  pub fn with_shared_context(mut self, id: &str) -> Self {
+    self.shared_context_id = Some(id.to_string());
+    self.join_shared_context(&Resolve::default());
+    self
+  }
+
+  /// Moves a handler with a shared context to the one of the compilers
+  /// resolving with the same `resolve` options. Has to be called before the
+  /// first build.
+  pub fn set_compiler_resolve_options(&self, resolve: &Resolve) {
+    self.join_shared_context(resolve);
+  }
+
+  fn join_shared_context(&self, compiler_resolve: &Resolve) {
+    let Some(id) = &self.shared_context_id else {
+      return;
+    };
+    let mut hasher = FxHasher::default();
+    compiler_resolve.hash(&mut hasher);
+    let key = SharedExternalsContextKey {
+      id: id.clone(),
+      dir: self.dir.clone(),
+      fingerprint: self.config_fingerprint(),
+      compiler_resolve: hasher.finish(),
+    };
+    let mut contexts = SHARED_EXTERNALS_CONTEXTS
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner);
+    contexts.retain(|_, context| context.strong_count() != 0);
+    let shared = match contexts.get(&key).and_then(Weak::upgrade) {
+      Some(shared) => shared,
+      None => {
+        let shared = Arc::new(SharedExternalsContext::default());
+        contexts.insert(key, Arc::downgrade(&shared));
+        shared
+      }
+    };
+    self
+      .generation
+      .store(shared.generation.load(Ordering::Relaxed), Ordering::Relaxed);
+    *self.shared.write().unwrap_or_else(PoisonError::into_inner) = shared;
+  }
+
+  fn shared(&self) -> Arc<SharedExternalsContext> {
+    self
+      .shared
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .clone()
+  }
+
+  /// Hashes everything besides `dir` that decisions depend on.
+  pub fn config_fingerprint(&self) -> u64 {
+    let mut hasher = FxHasher::default();
+    self.config.hash(&mut hasher);
+    self
+      .opt_out_bundling_package_regex
+      .as_ref()
+      .map(RspackRegex::to_source_string)
+      .hash(&mut hasher);
+    let mut server_external_packages = self.server_external_packages.iter().collect::<Vec<_>>();
+    server_external_packages.sort_unstable();
+    server_external_packages.hash(&mut hasher);
+    self.transpiled_packages.hash(&mut hasher);
+    let mut default_overrides = self.default_overrides.iter().collect::<Vec<_>>();
+    default_overrides.sort_unstable();
+    default_overrides.hash(&mut hasher);
+    self.next_version.hash(&mut hasher);
+    self.layers.hash(&mut hasher);
+    self.pnp.hash(&mut hasher);
+    self.resolve_options.hash(&mut hasher);
+    self.react_server_resolve_options.hash(&mut hasher);
+    self.runtime_roots.hash(&mut hasher);
+    hasher.finish()
+  }
+
+  /// The counters of this handler, which aren't shared with other handlers.
+  pub fn stats(&self) -> &Arc<ExternalsStats> {
+    &self.stats
+  }
+
+  /// The project directory requests are resolved from.
+  pub fn dir(&self) -> &str {
+    &self.dir
+  }
+
+  /// The id of the context shared with other handlers, if any.
+  pub fn shared_context_id(&self) -> Option<&str> {
+    self.shared_context_id.as_deref()
+  }
+
+  /// The directories externalized packages have to resolve identically from.
+  pub fn runtime_roots(&self) -> &[String] {
+    &self.runtime_roots
+  }
+
+  /// Whether a resolved path belongs to one of the server external packages,
+  /// or matches `opt_out_bundling_package_regex`.
+  fn is_opt_out_bundling(&self, resolved: &str) -> bool {
+    node_modules_package_names(resolved)
+      .any(|name| self.server_external_packages.contains(name.as_ref()))
+      || self
+        .opt_out_bundling_package_regex
+        .as_ref()
+        .is_some_and(|regex| regex.test(resolved))
+  }
+
+  /// Returns the ESM externals mode for a request, taking the per-package
+  /// `loose_esm_externals_packages` and `strict_esm_externals_packages` lists into account.
+  /// Listing a package as loose opts it into ESM externals even when they are
+  /// disabled, so an ESM only package can stay external when required.
+  fn esm_externals_for(&self, request: &str) -> EsmExternalsConfig {
+    let experimental = &self.config.experimental;
+    let Some(package_name) = get_package_name(request) else {
+      return experimental.esm_externals.clone();
+    };
+    let is_listed = |packages: &[String]| packages.iter().any(|pkg| pkg == package_name);
+    if is_listed(&experimental.loose_esm_externals_packages) {
+      EsmExternalsConfig::Loose
+    } else if experimental.esm_externals.is_loose()
+      && is_listed(&experimental.strict_esm_externals_packages)
+    {
+      EsmExternalsConfig::Strict
+    } else {
+      experimental.esm_externals.clone()
+    }
+  }
+
+  /// Starts a new generation of decisions for a build. In watch mode, files
+  /// may have been added, removed or linked since the previous build, so
+  /// the memoized decisions made before it started are made again. The ones
+  /// the handlers sharing the context make from then on are reused, however
+  /// often each of them rebuilds.
+  pub fn start_build(&self) {
+    self.decisions.clear();
+    let shared = self.shared();
+    let generation = shared.generation.fetch_add(1, Ordering::Relaxed) + 1;
+    self.generation.store(generation, Ordering::Relaxed);
+    // Handlers still building the previous generation may reuse its
+    // decisions, older ones would only be made again.
+    shared.decision_cache.retain_since(generation - 1);
+  }
+
+  /// Returns the resolved `transpilePackages` directories, if a request has
+  /// needed them since they were last invalidated.
+  pub fn transpile_package_dirs(&self) -> Option<Arc<TranspilePackageDirs>> {
+    self
+      .shared()
+      .resolved_external_package_dirs
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .get()
+      .cloned()
+  }
+
+  /// Uses directories resolved by an earlier build, unless they are resolved
+  /// already.
+  pub fn restore_transpile_package_dirs(&self, transpile_package_dirs: TranspilePackageDirs) {
+    let shared = self.shared();
+    let mut dirs = shared
+      .resolved_external_package_dirs
+      .write()
+      .unwrap_or_else(PoisonError::into_inner);
+    if dirs.get().is_none() {
+      *dirs = Arc::new(tokio::sync::OnceCell::new_with(Some(Arc::new(
+        transpile_package_dirs,
+      ))));
+    }
+  }
+
+  /// Forgets the resolved `transpilePackages` directories if any of the
+  /// changed files affects them, so they are resolved again on the next
+  /// request. Returns whether they were invalidated.
+  pub fn invalidate_transpile_package_dirs<'a>(
+    &self,
+    changed_files: impl IntoIterator<Item = &'a Path>,
+  ) -> bool {
+    let shared = self.shared();
+    let mut dirs = shared
+      .resolved_external_package_dirs
+      .write()
+      .unwrap_or_else(PoisonError::into_inner);
+    let Some(resolved) = dirs.get() else {
+      return false;
+    };
+    let is_affected = changed_files
+      .into_iter()
+      .any(|changed_file| resolved.is_affected_by(changed_file));
+    if is_affected {
+      *dirs = Default::default();
+    }
+    is_affected
+  }
+
+  /// Resolves the directory of each of the `transpilePackages` from the
+  /// project `dir`, once until the directories are invalidated.
+  async fn resolve_transpile_package_dirs(
+    &self,
+    get_resolve: GetResolveFn,
+  ) -> rspack_error::Result<Arc<TranspilePackageDirs>> {
+    // Clone the cell out of the lock so it is not held across the await.
+    let cell = self
+      .shared()
+      .resolved_external_package_dirs
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .clone();
+    let resolved = cell
+      .get_or_try_init(|| async {
+        let mut resolved = TranspilePackageDirs::default();
+        let pnp_manifest = self
+          .pnp
+          .then(|| yarn_pnp::find_manifest(Path::new(&self.dir)))
+          .flatten()
+          .map(|manifest| normalize_path_sep(&manifest.to_string_lossy()));
+        if let Some(manifest) = &pnp_manifest {
+          resolved.package_jsons.push(manifest.clone());
+        }
+        for pkg in &self.transpiled_packages {
+          let pkg_res = resolve_external(
+            std::slice::from_ref(&self.dir),
+            &self.config.experimental.esm_externals,
+            self.dir.to_string(),
+            format!("{pkg}/package.json"),
+            false,
+            get_resolve.clone(),
+            None,
+            None,
+            Some(&self.resolve_options.esm),
+            Some(&self.resolve_options.node),
+            Some(&self.resolve_options.base_esm),
+            Some(&self.resolve_options.base),
+          )
+          .await?;
+
+          // Plug'n'Play packages are installed by updating the manifest,
+          // which is watched already.
+          let local_package_json = pnp_manifest.is_none().then(|| {
+            normalize_path_sep(
+              &Path::new(&self.dir)
+                .join("node_modules")
+                .join(pkg)
+                .join("package.json")
+                .to_string_lossy(),
+            )
+          });
+          match pkg_res.res.filter(|_| !pkg_res.base_resolve_mismatch) {
+            Some(res) => {
+              if let Some((parent, _)) = res.rsplit_once('/') {
+                resolved.dirs.insert(pkg.clone(), parent.to_string());
+              }
+              if let Some(local_package_json) =
+                local_package_json.filter(|local_package_json| *local_package_json != res)
+              {
+                resolved.missing_package_jsons.push(local_package_json);
+              }
+              resolved.package_jsons.push(res);
+            }
+            None => resolved.missing_package_jsons.extend(local_package_json),
+          }
+        }
+        Ok::<_, rspack_error::Error>(Arc::new(resolved))
+      })
+      .await?;
+    Ok(resolved.clone())
+  }
+
+  /// Returns the decisions this handler made or reused in its current build,
+  /// sorted by request.
+  pub fn trace_entries(&self) -> Vec<TraceEntry> {
+    let mut entries = self
+      .decisions
+      .iter()
+      .map(|entry| {
+        let (key, decision) = entry.pair();
+        TraceEntry {
+          request: key.request.clone(),
+          context: key.context.clone(),
+          dependency_type: key.dependency_type.clone(),
+          layer: key.layer.clone(),
+          rule: decision.rule,
+          resolved: decision.resolved.clone(),
+          external: decision.external.clone(),
+        }
+      })
+      .collect::<Vec<_>>();
+    // Sort so traces of two builds can be diffed.
+    entries.sort_unstable_by(|a, b| {
+      (&a.request, &a.context, &a.dependency_type, &a.layer).cmp(&(
+        &b.request,
+        &b.context,
+        &b.dependency_type,
+        &b.layer,
+      ))
+    });
+    entries
+  }
+
+  /// Adds decisions made by an earlier build, e.g. from `trace_entries`, to
+  /// the ones this handler reuses.
+  pub fn restore_decisions(&self, entries: impl IntoIterator<Item = TraceEntry>) {
+    let shared = self.shared();
+    let generation = shared.generation.load(Ordering::Relaxed);
+    for entry in entries {
+      let TraceEntry {
+        request,
+        context,
+        dependency_type,
+        layer,
+        rule,
+        resolved,
+        external,
+      } = entry;
+      shared.decision_cache.restore(
+        DecisionKey {
+          context,
+          request,
+          dependency_type,
+          layer,
+        },
+        generation,
+        ExternalsDecision {
+          rule,
+          resolved,
+          external,
+        },
+      );
+    }
+  }
+
+  /// Returns the cache counters of this handler accumulated since the
+  /// previous call and resets them.
+  pub fn take_decision_cache_stats(&self) -> DecisionCacheStats {
+    self.decision_cache_counters.take()
+  }
+
+  /// Whether a resolved path belongs to one of the transpiled packages.
+  async fn is_transpiled(
+    &self,
+    res: &str,
+    get_resolve: GetResolveFn,
+  ) -> rspack_error::Result<bool> {
+    if self.transpiled_packages.is_empty() {
+      return Ok(false);
+    }
+    let transpile_package_dirs = self.resolve_transpile_package_dirs(get_resolve).await?;
+    Ok(is_resource_in_packages(
+      res,
+      &self.transpiled_packages,
+      Some(&transpile_package_dirs.dirs),
+    ))
+  }
+
   fn resolve_bundling_opt_out_packages(
     &self,
     resolved_res: &str,
@@ -208,7 +1130,8 @@ impl ExternalHandler {
     external_type: &str,
     is_opt_out_bundling: bool,
     request: &str,
-  ) -> Option<String> {
+    transpile_package_dirs: Option<&TranspilePackageDirs>,
+  ) -> (DecisionRule, Option<String>) {
     if NODE_MODULES_REGEX.is_match(resolved_res) {
       let should_bundle_pages = !is_app_layer
         && self
@@ -217,20 +1140,27 @@ impl ExternalHandler {
           .unwrap_or(false)
         && !is_opt_out_bundling;
 
-      let should_be_bundled = should_bundle_pages
-        || is_resource_in_packages(
-          resolved_res,
-          &self.transpiled_packages,
-          self.resolved_external_package_dirs.get(),
-        );
+      if should_bundle_pages {
+        return (DecisionRule::BundlePagesRouterDependencies, None);
+      }
 
-      if !should_be_bundled {
-        return Some(format!("{external_type} {request}"));
+      if is_resource_in_packages(
+        resolved_res,
+        &self.transpiled_packages,
+        transpile_package_dirs.map(|resolved| &resolved.dirs),
+      ) {
+        return (DecisionRule::TranspilePackage, None);
       }
+
+      return (
+        DecisionRule::NodeModulesExternal,
+        Some(format!("{external_type} {request}")),
+      );
     }
-    None
+    (DecisionRule::Default, None)
   }
 
+  #[allow(dead_code)]
   pub async fn handle_externals(
     &self,
     context: String,
@@ -239,6 +1169,69 @@ impl ExternalHandler {
     layer: Option<&str>,
     get_resolve: GetResolveFn,
   ) -> rspack_error::Result<Option<String>> {
+    Ok(
+      self
+        .decide(context, request, dependency_type, layer, get_resolve)
+        .await?
+        .external,
+    )
+  }
+
+  /// Like `handle_externals`, but also reports which rule made the decision.
+  pub async fn decide(
+    &self,
+    context: String,
+    request: String,
+    dependency_type: &str,
+    layer: Option<&str>,
+    get_resolve: GetResolveFn,
+  ) -> rspack_error::Result<ExternalsDecision> {
+    let key = DecisionKey {
+      context: context.clone(),
+      request: request.clone(),
+      dependency_type: dependency_type.to_string(),
+      layer: layer.map(|layer| layer.to_string()),
+    };
+    let start = Instant::now();
+    let stats = self.stats.clone();
+    let get_resolve: GetResolveFn = Arc::new(move |options| {
+      let resolve = get_resolve(options);
+      let stats = stats.clone();
+      Box::new(move |context, request| {
+        stats.record_resolver_call();
+        resolve(context, request)
+      })
+    });
+    let shared = self.shared();
+    let decision = shared
+      .decision_cache
+      .get_or_try_init(
+        key.clone(),
+        self.generation.load(Ordering::Relaxed),
+        shared.generation.load(Ordering::Relaxed),
+        &self.decision_cache_counters,
+        self.handle_externals_uncached(context, request, dependency_type, layer, get_resolve),
+      )
+      .await;
+    if let Ok(decision) = &decision {
+      self.decisions.insert(key, decision.clone());
+    }
+    let rule = match &decision {
+      Ok(decision) => decision.rule.as_str(),
+      Err(_) => "error",
+    };
+    self.stats.record_call(layer, rule, start.elapsed());
+    decision
+  }
+
+  async fn handle_externals_uncached(
+    &self,
+    context: String,
+    request: String,
+    dependency_type: &str,
+    layer: Option<&str>,
+    get_resolve: GetResolveFn,
+  ) -> rspack_error::Result<ExternalsDecision> {
     // We need to externalize internal requests for files intended to
     // not be bundled.
     let is_local = request.starts_with('.') || Path::new(&request).is_absolute();
@@ -246,12 +1239,13 @@ impl ExternalHandler {
     // make sure import "next" shows a warning when imported
     // in pages/components
     if request == "next" {
-      return Ok(Some(
-        "commonjs next/dist/lib/import-next-warning".to_string(),
+      return Ok(ExternalsDecision::new(
+        DecisionRule::ImportNextWarning,
+        Some("commonjs next/dist/lib/import-next-warning".to_string()),
       ));
     }
 
-    let is_app_layer = is_webpack_bundled_layer(layer);
+    let is_app_layer = self.layers.is_bundled(layer);
 
     // Relative requires don't need custom resolution, because they
     // are relative to requests we've already resolved here.
@@ -259,30 +1253,46 @@ impl ExternalHandler {
     // also have no need for customization as they're already resolved.
     if !is_local {
       if request == "next" {
-        return Ok(Some(format!("commonjs {request}")));
+        return Ok(ExternalsDecision::new(
+          DecisionRule::Next,
+          Some(format!("commonjs {request}")),
+        ));
       }
 
       // Handle React packages
       if REACT_PACKAGES_REGEX.is_match(&request) && !is_app_layer {
-        return Ok(Some(format!("commonjs {request}")));
+        return Ok(ExternalsDecision::new(
+          DecisionRule::ReactPackage,
+          Some(format!("commonjs {request}")),
+        ));
       }
 
       // Skip modules that should not be external
-      if NOT_EXTERNAL_MODULES_REGEX.is_match(&request) {
-        return Ok(None);
+      if self
+        .next_version
+        .not_external_modules_regex()
+        .is_match(&request)
+      {
+        return Ok(ExternalsDecision::new(
+          DecisionRule::NotExternalModule,
+          None,
+        ));
       }
     }
 
     // @swc/helpers should not be external as it would
     // require hoisting the package which we can't rely on
     if request.contains("@swc/helpers") {
-      return Ok(None);
+      return Ok(ExternalsDecision::new(DecisionRule::SwcHelpers, None));
     }
 
     // BARREL_OPTIMIZATION_PREFIX is a special marker that tells Next.js to
     // optimize the import by removing unused exports. This has to be compiled.
     if request.starts_with(BARREL_OPTIMIZATION_PREFIX) {
-      return Ok(None);
+      return Ok(ExternalsDecision::new(
+        DecisionRule::BarrelOptimization,
+        None,
+      ));
     }
 
     // When in esm externals mode, and using import, we resolve with
@@ -293,10 +1303,12 @@ impl ExternalHandler {
     // Don't bundle @vercel/og nodejs bundle for nodejs runtime.
     // TODO-APP: bundle route.js with different layer that externals common node_module deps.
     // Make sure @vercel/og is loaded as ESM for Node.js runtime
-    if should_use_react_server_condition(layer)
-      && request == "next/dist/compiled/@vercel/og/index.node.js"
+    if self.layers.is_server_only(layer) && request == "next/dist/compiled/@vercel/og/index.node.js"
     {
-      return Ok(Some(format!("module {request}")));
+      return Ok(ExternalsDecision::new(
+        DecisionRule::VercelOg,
+        Some(format!("module {request}")),
+      ));
     }
 
     // Specific Next.js imports that should remain external
@@ -305,28 +1317,50 @@ impl ExternalHandler {
       // Non external that needs to be transpiled
       // Image loader needs to be transpiled
       if NEXT_IMAGE_LOADER_REGEX.is_match(&request) {
-        return Ok(None);
+        return Ok(ExternalsDecision::new(DecisionRule::NextImageLoader, None));
       }
 
       if NEXT_SERVER_REGEX.is_match(&request) {
-        return Ok(Some(format!("commonjs {request}")));
+        return Ok(ExternalsDecision::new(
+          DecisionRule::NextServer,
+          Some(format!("commonjs {request}")),
+        ));
       }
 
       if NEXT_SHARED_CJS_REGEX.test(&request) || NEXT_COMPILED_CJS_REGEX.is_match(&request) {
-        return Ok(Some(format!("commonjs {request}")));
+        return Ok(ExternalsDecision::new(
+          DecisionRule::NextSharedCjs,
+          Some(format!("commonjs {request}")),
+        ));
       }
 
       if NEXT_SHARED_ESM_REGEX.test(&request) || NEXT_COMPILED_MJS_REGEX.is_match(&request) {
-        return Ok(Some(format!("module {request}")));
+        return Ok(ExternalsDecision::new(
+          DecisionRule::NextSharedEsm,
+          Some(format!("module {request}")),
+        ));
       }
 
-      return Ok(resolve_next_external(&request));
+      return Ok(ExternalsDecision::new(
+        DecisionRule::NextDistExternal,
+        resolve_next_external(&request),
+      ));
     }
 
+    let esm_externals = self.esm_externals_for(&request);
+
+    // Packages may export other files to the server-only layers, resolve the
+    // ones the runtime of the layer loads.
+    let resolve_options = if self.layers.is_server_only(layer) {
+      &self.react_server_resolve_options
+    } else {
+      &self.resolve_options
+    };
+
     // TODO-APP: Let's avoid this resolve call as much as possible, and eventually get rid of it.
     let resolve_result = resolve_external(
-      self.dir.to_string(),
-      &self.config.experimental.esm_externals,
+      &self.runtime_roots,
+      &esm_externals,
       context.to_string(),
       request.to_string(),
       is_esm_requested,
@@ -337,52 +1371,102 @@ impl ExternalHandler {
         None
       },
       None,
-      None,
-      None,
-      None,
-      None,
+      Some(&resolve_options.esm),
+      Some(&resolve_options.node),
+      Some(&resolve_options.base_esm),
+      Some(&resolve_options.base),
     )
     .await?;
+    self
+      .stats
+      .record_base_resolve_mismatches(resolve_result.base_resolve_mismatches);
 
     if let Some(local_res) = resolve_result.local_res {
-      return Ok(Some(local_res));
+      return Ok(ExternalsDecision::new(
+        DecisionRule::LocalNextExternal,
+        Some(local_res),
+      ));
+    }
+
+    // Other local files are bundled, except for native addons. These are
+    // required by their path, as the request is relative to the issuer and
+    // not to the bundle.
+    if is_local {
+      return Ok(match resolve_result.res {
+        Some(res) if native_addons::is_native_addon(&res) => {
+          ExternalsDecision::new(DecisionRule::NativeAddon, Some(format!("commonjs {res}")))
+            .with_resolved(res)
+        }
+        Some(res) => ExternalsDecision::new(DecisionRule::LocalFile, None).with_resolved(res),
+        None => ExternalsDecision::new(DecisionRule::Unresolved, None),
+      });
     }
 
     // Forcedly resolve the styled-jsx installed by next.js,
     // since `resolveExternal` cannot find the styled-jsx dep with pnpm
-    let (res, is_esm) = if request == "styled-jsx/style" {
+    let (res, is_esm, is_native, base_resolve_mismatch) = if request == "styled-jsx/style" {
       (
         self
           .default_overrides
           .get("styled-jsx/style")
-          .map(|s| s.to_string()),
+          .map(|s| normalize_path_sep(s)),
         resolve_result.is_esm,
+        false,
+        false,
       )
     } else {
-      (resolve_result.res, resolve_result.is_esm)
+      (
+        resolve_result.res,
+        resolve_result.is_esm,
+        resolve_result.is_native,
+        resolve_result.base_resolve_mismatch,
+      )
     };
 
     let Some(res) = res else {
       // If the request cannot be resolved we need to have
       // webpack "bundle" it so it surfaces the not found error.
-      return Ok(None);
+      return Ok(ExternalsDecision::new(DecisionRule::Unresolved, None));
     };
 
-    let is_opt_out_bundling = self.opt_out_bundling_package_regex.test(&res);
+    // The package available at runtime isn't the one resolved here, so it
+    // has to be bundled.
+    if base_resolve_mismatch {
+      return Ok(
+        ExternalsDecision::new(DecisionRule::BaseResolveMismatch, None).with_resolved(res),
+      );
+    }
+
+    // Native addons can't be bundled, so they are external even in the
+    // bundled layers. The packages loading them are too, unless they are
+    // transpiled. Packages loading them transitively are kept working as their
+    // own requests of native packages end up here.
+    if is_native
+      && (native_addons::is_native_addon(&res)
+        || !self.is_transpiled(&res, get_resolve.clone()).await?)
+    {
+      let external_type = if is_esm { "module" } else { "commonjs" };
+      return Ok(
+        ExternalsDecision::new(
+          DecisionRule::NativeAddon,
+          Some(format!("{external_type} {request}")),
+        )
+        .with_resolved(res),
+      );
+    }
+
+    let is_opt_out_bundling = self.is_opt_out_bundling(&res);
 
     // Apply bundling rules to all app layers.
     // Since handleExternals only handle the server layers, we don't need to exclude client here
     if !is_opt_out_bundling && is_app_layer {
-      return Ok(None);
+      return Ok(ExternalsDecision::new(DecisionRule::BundledLayer, None).with_resolved(res));
     }
 
     // ESM externals can only be imported (and not required).
     // Make an exception in loose mode.
-    if !is_esm_requested && is_esm && !self.loose_esm_externals && !is_local {
-      return Err(rspack_error::error!(
-        "ESM packages ({}) need to be imported. Use 'import' to reference the package instead. https://nextjs.org/docs/messages/import-esm-externals",
-        request
-      ));
+    if !is_esm_requested && is_esm && !esm_externals.is_loose() && !is_local {
+      return Err(EsmExternalsError::new(request, res).into());
     }
 
     let external_type = if is_esm { "module" } else { "commonjs" };
@@ -390,87 +1474,47 @@ impl ExternalHandler {
     // Default pages have to be transpiled
     // This is the @babel/plugin-transform-runtime "helpers: true" option
     if BABEL_RUNTIME_REGEX.is_match(&res) {
-      return Ok(None);
+      return Ok(ExternalsDecision::new(DecisionRule::BabelRuntime, None).with_resolved(res));
     }
 
     // Webpack itself has to be compiled because it doesn't always use module relative paths
     if WEBPACK_CSS_LOADER_REGEX.is_match(&res) {
-      return Ok(None);
+      return Ok(ExternalsDecision::new(DecisionRule::WebpackCssLoader, None).with_resolved(res));
     }
 
     // If a package should be transpiled by Next.js, we skip making it external.
     // It doesn't matter what the extension is, as we'll transpile it anyway.
-    if !self.transpiled_packages.is_empty() && self.resolved_external_package_dirs.get().is_none() {
-      let mut resolved_dirs = FxHashMap::default();
-
-      // We need to resolve all the external package dirs initially.
-      for pkg in &self.transpiled_packages {
-        let pkg_request = format!("{pkg}/package.json");
-        let pkg_res = resolve_external(
-          self.dir.to_string(),
-          &self.config.experimental.esm_externals,
-          context.to_string(),
-          pkg_request,
-          is_esm_requested,
-          get_resolve.clone(),
-          if is_local {
-            Some(Box::new(resolve_next_external))
-          } else {
-            None
-          },
-          None,
-          None,
-          None,
-          None,
-          None,
-        )
-        .await?;
-
-        if let Some(res) = pkg_res.res {
-          if let Some(parent) = Path::new(&res).parent() {
-            resolved_dirs.insert(pkg.clone(), parent.to_string_lossy().to_string());
-          }
-        }
-      }
-
-      self
-        .resolved_external_package_dirs
-        .get_or_init(move || resolved_dirs);
-    }
+    let transpile_package_dirs = if self.transpiled_packages.is_empty() {
+      None
+    } else {
+      Some(
+        self
+          .resolve_transpile_package_dirs(get_resolve.clone())
+          .await?,
+      )
+    };
 
-    let resolved_bundling_opt_out_res = self.resolve_bundling_opt_out_packages(
+    // if the rule yields no external, we default to bundling the file
+    let (rule, resolved_bundling_opt_out_res) = self.resolve_bundling_opt_out_packages(
       &res,
       is_app_layer,
       external_type,
       is_opt_out_bundling,
       &request,
+      transpile_package_dirs.as_deref(),
     );
 
-    if resolved_bundling_opt_out_res.is_some() {
-      return Ok(resolved_bundling_opt_out_res);
-    }
-
-    // if here, we default to bundling the file
-    Ok(None)
+    Ok(ExternalsDecision::new(rule, resolved_bundling_opt_out_res).with_resolved(res))
   }
 }
 
-static EXTERNAL_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
-  let path_separators = r"[/\\]";
-  let optional_esm_part = format!(r"(({path_separators})esm)?{path_separators}",);
-  let external_file_end = r"(\.external(\.js)?)$";
-  let next_dist = format!(r"next{path_separators}dist");
-
-  Regex::new(&format!(
-    r"{next_dist}{optional_esm_part}.*{external_file_end}"
-  ))
-  .unwrap()
-});
+static EXTERNAL_PATTERN: LazyLock<Regex> =
+  LazyLock::new(|| Regex::new(r"next/dist(/esm)?/.*(\.external(\.js)?)$").unwrap());
 
 static NEXT_DIST_REPLACE_PATTERN: LazyLock<Regex> =
-  LazyLock::new(|| Regex::new(r".*?next[/\\]dist").unwrap());
+  LazyLock::new(|| Regex::new(r".*?next/dist").unwrap());
 
-/// Returns an externalized path if the file is a Next.js file and ends with either `.shared-runtime.js` or `.external.js`
+/// Returns an externalized path if the file is a Next.js file and ends with `.external` or `.external.js`
 /// This is used to ensure that files used across the rendering runtime(s) and the user code are one and the same.
 /// The logic in this function will rewrite the require to the correct bundle location depending on the layer at which the file is being used.
 ///
@@ -480,15 +1524,15 @@ static NEXT_DIST_REPLACE_PATTERN: LazyLock<Regex> =
 /// # Returns
 /// * `Option<String>` - the externalized path, or None if not external
 pub fn resolve_next_external(local_res: &str) -> Option<String> {
-  let is_external = EXTERNAL_PATTERN.is_match(local_res);
+  let local_res = normalize_path_sep(local_res);
+  let is_external = EXTERNAL_PATTERN.is_match(&local_res);
 
   // If the file ends with .external, we need to make it a commonjs require in all cases
   // This is used mainly to share the async local storage across the routing, rendering and user layers.
   if is_external {
     // It's important we return the path that starts with `next/dist/` here instead of the absolute path
     // otherwise NFT will get tripped up
-    let normalized_path = NEXT_DIST_REPLACE_PATTERN.replace(local_res, "next/dist");
-    let normalized_path = normalize_path_sep(&normalized_path);
+    let normalized_path = NEXT_DIST_REPLACE_PATTERN.replace(&local_res, "next/dist");
 
     Some(format!("commonjs {normalized_path}"))
   } else {
@@ -496,16 +1540,45 @@ pub fn resolve_next_external(local_res: &str) -> Option<String> {
   }
 }
 
+/// Returns the package name of a bare request, e.g. `@scope/pkg` for `@scope/pkg/sub/path`.
+fn get_package_name(request: &str) -> Option<&str> {
+  if request.starts_with('.') || Path::new(request).is_absolute() {
+    return None;
+  }
+  let mut segments = request.splitn(3, '/');
+  let first = segments.next().filter(|segment| !segment.is_empty())?;
+  if first.starts_with('@') {
+    let second = segments.next().filter(|segment| !segment.is_empty())?;
+    Some(&request[..first.len() + 1 + second.len()])
+  } else {
+    Some(first)
+  }
+}
+
 /// Normalize path separators to forward slashes
-fn normalize_path_sep(path: &str) -> String {
+pub(crate) fn normalize_path_sep(path: &str) -> String {
   path.replace('\\', "/")
 }
 
+/// Whether a normalized path is inside a normalized directory.
+fn is_path_in_dir(path: &str, dir: &str) -> bool {
+  path
+    .strip_prefix(dir)
+    .is_some_and(|rest| rest.starts_with('/'))
+}
+
 #[derive(Debug)]
 pub struct ResolveResult {
   pub res: Option<String>,
   pub is_esm: bool,
+  /// Whether `res` is a native addon, or a module of a package loading them.
+  pub is_native: bool,
   pub local_res: Option<String>,
+  /// Whether `res` is something else than what the request resolves to from
+  /// the runtime roots, so it has to be bundled.
+  pub base_resolve_mismatch: bool,
+  /// How often the request resolved to something else from the runtime roots.
+  pub base_resolve_mismatches: u32,
 }
 
 impl EsmExternalsConfig {
@@ -518,22 +1591,34 @@ impl EsmExternalsConfig {
   }
 }
 
-type ResolveFn = Box<
+/// A request resolved by a `ResolveFn`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolvedRequest {
+  pub path: Option<String>,
+  pub is_esm: bool,
+  /// Whether the path is a native addon, or a module of a package loading
+  /// them.
+  pub is_native: bool,
+  /// The package version the path is in, if its `package.json` has both a
+  /// name and a version.
+  pub package: Option<PackageIdentity>,
+}
+
+pub(crate) type ResolveFn = Box<
   dyn Fn(
       String,
       String,
-    ) -> Pin<
-      Box<dyn Future<Output = rspack_error::Result<(Option<String>, bool)>> + Send + 'static>,
-    > + Send
+    ) -> Pin<Box<dyn Future<Output = rspack_error::Result<ResolvedRequest>> + Send + 'static>>
+    + Send
     + 'static,
 >;
-type GetResolveFn =
+pub(crate) type GetResolveFn =
   Arc<dyn Fn(Option<ResolveOptionsWithDependencyType>) -> ResolveFn + Send + Sync + 'static>;
 type IsLocalCallbackFn = Box<dyn Fn(&str) -> Option<String> + Send + Sync + 'static>;
 
 #[allow(clippy::too_many_arguments)]
 pub async fn resolve_external(
-  dir: String,
+  runtime_roots: &[String],
   esm_externals_config: &EsmExternalsConfig,
   context: String,
   request: String,
@@ -551,6 +1636,10 @@ pub async fn resolve_external(
 
   let mut res: Option<String> = None;
   let mut is_esm = false;
+  let mut is_native = false;
+  let mut package = None;
+  let mut base_resolve_mismatch = false;
+  let mut base_resolve_mismatches = 0;
 
   let prefer_esm_options = if esm_externals && is_esm_requested {
     vec![true, false]
@@ -572,13 +1661,16 @@ pub async fn resolve_external(
     };
 
     let resolve = get_resolve(Some(resolve_options.clone()));
+    base_resolve_mismatch = false;
 
     // Resolve the import with the webpack provided context, this
     // ensures we're resolving the correct version when multiple exist.
     match resolve(context.to_string(), request.to_string()).await {
-      Ok((resolved_path, resolved_is_esm)) => {
-        res = resolved_path;
-        is_esm = resolved_is_esm;
+      Ok(resolved) => {
+        res = resolved.path.as_deref().map(normalize_path_sep);
+        is_esm = resolved.is_esm;
+        is_native = resolved.is_native;
+        package = resolved.package;
       }
       Err(_) => {
         res = None;
@@ -598,9 +1690,12 @@ pub async fn resolve_external(
     if let Some(is_local_callback) = &is_local_callback {
       if let Some(ref resolved) = res {
         return Ok(ResolveResult {
-          res: None,
-          is_esm: false,
           local_res: is_local_callback(resolved),
+          res: Some(resolved.clone()),
+          is_esm: false,
+          is_native: false,
+          base_resolve_mismatch,
+          base_resolve_mismatches,
         });
       }
     }
@@ -618,18 +1713,55 @@ pub async fn resolve_external(
 
       let base_resolve = get_resolve(Some(resolve_options.clone()));
 
-      let (base_res, base_is_esm) = match base_resolve(dir.to_string(), request.to_string()).await {
-        Ok((resolved_path, resolved_is_esm)) => (resolved_path, resolved_is_esm),
-        Err(_) => (None, false),
-      };
-
-      // Same as above: if the package, when required from the root,
-      // would be different from what the real resolution would use, we
-      // cannot externalize it.
-      // If request is pointing to a symlink it could point to the same file,
-      // the resolver will resolve symlinks so this is handled
-      if base_res != res || is_esm != base_is_esm {
-        res = None;
+      // The package has to be available from one of the roots the server
+      // resolves packages from at runtime.
+      let mut resolves_identically = false;
+      let mut other_version = None;
+      for root in runtime_roots {
+        let (base_res, base_is_esm, base_package) =
+          match base_resolve(root.to_string(), request.to_string()).await {
+            Ok(resolved) => (
+              resolved.path.as_deref().map(normalize_path_sep),
+              resolved.is_esm,
+              resolved.package,
+            ),
+            Err(_) => (None, false, None),
+          };
+
+        // Same as above: if the package, when required from the root,
+        // would be different from what the real resolution would use, we
+        // cannot externalize it.
+        // Paths may differ while loading the same module, through symlinks
+        // or copies of the same package version, so they are compared by
+        // real path and package version rather than as strings.
+        let is_same_module = match (&base_res, &res) {
+          (Some(base_res), Some(res)) => {
+            package_identity::is_same_module(base_res, base_package.as_ref(), res, package.as_ref())
+          }
+          _ => base_res == res,
+        };
+        if is_same_module && is_esm == base_is_esm {
+          resolves_identically = true;
+          break;
+        }
+        if let (Some(base_res), Some(base_package), Some(package)) =
+          (base_res, base_package, &package)
+        {
+          if other_version.is_none() && base_package.is_other_version(package) {
+            other_version = Some((root, base_res, base_package));
+          }
+        }
+      }
+      if !resolves_identically {
+        if let (Some((root, base_res, base_package)), Some(res), Some(package)) =
+          (&other_version, &res, &package)
+        {
+          tracing::debug!(
+            "Bundling '{request}' requested in '{context}': it resolves to {package} ('{res}'), but to {base_package} ('{base_res}') from the runtime root '{root}'"
+          );
+        }
+        base_resolve_mismatch = true;
+        base_resolve_mismatches += 1;
         continue;
       }
     }
@@ -638,8 +1770,14 @@ pub async fn resolve_external(
   }
 
   Ok(ResolveResult {
+    is_native: is_native && res.is_some(),
     res,
     is_esm,
     local_res: None,
+    base_resolve_mismatch,
+    base_resolve_mismatches,
   })
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1861 @@
+use std::{
+  sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+  time::Duration,
+};
+
+use rspack_regex::RspackRegex;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde_json::json;
+
+use super::*;
+use crate::{
+  config_shared::ExperimentalConfig, test_utils::PackageTree,
+  webpack_layers::WEBPACK_BUNDLED_LAYERS,
+};
+
+const DIR: &str = "/app";
+const PAGES: &str = "/app/pages";
+const STYLED_JSX_STYLE: &str = "/app/node_modules/next/node_modules/styled-jsx/style.js";
+
+const ESM_MODES: &[EsmExternalsConfig] = &[
+  EsmExternalsConfig::None,
+  EsmExternalsConfig::Loose,
+  EsmExternalsConfig::Strict,
+];
+
+/// Every layer `handle_externals` runs for on the server: the bundled app
+/// layers and the pages layer (`None`).
+fn layers() -> impl Iterator<Item = Option<&'static str>> {
+  WEBPACK_BUNDLED_LAYERS
+    .iter()
+    .copied()
+    .map(Some)
+    .chain([None])
+}
+
+fn fixture() -> PackageTree {
+  PackageTree::new()
+    .with_package_json("/app", json!({ "name": "app" }))
+    .with_file("/app/pages/index.js", "")
+    .with_file("/app/lib/utils.js", "")
+    .with_package_json("/app/node_modules/react", json!({ "main": "index.js" }))
+    .with_file("/app/node_modules/react/index.js", "")
+    .with_package_json("/app/node_modules/cjs-pkg", json!({ "main": "index.js" }))
+    .with_file("/app/node_modules/cjs-pkg/index.js", "")
+    .with_file("/app/node_modules/cjs-pkg/data.json", "{}")
+    .with_package_json(
+      "/app/node_modules/esm-only",
+      json!({ "type": "module", "main": "index.js" }),
+    )
+    .with_file("/app/node_modules/esm-only/index.js", "")
+    .with_package_json(
+      "/app/node_modules/dual",
+      json!({ "exports": { "import": "./index.mjs", "require": "./index.cjs" } }),
+    )
+    .with_file("/app/node_modules/dual/index.mjs", "")
+    .with_file("/app/node_modules/dual/index.cjs", "")
+    .with_package_json(
+      "/app/node_modules/opt-out-pkg",
+      json!({ "main": "index.js" }),
+    )
+    .with_file("/app/node_modules/opt-out-pkg/index.js", "")
+    .with_package_json(
+      "/app/node_modules/@prisma/client",
+      json!({ "main": "index.js" }),
+    )
+    .with_file("/app/node_modules/@prisma/client/index.js", "")
+    .with_package_json(
+      "/app/node_modules/transpiled-pkg",
+      json!({ "main": "index.js" }),
+    )
+    .with_file("/app/node_modules/transpiled-pkg/index.js", "")
+    .with_package_json("/app/node_modules/@babel/runtime", json!({}))
+    .with_file(
+      "/app/node_modules/@babel/runtime/helpers/interopRequireDefault.js",
+      "",
+    )
+    .with_package_json(
+      "/app/node_modules/webpack",
+      json!({ "main": "lib/index.js" }),
+    )
+    .with_file("/app/node_modules/webpack/lib/index.js", "")
+    // Two versions of `dup`: the hoisted one and the one nested under `parent`.
+    .with_package_json("/app/node_modules/dup", json!({ "version": "1.0.0" }))
+    .with_file("/app/node_modules/dup/index.js", "")
+    .with_package_json("/app/node_modules/parent", json!({ "main": "index.js" }))
+    .with_file("/app/node_modules/parent/index.js", "")
+    .with_package_json(
+      "/app/node_modules/parent/node_modules/dup",
+      json!({ "version": "2.0.0" }),
+    )
+    .with_file("/app/node_modules/parent/node_modules/dup/index.js", "")
+    // `styled-jsx` is only reachable from `next`, as with pnpm.
+    .with_package_json("/app/node_modules/next", json!({ "main": "index.js" }))
+    .with_file("/app/node_modules/next/index.js", "")
+    .with_file(
+      "/app/node_modules/next/dist/server/app-render/work-unit-async-storage.external.js",
+      "",
+    )
+    .with_file("/app/node_modules/next/dist/server/render.js", "")
+    .with_package_json("/app/node_modules/next/node_modules/styled-jsx", json!({}))
+    .with_file(STYLED_JSX_STYLE, "")
+    .with_alias("aliased", "/app/node_modules/cjs-pkg/index.js")
+}
+
+fn handler(config: NextConfigComplete) -> ExternalHandler {
+  handler_with_server_external_packages(config, vec!["@prisma/client".to_string()])
+}
+
+fn handler_with_server_external_packages(
+  config: NextConfigComplete,
+  default_server_external_packages: Vec<String>,
+) -> ExternalHandler {
+  let mut default_overrides = FxHashMap::default();
+  default_overrides.insert("styled-jsx/style".to_string(), STYLED_JSX_STYLE.to_string());
+  ExternalHandler::new(
+    config,
+    Some(RspackRegex::new(r"[/\\]node_modules[/\\]opt-out-pkg[/\\]").unwrap()),
+    default_server_external_packages,
+    vec!["transpiled-pkg".to_string()],
+    DIR.to_string(),
+    default_overrides,
+  )
+}
+
+fn handler_with_esm_externals(esm_externals: EsmExternalsConfig) -> ExternalHandler {
+  handler(NextConfigComplete {
+    experimental: ExperimentalConfig {
+      esm_externals,
+      ..Default::default()
+    },
+    ..Default::default()
+  })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Expected {
+  Decision(DecisionRule, Option<String>),
+  Error(&'static str),
+}
+
+fn decision(rule: DecisionRule, external: Option<&str>) -> Expected {
+  Expected::Decision(rule, external.map(|external| external.to_string()))
+}
+
+fn bundled_or(layer: Option<&str>, expected: Expected) -> Expected {
+  if WebpackLayers::default().is_bundled(layer) {
+    decision(DecisionRule::BundledLayer, None)
+  } else {
+    expected
+  }
+}
+
+struct Case {
+  name: &'static str,
+  context: &'static str,
+  request: &'static str,
+  dependency_type: &'static str,
+  expect: fn(Option<&str>, &EsmExternalsConfig) -> Expected,
+}
+
+const CASES: &[Case] = &[
+  Case {
+    name: "import of next shows a warning",
+    context: PAGES,
+    request: "next",
+    dependency_type: "cjs",
+    expect: |_, _| {
+      decision(
+        DecisionRule::ImportNextWarning,
+        Some("commonjs next/dist/lib/import-next-warning"),
+      )
+    },
+  },
+  Case {
+    name: "react is external outside of the app layers",
+    context: PAGES,
+    request: "react",
+    dependency_type: "cjs",
+    expect: |layer, _| {
+      bundled_or(
+        layer,
+        decision(DecisionRule::ReactPackage, Some("commonjs react")),
+      )
+    },
+  },
+  Case {
+    name: "next entry points are never external",
+    context: PAGES,
+    request: "next/link",
+    dependency_type: "esm",
+    expect: |_, _| decision(DecisionRule::NotExternalModule, None),
+  },
+  Case {
+    name: "swc helpers are bundled",
+    context: PAGES,
+    request: "@swc/helpers/_/_interop_require_default",
+    dependency_type: "cjs",
+    expect: |_, _| decision(DecisionRule::SwcHelpers, None),
+  },
+  Case {
+    name: "barrel optimized imports are bundled",
+    context: PAGES,
+    request: "__barrel_optimize__?names=Button!=!cjs-pkg",
+    dependency_type: "esm",
+    expect: |_, _| decision(DecisionRule::BarrelOptimization, None),
+  },
+  Case {
+    name: "vercel og is a module in server only layers",
+    context: PAGES,
+    request: "next/dist/compiled/@vercel/og/index.node.js",
+    dependency_type: "esm",
+    expect: |layer, _| {
+      if WebpackLayers::default().is_server_only(layer) {
+        decision(
+          DecisionRule::VercelOg,
+          Some("module next/dist/compiled/@vercel/og/index.node.js"),
+        )
+      } else {
+        decision(
+          DecisionRule::NextSharedCjs,
+          Some("commonjs next/dist/compiled/@vercel/og/index.node.js"),
+        )
+      }
+    },
+  },
+  Case {
+    name: "next image loader is transpiled",
+    context: PAGES,
+    request: "next/dist/shared/lib/image-loader",
+    dependency_type: "esm",
+    expect: |_, _| decision(DecisionRule::NextImageLoader, None),
+  },
+  Case {
+    name: "next server runtime is external",
+    context: PAGES,
+    request: "next/dist/compiled/next-server/app-page.runtime.prod.js",
+    dependency_type: "cjs",
+    expect: |_, _| {
+      decision(
+        DecisionRule::NextServer,
+        Some("commonjs next/dist/compiled/next-server/app-page.runtime.prod.js"),
+      )
+    },
+  },
+  Case {
+    name: "next shared cjs is external",
+    context: PAGES,
+    request: "next/dist/shared/lib/utils",
+    dependency_type: "cjs",
+    expect: |_, _| {
+      decision(
+        DecisionRule::NextSharedCjs,
+        Some("commonjs next/dist/shared/lib/utils"),
+      )
+    },
+  },
+  Case {
+    name: "next shared router is not treated as shared",
+    context: PAGES,
+    request: "next/dist/shared/lib/router/router",
+    dependency_type: "cjs",
+    expect: |_, _| decision(DecisionRule::NextDistExternal, None),
+  },
+  Case {
+    name: "next shared esm is a module",
+    context: PAGES,
+    request: "next/dist/esm/shared/lib/utils",
+    dependency_type: "esm",
+    expect: |_, _| {
+      decision(
+        DecisionRule::NextSharedEsm,
+        Some("module next/dist/esm/shared/lib/utils"),
+      )
+    },
+  },
+  Case {
+    name: "next compiled mjs is a module",
+    context: PAGES,
+    request: "next/dist/compiled/foo/index.mjs",
+    dependency_type: "esm",
+    expect: |_, _| {
+      decision(
+        DecisionRule::NextSharedEsm,
+        Some("module next/dist/compiled/foo/index.mjs"),
+      )
+    },
+  },
+  Case {
+    name: "next external files are external",
+    context: PAGES,
+    request: "next/dist/server/app-render/work-async-storage.external",
+    dependency_type: "cjs",
+    expect: |_, _| {
+      decision(
+        DecisionRule::NextDistExternal,
+        Some("commonjs next/dist/server/app-render/work-async-storage.external"),
+      )
+    },
+  },
+  Case {
+    name: "other next dist files are bundled",
+    context: PAGES,
+    request: "next/dist/client/components/navigation",
+    dependency_type: "esm",
+    expect: |_, _| decision(DecisionRule::NextDistExternal, None),
+  },
+  Case {
+    name: "local next external files are external",
+    context: "/app/node_modules/next/dist/server",
+    request: "./app-render/work-unit-async-storage.external.js",
+    dependency_type: "cjs",
+    expect: |_, _| {
+      decision(
+        DecisionRule::LocalNextExternal,
+        Some("commonjs next/dist/server/app-render/work-unit-async-storage.external.js"),
+      )
+    },
+  },
+  Case {
+    name: "other local files are bundled",
+    context: PAGES,
+    request: "../lib/utils",
+    dependency_type: "esm",
+    expect: |_, _| decision(DecisionRule::LocalFile, None),
+  },
+  Case {
+    name: "missing packages are bundled to surface the error",
+    context: PAGES,
+    request: "missing-pkg",
+    dependency_type: "cjs",
+    expect: |_, _| decision(DecisionRule::Unresolved, None),
+  },
+  Case {
+    name: "a nested version differing from the root is bundled",
+    context: "/app/node_modules/parent",
+    request: "dup",
+    dependency_type: "cjs",
+    expect: |_, _| decision(DecisionRule::BaseResolveMismatch, None),
+  },
+  Case {
+    name: "a hoisted version matching the root is external",
+    context: PAGES,
+    request: "dup",
+    dependency_type: "cjs",
+    expect: |layer, _| {
+      bundled_or(
+        layer,
+        decision(DecisionRule::NodeModulesExternal, Some("commonjs dup")),
+      )
+    },
+  },
+  Case {
+    name: "aliased requests that the root can't resolve are bundled",
+    context: PAGES,
+    request: "aliased",
+    dependency_type: "cjs",
+    expect: |_, _| decision(DecisionRule::BaseResolveMismatch, None),
+  },
+  Case {
+    name: "cjs packages are external",
+    context: PAGES,
+    request: "cjs-pkg",
+    dependency_type: "cjs",
+    expect: |layer, _| {
+      bundled_or(
+        layer,
+        decision(DecisionRule::NodeModulesExternal, Some("commonjs cjs-pkg")),
+      )
+    },
+  },
+  Case {
+    name: "opted out packages are external in every layer",
+    context: PAGES,
+    request: "opt-out-pkg",
+    dependency_type: "esm",
+    expect: |_, _| {
+      decision(
+        DecisionRule::NodeModulesExternal,
+        Some("commonjs opt-out-pkg"),
+      )
+    },
+  },
+  Case {
+    name: "server external packages are external in every layer",
+    context: PAGES,
+    request: "@prisma/client",
+    dependency_type: "cjs",
+    expect: |_, _| {
+      decision(
+        DecisionRule::NodeModulesExternal,
+        Some("commonjs @prisma/client"),
+      )
+    },
+  },
+  Case {
+    name: "styled-jsx resolves through the default override",
+    context: PAGES,
+    request: "styled-jsx/style",
+    dependency_type: "cjs",
+    expect: |layer, _| {
+      bundled_or(
+        layer,
+        decision(
+          DecisionRule::NodeModulesExternal,
+          Some("commonjs styled-jsx/style"),
+        ),
+      )
+    },
+  },
+  Case {
+    name: "babel runtime is bundled",
+    context: PAGES,
+    request: "@babel/runtime/helpers/interopRequireDefault",
+    dependency_type: "cjs",
+    expect: |layer, _| bundled_or(layer, decision(DecisionRule::BabelRuntime, None)),
+  },
+  Case {
+    name: "webpack is bundled",
+    context: PAGES,
+    request: "webpack",
+    dependency_type: "cjs",
+    expect: |layer, _| bundled_or(layer, decision(DecisionRule::WebpackCssLoader, None)),
+  },
+  Case {
+    name: "transpiled packages are bundled",
+    context: PAGES,
+    request: "transpiled-pkg",
+    dependency_type: "esm",
+    expect: |layer, _| bundled_or(layer, decision(DecisionRule::TranspilePackage, None)),
+  },
+  Case {
+    name: "files outside of js modules use the default",
+    context: PAGES,
+    request: "cjs-pkg/data.json",
+    dependency_type: "cjs",
+    expect: |layer, _| bundled_or(layer, decision(DecisionRule::Default, None)),
+  },
+  Case {
+    name: "required esm packages error unless loose",
+    context: PAGES,
+    request: "esm-only",
+    dependency_type: "cjs",
+    expect: |layer, esm_externals| {
+      bundled_or(
+        layer,
+        if esm_externals.is_loose() {
+          decision(DecisionRule::NodeModulesExternal, Some("module esm-only"))
+        } else {
+          Expected::Error("ESM packages (esm-only) need to be imported")
+        },
+      )
+    },
+  },
+  Case {
+    name: "imported esm packages are modules",
+    context: PAGES,
+    request: "esm-only",
+    dependency_type: "esm",
+    expect: |layer, _| {
+      bundled_or(
+        layer,
+        decision(DecisionRule::NodeModulesExternal, Some("module esm-only")),
+      )
+    },
+  },
+  Case {
+    name: "required dual packages use the require condition",
+    context: PAGES,
+    request: "dual",
+    dependency_type: "cjs",
+    expect: |layer, _| {
+      bundled_or(
+        layer,
+        decision(DecisionRule::NodeModulesExternal, Some("commonjs dual")),
+      )
+    },
+  },
+  Case {
+    name: "imported dual packages use the import condition with esm externals",
+    context: PAGES,
+    request: "dual",
+    dependency_type: "esm",
+    expect: |layer, esm_externals| {
+      let external = if esm_externals.is_enabled() {
+        "module dual"
+      } else {
+        "commonjs dual"
+      };
+      bundled_or(
+        layer,
+        decision(DecisionRule::NodeModulesExternal, Some(external)),
+      )
+    },
+  },
+];
+
+#[tokio::test]
+async fn handle_externals_cases() {
+  let get_resolve = fixture().into_get_resolve();
+  for esm_externals in ESM_MODES {
+    let handler = handler_with_esm_externals(esm_externals.clone());
+    for layer in layers() {
+      for case in CASES {
+        let actual = match handler
+          .decide(
+            case.context.to_string(),
+            case.request.to_string(),
+            case.dependency_type,
+            layer,
+            get_resolve.clone(),
+          )
+          .await
+        {
+          Ok(decision) => Expected::Decision(decision.rule, decision.external),
+          Err(error) => {
+            let expected = (case.expect)(layer, esm_externals);
+            let message = error.to_string();
+            match expected {
+              Expected::Error(substring) if message.contains(substring) => expected,
+              _ => panic!(
+                "{} ({esm_externals:?}, {layer:?}): unexpected error {message}",
+                case.name
+              ),
+            }
+          }
+        };
+        assert_eq!(
+          actual,
+          (case.expect)(layer, esm_externals),
+          "{} ({esm_externals:?}, {layer:?})",
+          case.name
+        );
+      }
+    }
+  }
+}
+
+#[tokio::test]
+async fn bundle_pages_router_dependencies() {
+  let get_resolve = fixture().into_get_resolve();
+  let handler = handler(NextConfigComplete {
+    bundle_pages_router_dependencies: Some(true),
+    ..Default::default()
+  });
+  for layer in layers() {
+    let decide = |request: &str| {
+      handler.decide(
+        PAGES.to_string(),
+        request.to_string(),
+        "cjs",
+        layer,
+        get_resolve.clone(),
+      )
+    };
+    let decision = decide("cjs-pkg").await.unwrap();
+    assert_eq!(
+      decision.rule,
+      if WebpackLayers::default().is_bundled(layer) {
+        DecisionRule::BundledLayer
+      } else {
+        DecisionRule::BundlePagesRouterDependencies
+      },
+      "{layer:?}"
+    );
+    // Opted out packages stay external.
+    let decision = decide("opt-out-pkg").await.unwrap();
+    assert_eq!(
+      decision.rule,
+      DecisionRule::NodeModulesExternal,
+      "{layer:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn loose_esm_externals_packages() {
+  let get_resolve = fixture().into_get_resolve();
+  // Listed packages are loose in every mode, including when ESM externals
+  // are disabled.
+  for esm_externals in ESM_MODES {
+    let handler = handler(NextConfigComplete {
+      experimental: ExperimentalConfig {
+        esm_externals: esm_externals.clone(),
+        loose_esm_externals_packages: vec!["esm-only".to_string()],
+        ..Default::default()
+      },
+      ..Default::default()
+    });
+    let decision = handler
+      .decide(
+        PAGES.to_string(),
+        "esm-only".to_string(),
+        "cjs",
+        None,
+        get_resolve.clone(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      decision.external.as_deref(),
+      Some("module esm-only"),
+      "{esm_externals:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn strict_esm_externals_packages() {
+  let get_resolve = fixture().into_get_resolve();
+  let decide = |strict_esm_externals_packages: Vec<String>| {
+    let handler = handler(NextConfigComplete {
+      experimental: ExperimentalConfig {
+        esm_externals: EsmExternalsConfig::Loose,
+        strict_esm_externals_packages,
+        ..Default::default()
+      },
+      ..Default::default()
+    });
+    let get_resolve = get_resolve.clone();
+    async move {
+      handler
+        .decide(
+          PAGES.to_string(),
+          "esm-only".to_string(),
+          "cjs",
+          None,
+          get_resolve,
+        )
+        .await
+    }
+  };
+
+  let decision = decide(vec![]).await.unwrap();
+  assert_eq!(decision.external.as_deref(), Some("module esm-only"));
+
+  // Listed packages aren't loose, so requiring an ESM only one fails.
+  let error = decide(vec!["esm-only".to_string()]).await.unwrap_err();
+  assert!(
+    error
+      .to_string()
+      .contains("ESM packages (esm-only) need to be imported"),
+    "{error}"
+  );
+}
+
+#[tokio::test]
+async fn resolve_external_detects_esm_from_package_type() {
+  let get_resolve = PackageTree::new()
+    .with_package_json("/app/node_modules/typed", json!({ "type": "module" }))
+    .with_file("/app/node_modules/typed/index.js", "")
+    .with_file("/app/node_modules/typed/legacy.cjs", "")
+    .with_package_json("/app/node_modules/untyped", json!({}))
+    .with_file("/app/node_modules/untyped/index.js", "")
+    .with_file("/app/node_modules/untyped/module.mjs", "")
+    .into_get_resolve();
+
+  for (request, expected_res, expected_is_esm) in [
+    ("typed", "/app/node_modules/typed/index.js", true),
+    (
+      "typed/legacy.cjs",
+      "/app/node_modules/typed/legacy.cjs",
+      false,
+    ),
+    ("untyped", "/app/node_modules/untyped/index.js", false),
+    (
+      "untyped/module.mjs",
+      "/app/node_modules/untyped/module.mjs",
+      true,
+    ),
+  ] {
+    let result = resolve_external(
+      &[DIR.to_string()],
+      &EsmExternalsConfig::Loose,
+      PAGES.to_string(),
+      request.to_string(),
+      false,
+      get_resolve.clone(),
+      None,
+      None,
+      None,
+      None,
+      None,
+      None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.res.as_deref(), Some(expected_res), "{request}");
+    assert_eq!(result.is_esm, expected_is_esm, "{request}");
+  }
+}
+
+#[tokio::test]
+async fn resolve_external_prefers_esm_when_enabled() {
+  let get_resolve = fixture().into_get_resolve();
+  for esm_externals in ESM_MODES {
+    let result = resolve_external(
+      &[DIR.to_string()],
+      esm_externals,
+      PAGES.to_string(),
+      "dual".to_string(),
+      true,
+      get_resolve.clone(),
+      None,
+      None,
+      None,
+      None,
+      None,
+      None,
+    )
+    .await
+    .unwrap();
+    let (expected_res, expected_is_esm) = if esm_externals.is_enabled() {
+      ("/app/node_modules/dual/index.mjs", true)
+    } else {
+      ("/app/node_modules/dual/index.cjs", false)
+    };
+    assert_eq!(
+      result.res.as_deref(),
+      Some(expected_res),
+      "{esm_externals:?}"
+    );
+    assert_eq!(result.is_esm, expected_is_esm, "{esm_externals:?}");
+  }
+}
+
+#[tokio::test]
+async fn transpile_package_dirs_resolve_from_dir() {
+  let get_resolve = fixture()
+    .with_package_json(
+      "/app/node_modules/parent/node_modules/transpiled-pkg",
+      json!({ "main": "index.js" }),
+    )
+    .with_file(
+      "/app/node_modules/parent/node_modules/transpiled-pkg/index.js",
+      "",
+    )
+    .into_get_resolve();
+  let handler = handler(NextConfigComplete::default());
+  assert!(handler.transpile_package_dirs().is_none());
+
+  // The first request comes from a package with its own copy of the
+  // transpiled package, which must not be what the directory resolves to.
+  let decision = handler
+    .decide(
+      "/app/node_modules/parent".to_string(),
+      "cjs-pkg".to_string(),
+      "commonjs",
+      None,
+      get_resolve.clone(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(decision.rule, DecisionRule::NodeModulesExternal);
+
+  let dirs = handler.transpile_package_dirs().unwrap();
+  assert_eq!(
+    dirs.package_jsons(),
+    ["/app/node_modules/transpiled-pkg/package.json"]
+  );
+  assert!(dirs.missing_package_jsons().is_empty());
+
+  assert!(!handler.invalidate_transpile_package_dirs([Path::new("/app/pages/index.js")]));
+  assert!(handler.transpile_package_dirs().is_some());
+  assert!(handler.invalidate_transpile_package_dirs([Path::new(
+    "/app/node_modules/transpiled-pkg/package.json"
+  )]));
+  assert!(handler.transpile_package_dirs().is_none());
+
+  // Removing the `node_modules` directory invalidates everything in it.
+  handler
+    .decide(
+      PAGES.to_string(),
+      "transpiled-pkg".to_string(),
+      "commonjs",
+      None,
+      get_resolve,
+    )
+    .await
+    .unwrap();
+  assert!(handler.invalidate_transpile_package_dirs([Path::new("/app/node_modules")]));
+}
+
+#[tokio::test]
+async fn server_external_packages() {
+  let get_resolve = fixture().into_get_resolve();
+  let config = NextConfigComplete {
+    server_external_packages: vec!["cjs-pkg".to_string()],
+    ..Default::default()
+  };
+  let cases = [
+    // The configured packages extend the default list.
+    (
+      handler_with_server_external_packages(config.clone(), default_server_external_packages()),
+      DecisionRule::NodeModulesExternal,
+    ),
+    // Replacing the default list leaves only the configured packages.
+    (
+      handler_with_server_external_packages(config, vec![]),
+      DecisionRule::BundledLayer,
+    ),
+  ];
+  for (handler, prisma_rule) in cases {
+    for (request, expected) in [
+      ("cjs-pkg", DecisionRule::NodeModulesExternal),
+      ("@prisma/client", prisma_rule),
+    ] {
+      let decision = handler
+        .decide(
+          PAGES.to_string(),
+          request.to_string(),
+          "cjs",
+          Some("rsc"),
+          get_resolve.clone(),
+        )
+        .await
+        .unwrap();
+      assert_eq!(decision.rule, expected, "{request}");
+    }
+  }
+
+  // Transpiling a package of the default list bundles it.
+  let handler = ExternalHandler::new(
+    NextConfigComplete::default(),
+    None,
+    default_server_external_packages(),
+    vec!["@prisma/client".to_string()],
+    DIR.to_string(),
+    FxHashMap::default(),
+  );
+  for (layer, expected) in [
+    (Some("rsc"), DecisionRule::BundledLayer),
+    (None, DecisionRule::TranspilePackage),
+  ] {
+    let decision = handler
+      .decide(
+        PAGES.to_string(),
+        "@prisma/client".to_string(),
+        "cjs",
+        layer,
+        get_resolve.clone(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(decision.rule, expected, "{layer:?}");
+  }
+}
+
+/// Wraps a resolver so it returns paths with `\\` separators, as on Windows.
+/// With `mixed`, every other path keeps its `/` separators, so two resolutions
+/// of the same request can differ in their separators only.
+fn with_windows_separators(get_resolve: GetResolveFn, mixed: bool) -> GetResolveFn {
+  let calls = Arc::new(AtomicUsize::new(0));
+  Arc::new(move |options| {
+    let resolve = get_resolve(options);
+    let calls = calls.clone();
+    let resolve: ResolveFn = Box::new(move |context, request| {
+      let result = resolve(context, request);
+      let backslashes = !mixed || calls.fetch_add(1, Ordering::Relaxed) % 2 == 0;
+      Box::pin(async move {
+        result.await.map(|mut resolved| {
+          resolved.path = resolved.path.map(|res| {
+            if backslashes {
+              format!("C:{}", res.replace('/', "\\"))
+            } else {
+              format!("C:{res}")
+            }
+          });
+          resolved
+        })
+      })
+    });
+    resolve
+  })
+}
+
+#[tokio::test]
+async fn windows_separators() {
+  for mixed in [false, true] {
+    let get_resolve = with_windows_separators(fixture().into_get_resolve(), mixed);
+    let handler = handler(NextConfigComplete::default());
+    for (request, layer, expected) in [
+      ("cjs-pkg", None, DecisionRule::NodeModulesExternal),
+      (
+        "opt-out-pkg",
+        Some("rsc"),
+        DecisionRule::NodeModulesExternal,
+      ),
+      (
+        "@prisma/client",
+        Some("rsc"),
+        DecisionRule::NodeModulesExternal,
+      ),
+      ("transpiled-pkg", None, DecisionRule::TranspilePackage),
+      ("webpack", None, DecisionRule::WebpackCssLoader),
+      (
+        "@babel/runtime/helpers/interopRequireDefault",
+        None,
+        DecisionRule::BabelRuntime,
+      ),
+    ] {
+      let decision = handler
+        .decide(
+          PAGES.to_string(),
+          request.to_string(),
+          "cjs",
+          layer,
+          get_resolve.clone(),
+        )
+        .await
+        .unwrap();
+      assert_eq!(decision.rule, expected, "{request} (mixed: {mixed})");
+      if let Some(external) = decision.external {
+        assert!(!external.contains('\\'), "{request}: {external}");
+      }
+    }
+    let dirs = handler.transpile_package_dirs().unwrap();
+    assert_eq!(
+      dirs.dirs.get("transpiled-pkg").map(String::as_str),
+      Some("C:/app/node_modules/transpiled-pkg")
+    );
+  }
+}
+
+#[tokio::test]
+async fn windows_default_overrides() {
+  let get_resolve = fixture().into_get_resolve();
+  let mut default_overrides = FxHashMap::default();
+  default_overrides.insert(
+    "styled-jsx/style".to_string(),
+    r"C:\app\node_modules\next\node_modules\styled-jsx\style.js".to_string(),
+  );
+  let handler = ExternalHandler::new(
+    NextConfigComplete::default(),
+    None,
+    vec![],
+    vec![],
+    DIR.to_string(),
+    default_overrides,
+  );
+  let decision = handler
+    .decide(
+      PAGES.to_string(),
+      "styled-jsx/style".to_string(),
+      "cjs",
+      None,
+      get_resolve,
+    )
+    .await
+    .unwrap();
+  assert_eq!(decision.rule, DecisionRule::NodeModulesExternal);
+  assert_eq!(
+    decision.external.as_deref(),
+    Some("commonjs styled-jsx/style")
+  );
+  assert_eq!(
+    decision.resolved.as_deref(),
+    Some("C:/app/node_modules/next/node_modules/styled-jsx/style.js")
+  );
+}
+
+#[test]
+fn next_externals_with_any_separator() {
+  let expected =
+    Some("commonjs next/dist/server/app-render/work-unit-async-storage.external.js".to_string());
+  for local_res in [
+    "/app/node_modules/next/dist/server/app-render/work-unit-async-storage.external.js",
+    "C:\\app\\node_modules\\next\\dist\\server\\app-render\\work-unit-async-storage.external.js",
+    "C:\\app\\node_modules\\next/dist\\server/app-render\\work-unit-async-storage.external.js",
+  ] {
+    assert_eq!(resolve_next_external(local_res), expected, "{local_res}");
+  }
+  assert_eq!(
+    resolve_next_external("C:\\app\\node_modules\\next\\dist\\server\\render.js"),
+    None
+  );
+}
+
+#[test]
+fn resource_in_packages_with_any_separator() {
+  let packages = ["pkg".to_string()];
+  let dirs = FxHashMap::from_iter([("pkg".to_string(), "C:\\app\\node_modules\\pkg".to_string())]);
+  for resource in [
+    "C:\\app\\node_modules\\pkg\\index.js",
+    "C:/app/node_modules/pkg/index.js",
+    "C:\\app/node_modules\\pkg/lib\\index.js",
+  ] {
+    assert!(
+      is_resource_in_packages(resource, &packages, Some(&dirs)),
+      "{resource}"
+    );
+    assert!(
+      is_resource_in_packages(resource, &packages, None),
+      "{resource}"
+    );
+  }
+  // A sibling whose name starts with the package name is not inside it.
+  assert!(!is_resource_in_packages(
+    "C:\\app\\node_modules\\pkg-extra\\index.js",
+    &packages,
+    Some(&dirs)
+  ));
+}
+
+/// Wraps a resolver to count the requests it resolves.
+fn counting_resolves(get_resolve: GetResolveFn) -> (GetResolveFn, Arc<AtomicUsize>) {
+  let count = Arc::new(AtomicUsize::new(0));
+  let counter = count.clone();
+  let get_resolve: GetResolveFn = Arc::new(move |options| {
+    let resolve = get_resolve(options);
+    let counter = counter.clone();
+    let resolve: ResolveFn = Box::new(move |context, request| {
+      counter.fetch_add(1, Ordering::Relaxed);
+      resolve(context, request)
+    });
+    resolve
+  });
+  (get_resolve, count)
+}
+
+async fn decide_rule(
+  handler: &ExternalHandler,
+  request: &str,
+  get_resolve: &GetResolveFn,
+) -> DecisionRule {
+  handler
+    .decide(
+      PAGES.to_string(),
+      request.to_string(),
+      "cjs",
+      None,
+      get_resolve.clone(),
+    )
+    .await
+    .unwrap()
+    .rule
+}
+
+#[tokio::test]
+async fn shared_context() {
+  const ID: &str = "shared-context-test";
+  let (get_resolve, resolves) = counting_resolves(fixture().into_get_resolve());
+  let server = handler(NextConfigComplete::default()).with_shared_context(ID);
+  let other_server = handler(NextConfigComplete::default()).with_shared_context(ID);
+
+  for request in ["cjs-pkg", "transpiled-pkg"] {
+    decide_rule(&server, request, &get_resolve).await;
+  }
+  let resolved = resolves.load(Ordering::Relaxed);
+  assert_ne!(resolved, 0);
+  assert!(other_server.transpile_package_dirs().is_some());
+
+  // The other handler reuses the decisions without resolving anything.
+  for (request, expected) in [
+    ("cjs-pkg", DecisionRule::NodeModulesExternal),
+    ("transpiled-pkg", DecisionRule::TranspilePackage),
+  ] {
+    assert_eq!(
+      decide_rule(&other_server, request, &get_resolve).await,
+      expected
+    );
+  }
+  assert_eq!(resolves.load(Ordering::Relaxed), resolved);
+  assert_eq!(
+    other_server.take_decision_cache_stats(),
+    DecisionCacheStats { hits: 2, misses: 0 }
+  );
+  assert_eq!(
+    server.take_decision_cache_stats(),
+    DecisionCacheStats { hits: 0, misses: 2 }
+  );
+
+  // Each handler only traces the requests it decided or reused.
+  decide_rule(&server, "react", &get_resolve).await;
+  let requests = |handler: &ExternalHandler| {
+    handler
+      .trace_entries()
+      .into_iter()
+      .map(|entry| entry.request)
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(requests(&server), ["cjs-pkg", "react", "transpiled-pkg"]);
+  assert_eq!(requests(&other_server), ["cjs-pkg", "transpiled-pkg"]);
+
+  // Rebuilding together, the decisions made since both started are shared.
+  server.start_build();
+  other_server.start_build();
+  assert!(requests(&other_server).is_empty());
+  decide_rule(&server, "cjs-pkg", &get_resolve).await;
+  decide_rule(&other_server, "cjs-pkg", &get_resolve).await;
+  assert_eq!(
+    other_server.take_decision_cache_stats(),
+    DecisionCacheStats { hits: 1, misses: 0 }
+  );
+  // A handler rebuilding on its own makes them again.
+  server.start_build();
+  assert!(requests(&server).is_empty());
+  server.take_decision_cache_stats();
+  decide_rule(&server, "cjs-pkg", &get_resolve).await;
+  assert_eq!(
+    server.take_decision_cache_stats(),
+    DecisionCacheStats { hits: 0, misses: 1 }
+  );
+
+  // A different configuration or id gets a context of its own.
+  let bundled_pages = handler(NextConfigComplete {
+    bundle_pages_router_dependencies: Some(true),
+    ..Default::default()
+  })
+  .with_shared_context(ID);
+  assert!(bundled_pages.transpile_package_dirs().is_none());
+  assert_eq!(
+    decide_rule(&bundled_pages, "cjs-pkg", &get_resolve).await,
+    DecisionRule::BundlePagesRouterDependencies
+  );
+  let other_id = handler(NextConfigComplete::default()).with_shared_context("other");
+  assert!(other_id.transpile_package_dirs().is_none());
+
+  // So do handlers resolving differently, or applied to compilers that do.
+  let pnp = handler(NextConfigComplete::default())
+    .with_pnp(true)
+    .with_shared_context(ID);
+  assert!(pnp.transpile_package_dirs().is_none());
+  let next_14 = handler(NextConfigComplete::default())
+    .with_next_version(NextVersion::V14)
+    .with_shared_context(ID);
+  assert!(next_14.transpile_package_dirs().is_none());
+  let web_modules = handler(NextConfigComplete::default()).with_shared_context(ID);
+  web_modules.set_compiler_resolve_options(&Resolve {
+    modules: Some(vec!["web_modules".to_string()]),
+    ..Default::default()
+  });
+  assert!(web_modules.transpile_package_dirs().is_none());
+  let default_resolve = handler(NextConfigComplete::default()).with_shared_context(ID);
+  default_resolve.set_compiler_resolve_options(&Resolve::default());
+  assert!(default_resolve.transpile_package_dirs().is_some());
+
+  // The context goes away with the last handler using it.
+  drop((server, other_server, default_resolve));
+  let next_build = handler(NextConfigComplete::default()).with_shared_context(ID);
+  assert!(next_build.transpile_package_dirs().is_none());
+}
+
+#[tokio::test]
+async fn uneven_rebuilds() {
+  const ID: &str = "uneven-rebuilds-test";
+  // `cjs-pkg` starts building a native addon after the first builds.
+  let (get_resolve, changed) = changing_resolves(
+    fixture().into_get_resolve(),
+    fixture()
+      .with_package_json(
+        "/app/node_modules/cjs-pkg",
+        json!({ "main": "index.js", "gypfile": true }),
+      )
+      .into_get_resolve(),
+  );
+  let server = handler(NextConfigComplete::default()).with_shared_context(ID);
+  let other_server = handler(NextConfigComplete::default()).with_shared_context(ID);
+
+  server.start_build();
+  other_server.start_build();
+  for handler in [&server, &other_server] {
+    assert_eq!(
+      decide_rule(handler, "cjs-pkg", &get_resolve).await,
+      DecisionRule::NodeModulesExternal
+    );
+  }
+
+  // The server rebuilds more often than the other handler, for changes the
+  // other handler doesn't depend on.
+  for _ in 0..2 {
+    server.start_build();
+    decide_rule(&server, "cjs-pkg", &get_resolve).await;
+  }
+
+  // Only the other handler rebuilds for the next change, and it doesn't
+  // reuse what the server decided before it.
+  changed.store(true, Ordering::Relaxed);
+  other_server.start_build();
+  assert_eq!(
+    decide_rule(&other_server, "cjs-pkg", &get_resolve).await,
+    DecisionRule::NativeAddon
+  );
+  // The server reuses the newer decision.
+  assert_eq!(
+    decide_rule(&server, "cjs-pkg", &get_resolve).await,
+    DecisionRule::NativeAddon
+  );
+}
+
+/// Resolves with `before` until the returned flag is set, then with `after`,
+/// as if files changed in between.
+fn changing_resolves(before: GetResolveFn, after: GetResolveFn) -> (GetResolveFn, Arc<AtomicBool>) {
+  let changed = Arc::new(AtomicBool::new(false));
+  let get_resolve: GetResolveFn = {
+    let changed = changed.clone();
+    Arc::new(move |options| {
+      if changed.load(Ordering::Relaxed) {
+        after(options)
+      } else {
+        before(options)
+      }
+    })
+  };
+  (get_resolve, changed)
+}
+
+/// Delays every resolution, so that concurrent lookups overlap.
+fn slow(get_resolve: GetResolveFn) -> GetResolveFn {
+  Arc::new(move |options| {
+    let resolve = get_resolve(options);
+    Box::new(move |context, request| {
+      let resolved = resolve(context, request);
+      Box::pin(async move {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        resolved.await
+      })
+    })
+  })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_lookups_resolve_once() {
+  const LOOKUPS: usize = 8;
+  let get_resolve = slow(fixture().into_get_resolve());
+  let decide = |handler: Arc<ExternalHandler>| {
+    let get_resolve = get_resolve.clone();
+    async move {
+      handler
+        .decide(
+          PAGES.to_string(),
+          "cjs-pkg".to_string(),
+          "cjs",
+          None,
+          get_resolve,
+        )
+        .await
+        .map(|decision| (decision.rule, decision.external))
+        .unwrap()
+    }
+  };
+
+  let sequential = Arc::new(handler(NextConfigComplete::default()));
+  let expected = decide(sequential.clone()).await;
+  let resolver_calls = sequential.stats().snapshot().resolver_calls;
+
+  let handler = Arc::new(handler(NextConfigComplete::default()));
+  let lookups = (0..LOOKUPS)
+    .map(|_| tokio::spawn(decide(handler.clone())))
+    .collect::<Vec<_>>();
+  for lookup in lookups {
+    assert_eq!(lookup.await.unwrap(), expected);
+  }
+  assert_eq!(handler.stats().snapshot().resolver_calls, resolver_calls);
+  assert_eq!(
+    handler.take_decision_cache_stats(),
+    DecisionCacheStats {
+      hits: LOOKUPS as u64 - 1,
+      misses: 1,
+    }
+  );
+}
+
+#[tokio::test]
+async fn failed_decisions_are_retried() {
+  // `esm-only` is republished as CommonJS after the first build failed.
+  let (get_resolve, republished) = changing_resolves(
+    fixture().into_get_resolve(),
+    fixture()
+      .with_package_json("/app/node_modules/esm-only", json!({ "main": "index.js" }))
+      .into_get_resolve(),
+  );
+  let handler = handler_with_esm_externals(EsmExternalsConfig::Strict);
+  let decide = || {
+    handler.decide(
+      PAGES.to_string(),
+      "esm-only".to_string(),
+      "cjs",
+      None,
+      get_resolve.clone(),
+    )
+  };
+
+  assert!(decide().await.is_err());
+  assert!(decide().await.is_err());
+  republished.store(true, Ordering::Relaxed);
+  let decision = decide().await.unwrap();
+  assert_eq!(decision.rule, DecisionRule::NodeModulesExternal);
+  assert_eq!(decision.external.as_deref(), Some("commonjs esm-only"));
+  assert_eq!(
+    handler.take_decision_cache_stats(),
+    DecisionCacheStats { hits: 0, misses: 1 }
+  );
+}
+
+#[tokio::test]
+async fn stats() {
+  let get_resolve = fixture().into_get_resolve();
+  let handler = handler(NextConfigComplete::default());
+  let decide = |context: &str, request: &str, layer: Option<&'static str>| {
+    handler.decide(
+      context.to_string(),
+      request.to_string(),
+      "cjs",
+      layer,
+      get_resolve.clone(),
+    )
+  };
+
+  decide("/app/node_modules/parent", "dup", None)
+    .await
+    .unwrap();
+  decide(PAGES, "cjs-pkg", None).await.unwrap();
+  let stats = handler.stats().snapshot();
+  assert_eq!(stats.base_resolve_mismatches, 1);
+  let resolver_calls = stats.resolver_calls;
+  assert_ne!(resolver_calls, 0);
+
+  // Cached decisions are counted as calls, without resolving again.
+  decide(PAGES, "cjs-pkg", None).await.unwrap();
+  assert_eq!(handler.stats().snapshot().resolver_calls, resolver_calls);
+
+  decide(PAGES, "react", Some("rsc")).await.unwrap();
+  let stats = handler.stats().snapshot();
+  assert_eq!(stats.calls, 4);
+  assert_eq!(
+    stats.calls_per_layer,
+    FxHashMap::from_iter([(None, 3), (Some("rsc".to_string()), 1)])
+  );
+  assert_eq!(
+    stats.decisions_per_rule,
+    FxHashMap::from_iter([
+      (DecisionRule::BaseResolveMismatch.as_str(), 1),
+      (DecisionRule::NodeModulesExternal.as_str(), 2),
+      (DecisionRule::BundledLayer.as_str(), 1),
+    ])
+  );
+  assert!(stats.p99_time <= stats.total_time);
+}
+
+#[test]
+fn rule_names_match_traces() {
+  let mut names = FxHashSet::default();
+  for rule in DecisionRule::VALUES {
+    assert!(names.insert(rule.as_str()), "{rule:?}");
+    let value = serde_json::to_value(rule).unwrap();
+    assert_eq!(value, rule.as_str());
+    assert_eq!(
+      serde_json::from_value::<DecisionRule>(value).unwrap(),
+      *rule
+    );
+  }
+  assert_eq!(
+    DecisionRule::NodeModulesExternal.as_str(),
+    "node-modules-external"
+  );
+  assert!(serde_json::from_value::<DecisionRule>(json!("missing-rule")).is_err());
+}
+
+#[test]
+fn package_names_in_node_modules() {
+  let names = |resource: &str| {
+    node_modules_package_names(resource)
+      .map(Cow::into_owned)
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(names("/app/node_modules/sharp/lib/index.js"), ["sharp"]);
+  assert_eq!(
+    names("/app/node_modules/.pnpm/sharp@0.33.0/node_modules/sharp/lib/index.js"),
+    [".pnpm", "sharp"]
+  );
+  assert_eq!(
+    names("C:\\app\\node_modules\\@prisma\\client\\index.js"),
+    ["@prisma/client"]
+  );
+  // The package directory has to be followed by a file.
+  assert!(names("/app/node_modules/sharp").is_empty());
+  assert!(names("/app/node_modules/@prisma/client").is_empty());
+  assert!(names("/app/lib/index.js").is_empty());
+}
+
+#[test]
+fn package_name() {
+  assert_eq!(get_package_name("react"), Some("react"));
+  assert_eq!(get_package_name("react-dom/server"), Some("react-dom"));
+  assert_eq!(get_package_name("@scope/pkg/sub/path"), Some("@scope/pkg"));
+  assert_eq!(get_package_name("@scope"), None);
+  assert_eq!(get_package_name("./local"), None);
+  assert_eq!(get_package_name("/abs/path"), None);
+}
+
+#[tokio::test]
+async fn custom_layers() {
+  let get_resolve = fixture().into_get_resolve();
+  let layers = WebpackLayers::new(
+    NextVersion::V15,
+    Some(vec!["rsc".to_string(), "app-edge".to_string()]),
+    Some(vec!["app-edge".to_string()]),
+  )
+  .unwrap();
+  let handler = handler(NextConfigComplete::default()).with_layers(layers);
+  let decide = |request: &'static str, layer: Option<&'static str>| {
+    let handler = &handler;
+    let get_resolve = get_resolve.clone();
+    async move {
+      handler
+        .decide(
+          PAGES.to_string(),
+          request.to_string(),
+          "cjs",
+          layer,
+          get_resolve,
+        )
+        .await
+        .unwrap()
+        .rule
+    }
+  };
+
+  assert_eq!(
+    decide("react", Some("app-edge")).await,
+    DecisionRule::BundledLayer
+  );
+  // No longer a bundled layer.
+  assert_eq!(
+    decide("react", Some("ssr")).await,
+    DecisionRule::ReactPackage
+  );
+  assert_eq!(
+    decide(
+      "next/dist/compiled/@vercel/og/index.node.js",
+      Some("app-edge")
+    )
+    .await,
+    DecisionRule::VercelOg
+  );
+  assert_ne!(
+    decide("next/dist/compiled/@vercel/og/index.node.js", Some("rsc")).await,
+    DecisionRule::VercelOg
+  );
+
+  assert_ne!(
+    handler.config_fingerprint(),
+    self::handler(NextConfigComplete::default()).config_fingerprint()
+  );
+}
+
+#[tokio::test]
+async fn react_server_condition() {
+  let get_resolve = fixture()
+    .with_package_json(
+      "/app/node_modules/server-pkg",
+      json!({ "exports": { "react-server": "./server.mjs", "default": "./index.js" } }),
+    )
+    .with_file("/app/node_modules/server-pkg/server.mjs", "")
+    .with_file("/app/node_modules/server-pkg/index.js", "")
+    .into_get_resolve();
+  let handler = handler_with_server_external_packages(
+    NextConfigComplete::default(),
+    vec!["server-pkg".to_string()],
+  );
+  let decide = |dependency_type: &'static str, layer: Option<&'static str>| {
+    let handler = &handler;
+    let get_resolve = get_resolve.clone();
+    async move {
+      handler
+        .decide(
+          PAGES.to_string(),
+          "server-pkg".to_string(),
+          dependency_type,
+          layer,
+          get_resolve,
+        )
+        .await
+    }
+  };
+
+  for layer in ["rsc", "action-browser"] {
+    let decision = decide("esm", Some(layer)).await.unwrap();
+    assert_eq!(
+      decision.resolved.as_deref(),
+      Some("/app/node_modules/server-pkg/server.mjs"),
+      "{layer}"
+    );
+    assert_eq!(decision.external.as_deref(), Some("module server-pkg"));
+    // The `react-server` export is ESM, so it can't be required.
+    assert!(decide("cjs", Some(layer)).await.is_err(), "{layer}");
+  }
+
+  for layer in [None, Some("ssr")] {
+    let decision = decide("esm", layer).await.unwrap();
+    assert_eq!(
+      decision.resolved.as_deref(),
+      Some("/app/node_modules/server-pkg/index.js"),
+      "{layer:?}"
+    );
+    assert_eq!(decision.external.as_deref(), Some("commonjs server-pkg"));
+  }
+}
+
+#[tokio::test]
+async fn native_addons() {
+  let get_resolve = fixture()
+    .with_package_json(
+      "/app/node_modules/gyp-addon",
+      json!({ "main": "index.js", "gypfile": true }),
+    )
+    .with_file("/app/node_modules/gyp-addon/index.js", "")
+    .with_package_json(
+      "/app/node_modules/prebuilt",
+      json!({ "main": "prebuilt.node" }),
+    )
+    .with_file("/app/node_modules/prebuilt/prebuilt.node", "")
+    // Only compiled against the headers of `nan`, its addon is optional.
+    .with_package_json(
+      "/app/node_modules/headers",
+      json!({ "main": "index.js", "dependencies": { "nan": "^2.0.0" } }),
+    )
+    .with_file("/app/node_modules/headers/index.js", "")
+    .with_package_json(
+      "/app/node_modules/transpiled-pkg",
+      json!({ "main": "index.js", "gypfile": true }),
+    )
+    .with_file("/app/node_modules/transpiled-pkg/addon.node", "")
+    .with_file(
+      "/app/node_modules/transpiled-pkg/build/Release/addon.node",
+      "",
+    )
+    .into_get_resolve();
+  let handler = handler(NextConfigComplete::default());
+
+  for layer in [None, Some("rsc"), Some("ssr")] {
+    for (request, resolved) in [
+      ("gyp-addon", "/app/node_modules/gyp-addon/index.js"),
+      ("prebuilt", "/app/node_modules/prebuilt/prebuilt.node"),
+    ] {
+      let decision = handler
+        .decide(
+          PAGES.to_string(),
+          request.to_string(),
+          "cjs",
+          layer,
+          get_resolve.clone(),
+        )
+        .await
+        .unwrap();
+      assert_eq!(
+        decision.rule,
+        DecisionRule::NativeAddon,
+        "{request} {layer:?}"
+      );
+      assert_eq!(decision.external, Some(format!("commonjs {request}")));
+      assert_eq!(decision.resolved.as_deref(), Some(resolved));
+    }
+  }
+
+  // Header only dependencies don't make a package native, and transpiled
+  // packages are bundled, except for their `.node` files, which relative
+  // requests require by path.
+  for (context, request, rule, external) in [
+    (
+      PAGES,
+      "headers",
+      DecisionRule::NodeModulesExternal,
+      Some("commonjs headers"),
+    ),
+    (
+      PAGES,
+      "transpiled-pkg",
+      DecisionRule::TranspilePackage,
+      None,
+    ),
+    (
+      PAGES,
+      "transpiled-pkg/addon.node",
+      DecisionRule::NativeAddon,
+      Some("commonjs transpiled-pkg/addon.node"),
+    ),
+    (
+      "/app/node_modules/transpiled-pkg",
+      "./build/Release/addon.node",
+      DecisionRule::NativeAddon,
+      Some("commonjs /app/node_modules/transpiled-pkg/build/Release/addon.node"),
+    ),
+  ] {
+    let decision = handler
+      .decide(
+        context.to_string(),
+        request.to_string(),
+        "cjs",
+        None,
+        get_resolve.clone(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      (decision.rule, decision.external.as_deref()),
+      (rule, external),
+      "{request}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn next_version_profiles() {
+  let get_resolve = fixture().into_get_resolve();
+  let decide = |handler: ExternalHandler, request: &'static str, layer: Option<&'static str>| {
+    let get_resolve = get_resolve.clone();
+    async move {
+      handler
+        .decide(
+          PAGES.to_string(),
+          request.to_string(),
+          "cjs",
+          layer,
+          get_resolve,
+        )
+        .await
+        .map(|decision| decision.rule)
+        .ok()
+    }
+  };
+  let next_14 = || handler(NextConfigComplete::default()).with_next_version(NextVersion::V14);
+  let next_15 = || handler(NextConfigComplete::default()).with_next_version(NextVersion::V15);
+
+  // Entries added in Next.js 15 are left to the other rules by Next.js 14.
+  assert_eq!(
+    decide(next_15(), "next/cache", None).await,
+    Some(DecisionRule::NotExternalModule)
+  );
+  assert_ne!(
+    decide(next_14(), "next/cache", None).await,
+    Some(DecisionRule::NotExternalModule)
+  );
+  assert_eq!(
+    decide(next_14(), "next/link", None).await,
+    Some(DecisionRule::NotExternalModule)
+  );
+
+  // Next.js 14 routes had layers of their own, and middleware wasn't an app
+  // layer yet.
+  assert_eq!(
+    decide(next_14(), "react", Some("app-route-handler")).await,
+    Some(DecisionRule::BundledLayer)
+  );
+  assert_eq!(
+    decide(next_14(), "react", Some("middleware")).await,
+    Some(DecisionRule::ReactPackage)
+  );
+  assert_eq!(
+    decide(next_15(), "react", Some("middleware")).await,
+    Some(DecisionRule::BundledLayer)
+  );
+  assert_eq!(
+    decide(
+      next_14(),
+      "next/dist/compiled/@vercel/og/index.node.js",
+      Some("app-route-handler")
+    )
+    .await,
+    Some(DecisionRule::VercelOg)
+  );
+
+  assert_ne!(
+    next_14().config_fingerprint(),
+    next_15().config_fingerprint()
+  );
+}
+
+#[tokio::test]
+async fn next_dist_layout_across_versions() {
+  // The shared, compiled and `.external` files of `next/dist` are laid out the
+  // same way in every supported version, so their rules aren't per profile.
+  let cases = [
+    (
+      "next/dist/shared/lib/utils",
+      DecisionRule::NextSharedCjs,
+      Some("commonjs next/dist/shared/lib/utils"),
+    ),
+    (
+      "next/dist/compiled/react/index.js",
+      DecisionRule::NextSharedCjs,
+      Some("commonjs next/dist/compiled/react/index.js"),
+    ),
+    (
+      "next/dist/esm/shared/lib/utils.js",
+      DecisionRule::NextSharedEsm,
+      Some("module next/dist/esm/shared/lib/utils.js"),
+    ),
+    (
+      "next/dist/compiled/react/index.mjs",
+      DecisionRule::NextSharedEsm,
+      Some("module next/dist/compiled/react/index.mjs"),
+    ),
+    (
+      "next/dist/shared/lib/router/router",
+      DecisionRule::NextDistExternal,
+      None,
+    ),
+    (
+      "next/dist/client/components/request-async-storage.external.js",
+      DecisionRule::NextDistExternal,
+      Some("commonjs next/dist/client/components/request-async-storage.external.js"),
+    ),
+    (
+      "next/dist/esm/server/app-render/work-unit-async-storage.external",
+      DecisionRule::NextDistExternal,
+      Some("commonjs next/dist/esm/server/app-render/work-unit-async-storage.external"),
+    ),
+    (
+      "next/dist/server/lib/shared-runtime.js",
+      DecisionRule::NextDistExternal,
+      None,
+    ),
+  ];
+
+  let get_resolve = fixture().into_get_resolve();
+  for next_version in NextVersion::VALUES {
+    let handler = handler(NextConfigComplete::default()).with_next_version(*next_version);
+    for (request, rule, external) in cases {
+      let decision = handler
+        .decide(
+          PAGES.to_string(),
+          request.to_string(),
+          "cjs",
+          None,
+          get_resolve.clone(),
+        )
+        .await
+        .unwrap();
+      assert_eq!(
+        (decision.rule, decision.external.as_deref()),
+        (rule, external),
+        "{request} in Next.js {}",
+        next_version.major()
+      );
+    }
+  }
+}
+
+#[tokio::test]
+async fn runtime_roots() {
+  // A workspace package of a monorepo with a dependency that isn't hoisted,
+  // so the app can't resolve it.
+  let get_resolve = PackageTree::new()
+    .with_package_json("/repo/apps/web", json!({ "name": "web" }))
+    .with_file("/repo/apps/web/pages/index.js", "")
+    .with_package_json("/repo/packages/ui", json!({ "name": "ui" }))
+    .with_file("/repo/packages/ui/index.js", "")
+    .with_package_json(
+      "/repo/packages/ui/node_modules/ui-dep",
+      json!({ "main": "index.js" }),
+    )
+    .with_file("/repo/packages/ui/node_modules/ui-dep/index.js", "")
+    .into_get_resolve();
+  let handler = || {
+    ExternalHandler::new(
+      NextConfigComplete::default(),
+      None,
+      vec![],
+      vec![],
+      "/repo/apps/web".to_string(),
+      FxHashMap::default(),
+    )
+  };
+  let decide = |handler: ExternalHandler| {
+    let get_resolve = get_resolve.clone();
+    async move {
+      handler
+        .decide(
+          "/repo/packages/ui".to_string(),
+          "ui-dep".to_string(),
+          "cjs",
+          None,
+          get_resolve,
+        )
+        .await
+        .unwrap()
+    }
+  };
+
+  let app_only = handler().with_runtime_roots(vec![]);
+  assert_eq!(app_only.runtime_roots(), ["/repo/apps/web"]);
+  assert_eq!(
+    decide(app_only).await.rule,
+    DecisionRule::BaseResolveMismatch
+  );
+
+  let workspace = handler().with_runtime_roots(vec![
+    "/repo/apps/web".to_string(),
+    "../../packages/ui".to_string(),
+  ]);
+  assert_eq!(
+    workspace.runtime_roots(),
+    ["/repo/apps/web", "/repo/packages/ui"]
+  );
+  assert_ne!(
+    workspace.config_fingerprint(),
+    handler().config_fingerprint()
+  );
+  let decision = decide(workspace).await;
+  assert_eq!(decision.rule, DecisionRule::NodeModulesExternal);
+  assert_eq!(decision.external.as_deref(), Some("commonjs ui-dep"));
+}
+
+#[tokio::test]
+async fn copies_of_a_package_version() {
+  // The same version of `copied` installed twice, as with pnpm's
+  // `node-linker=hoisted`, and two versions of `versioned`.
+  let get_resolve = fixture()
+    .with_package_json(
+      "/app/node_modules/copied",
+      json!({ "name": "copied", "version": "1.0.0" }),
+    )
+    .with_file("/app/node_modules/copied/index.js", "")
+    .with_package_json(
+      "/app/node_modules/parent/node_modules/copied",
+      json!({ "name": "copied", "version": "1.0.0" }),
+    )
+    .with_file("/app/node_modules/parent/node_modules/copied/index.js", "")
+    .with_package_json(
+      "/app/node_modules/versioned",
+      json!({ "name": "versioned", "version": "1.0.0" }),
+    )
+    .with_file("/app/node_modules/versioned/index.js", "")
+    .with_package_json(
+      "/app/node_modules/parent/node_modules/versioned",
+      json!({ "name": "versioned", "version": "2.0.0" }),
+    )
+    .with_file(
+      "/app/node_modules/parent/node_modules/versioned/index.js",
+      "",
+    )
+    .into_get_resolve();
+  let handler = handler(NextConfigComplete::default());
+
+  let copied = handler
+    .decide(
+      "/app/node_modules/parent".to_string(),
+      "copied".to_string(),
+      "cjs",
+      None,
+      get_resolve.clone(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(copied.rule, DecisionRule::NodeModulesExternal);
+  assert_eq!(copied.external.as_deref(), Some("commonjs copied"));
+
+  let versioned = handler
+    .decide(
+      "/app/node_modules/parent".to_string(),
+      "versioned".to_string(),
+      "cjs",
+      None,
+      get_resolve,
+    )
+    .await
+    .unwrap();
+  assert_eq!(versioned.rule, DecisionRule::BaseResolveMismatch);
+  assert_eq!(handler.stats().snapshot().base_resolve_mismatches, 1);
+}
@@ -1,16 +1,41 @@
+mod builtin_modules;
 mod config_shared;
+mod esm_externals_error;
+mod externals_fallback;
+mod externals_rules;
+mod externals_stats;
+mod externals_trace;
 mod handle_externals;
+mod native_addons;
 mod next_externals_plugin;
+mod next_version;
+mod package_identity;
+mod persistent_decisions;
+#[cfg(test)]
+mod test_utils;
+mod webpack_layers;
+mod yarn_pnp;
+
+use std::sync::Arc;
 
 use napi::bindgen_prelude::*;
 use rspack_binding_builder_macros::register_plugin;
 use rspack_core::BoxPlugin;
+use rspack_error::{miette::MietteDiagnostic, Diagnostic};
+use rspack_napi::threadsafe_function::ThreadsafeFunction;
 use rspack_regex::RspackRegex;
 use rustc_hash::FxHashMap;
 
 use crate::{
   config_shared::{EsmExternalsConfig, ExperimentalConfig, NextConfigComplete},
-  next_externals_plugin::{NextExternalsPlugin, NextExternalsPluginOptions},
+  externals_fallback::{ExternalsFallback, ExternalsFallbackRequest},
+  externals_rules::{ExternalsRule, LayerMatcher, RequestMatcher, RuleExternal},
+  externals_stats::{get_externals_stats, ExternalsStatsSnapshot},
+  next_externals_plugin::{
+    CompilerType, EdgeRuntimeOptions, NextExternalsPlugin, NextExternalsPluginOptions,
+  },
+  next_version::NextVersion,
+  webpack_layers::WebpackLayers,
 };
 
 #[macro_use]
@@ -21,18 +46,37 @@ extern crate rspack_binding_builder;
 #[napi(object, object_to_js = false)]
 pub struct NapiExperimentalConfig {
   pub esm_externals: Option<Either<String, bool>>,
+  /// Packages resolved in loose ESM externals mode regardless of `esmExternals`, even when it is `false`, so ESM only packages can be required and stay external.
+  pub loose_esm_externals_packages: Option<Vec<String>>,
+  /// Packages never resolved in loose ESM externals mode, even when `esmExternals` is `"loose"`.
+  pub strict_esm_externals_packages: Option<Vec<String>>,
 }
 
-impl From<NapiExperimentalConfig> for ExperimentalConfig {
-  fn from(value: NapiExperimentalConfig) -> Self {
-    ExperimentalConfig {
-      esm_externals: match value.esm_externals {
+impl TryFrom<NapiExperimentalConfig> for ExperimentalConfig {
+  type Error = rspack_error::Error;
+
+  fn try_from(value: NapiExperimentalConfig) -> rspack_error::Result<Self> {
+    let NapiExperimentalConfig {
+      esm_externals,
+      loose_esm_externals_packages,
+      strict_esm_externals_packages,
+    } = value;
+    Ok(ExperimentalConfig {
+      esm_externals: match esm_externals {
         Some(esm_externals) => match esm_externals {
           Either::A(s) => {
             if s == "loose" {
               EsmExternalsConfig::Loose
             } else {
-              EsmExternalsConfig::Strict
+              return Err(
+                MietteDiagnostic::new(format!("Invalid value {s:?} for experimental.esmExternals"))
+                  .with_code("NextExternalsPlugin")
+                  .with_help(format!(
+                    "Valid options are: {}",
+                    EsmExternalsConfig::VALID_VALUES.join(", ")
+                  ))
+                  .into(),
+              );
             }
           }
           Either::B(b) => {
@@ -45,7 +89,9 @@ impl From<NapiExperimentalConfig> for ExperimentalConfig {
         },
         None => EsmExternalsConfig::None,
       },
-    }
+      loose_esm_externals_packages: loose_esm_externals_packages.unwrap_or_default(),
+      strict_esm_externals_packages: strict_esm_externals_packages.unwrap_or_default(),
+    })
   }
 }
 
@@ -54,60 +100,387 @@ impl From<NapiExperimentalConfig> for ExperimentalConfig {
 pub struct NapiNextConfigComplete {
   pub experimental: NapiExperimentalConfig,
   pub bundle_pages_router_dependencies: Option<bool>,
+  /// Packages never bundled on the server, on top of `defaultServerExternalPackages`.
+  pub server_external_packages: Option<Vec<String>>,
 }
 
-impl From<NapiNextConfigComplete> for NextConfigComplete {
-  fn from(value: NapiNextConfigComplete) -> Self {
+impl TryFrom<NapiNextConfigComplete> for NextConfigComplete {
+  type Error = rspack_error::Error;
+
+  fn try_from(value: NapiNextConfigComplete) -> rspack_error::Result<Self> {
     let NapiNextConfigComplete {
       experimental,
       bundle_pages_router_dependencies,
+      server_external_packages,
     } = value;
-    NextConfigComplete {
-      experimental: experimental.into(),
+    Ok(NextConfigComplete {
+      experimental: experimental.try_into()?,
       bundle_pages_router_dependencies,
+      server_external_packages: server_external_packages.unwrap_or_default(),
+    })
+  }
+}
+
+#[derive(Debug)]
+#[napi(object, object_to_js = false)]
+pub struct NapiEdgeRuntimeOptions {
+  /// Node.js modules provided by the edge runtime, which stay external as `node:<module>`.
+  /// Replaces the defaults: `buffer`, `events`, `assert`, `util` and `async_hooks`.
+  pub polyfilled_modules: Option<Vec<String>>,
+  /// Requests replaced with the given expression in edge bundles.
+  /// Replaces the defaults, which stub `@builder.io/partytown` and `next/dist/compiled/etag` with `{}`.
+  #[napi(ts_type = "Record<string, string>")]
+  pub stubs: Option<FxHashMap<String, String>>,
+  /// Fail the build, instead of warning, when a middleware or edge API route imports a Node.js module the edge runtime doesn't support.
+  pub strict_unsupported_modules: Option<bool>,
+}
+
+impl From<NapiEdgeRuntimeOptions> for EdgeRuntimeOptions {
+  fn from(value: NapiEdgeRuntimeOptions) -> Self {
+    let NapiEdgeRuntimeOptions {
+      polyfilled_modules,
+      stubs,
+      strict_unsupported_modules,
+    } = value;
+    let defaults = EdgeRuntimeOptions::default();
+    EdgeRuntimeOptions {
+      polyfilled_modules: polyfilled_modules.unwrap_or(defaults.polyfilled_modules),
+      stubs: stubs.unwrap_or(defaults.stubs),
+      strict_unsupported_modules: strict_unsupported_modules
+        .unwrap_or(defaults.strict_unsupported_modules),
+    }
+  }
+}
+
+#[derive(Debug)]
+#[napi(object, object_to_js = false)]
+pub struct NapiExternalsRule {
+  /// A glob, where `*` doesn't match `/` and `**` matches anything, or a RegExp tested against the request.
+  #[napi(ts_type = "string | RegExp")]
+  pub request: Either<String, RspackRegex>,
+  /// The layer of the issuing module, with `""` matching modules without a layer.
+  #[napi(ts_type = "string | RegExp")]
+  pub issuer_layer: Option<Either<String, RspackRegex>>,
+  /// The compilers the rule applies to, all of them by default.
+  #[napi(ts_type = "CompilerType[]")]
+  pub compiler_types: Option<Vec<String>>,
+  /// Dependency categories such as `"esm"`, `"commonjs"` or `"url"`, all of them by default.
+  pub dependency_types: Option<Vec<String>>,
+  /// `"bundle"` to bundle the request, `"commonjs"` or `"module"` to keep it external with that type, or any other externals value used as is.
+  #[napi(ts_type = "'bundle' | 'commonjs' | 'module' | (string & {})")]
+  pub external: String,
+}
+
+impl TryFrom<NapiExternalsRule> for ExternalsRule {
+  type Error = napi::Error;
+
+  fn try_from(value: NapiExternalsRule) -> Result<Self> {
+    let NapiExternalsRule {
+      request,
+      issuer_layer,
+      compiler_types,
+      dependency_types,
+      external,
+    } = value;
+    Ok(ExternalsRule {
+      request: request_matcher(request),
+      issuer_layer: issuer_layer.map(|issuer_layer| match issuer_layer {
+        Either::A(name) => LayerMatcher::Name(name),
+        Either::B(regex) => LayerMatcher::Regex(regex),
+      }),
+      compiler_types: compiler_types
+        .map(|compiler_types| {
+          compiler_types
+            .iter()
+            .map(|compiler_type| compiler_type.parse::<CompilerType>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .map_err(|e| napi::Error::new(Status::InvalidArg, e))?,
+      dependency_types,
+      external: RuleExternal::parse(external),
+    })
+  }
+}
+
+#[derive(Debug)]
+#[napi(object, object_to_js = false)]
+pub struct NapiWebpackLayerGroups {
+  pub bundled: Option<Vec<String>>,
+  pub server_only: Option<Vec<String>>,
+}
+
+/// The layer groups of Next.js, given directly or as its `WEBPACK_LAYERS` object.
+#[derive(Debug)]
+#[napi(object, object_to_js = false)]
+pub struct NapiWebpackLayers {
+  /// Layers of the app directory, whose React packages are bundled.
+  pub bundled: Option<Vec<String>>,
+  /// Layers resolved with the `react-server` condition. Each of them has to be a bundled layer.
+  pub server_only: Option<Vec<String>>,
+  /// The groups of `WEBPACK_LAYERS`, used for the groups not given directly.
+  #[napi(js_name = "GROUP")]
+  pub group: Option<NapiWebpackLayerGroups>,
+}
+
+impl NapiWebpackLayers {
+  fn into_webpack_layers(self, next_version: NextVersion) -> Result<WebpackLayers> {
+    let NapiWebpackLayers {
+      bundled,
+      server_only,
+      group,
+    } = self;
+    let (group_bundled, group_server_only) = group
+      .map(|group| (group.bundled, group.server_only))
+      .unwrap_or_default();
+    WebpackLayers::new(
+      next_version,
+      bundled.or(group_bundled),
+      server_only.or(group_server_only),
+    )
+    .map_err(|e| napi::Error::new(Status::InvalidArg, e))
+  }
+}
+
+/// A request passed to the `fallback` callback.
+#[derive(Debug)]
+#[napi(object, object_from_js = false)]
+pub struct NapiExternalsFallbackRequest {
+  pub context: String,
+  pub request: String,
+  /// The layer of the issuing module.
+  pub layer: Option<String>,
+  /// The external chosen by the built-in externals, or `undefined` if they bundle the request.
+  pub decision: Option<String>,
+}
+
+impl From<ExternalsFallbackRequest> for NapiExternalsFallbackRequest {
+  fn from(value: ExternalsFallbackRequest) -> Self {
+    let ExternalsFallbackRequest {
+      context,
+      request,
+      layer,
+      decision,
+    } = value;
+    NapiExternalsFallbackRequest {
+      context,
+      request,
+      layer,
+      decision,
     }
   }
 }
 
+#[derive(Debug)]
+#[napi(object, object_to_js = false)]
+pub struct NapiExternalsFallback {
+  /// Globs or RegExps matched like the `request` of rules. Only matching requests are passed to the callback.
+  #[napi(ts_type = "Array<string | RegExp>")]
+  pub prefilter: Vec<Either<String, RspackRegex>>,
+  /// Resolves to an `external` value like the one of rules overriding the decision, or `undefined` to keep it.
+  #[napi(
+    ts_type = "(request: NapiExternalsFallbackRequest) => Promise<'bundle' | 'commonjs' | 'module' | (string & {}) | undefined>"
+  )]
+  pub callback: ThreadsafeFunction<NapiExternalsFallbackRequest, Promise<Option<String>>>,
+}
+
+impl From<NapiExternalsFallback> for ExternalsFallback {
+  fn from(value: NapiExternalsFallback) -> Self {
+    let NapiExternalsFallback {
+      prefilter,
+      callback,
+    } = value;
+    ExternalsFallback::new(
+      prefilter.into_iter().map(request_matcher).collect(),
+      Arc::new(move |request| {
+        let callback = callback.clone();
+        Box::pin(async move {
+          Ok(
+            callback
+              .call_with_promise(request.into())
+              .await?
+              .map(RuleExternal::parse),
+          )
+        })
+      }),
+    )
+  }
+}
+
+fn request_matcher(request: Either<String, RspackRegex>) -> RequestMatcher {
+  match request {
+    Either::A(glob) => RequestMatcher::glob(&glob),
+    Either::B(regex) => RequestMatcher::Regex(regex),
+  }
+}
+
 #[derive(Debug)]
 #[napi(object, object_to_js = false)]
 pub struct NapiNextExternalsPluginOptions {
+  #[napi(ts_type = "CompilerType")]
   pub compiler_type: String,
   pub config: NapiNextConfigComplete,
+  /// Node.js builtin modules such as `require('module').builtinModules`, with or without the `node:` scheme. Subpaths are only recognized when listed, such as `fs/promises`, and `node:` scheme only modules such as `node:test` either way.
   pub builtin_modules: Vec<String>,
+  /// Matched against resolved paths, for opt-outs that can't be expressed as package names.
   #[napi(ts_type = "RegExp")]
-  pub opt_out_bundling_package_regex: RspackRegex,
+  pub opt_out_bundling_package_regex: Option<RspackRegex>,
+  /// Packages never bundled on the server. Replaces the list built into Next.js.
+  pub default_server_external_packages: Option<Vec<String>>,
   pub final_transpile_packages: Vec<String>,
   pub dir: String,
   #[napi(ts_type = "Record<string, string>")]
   pub default_overrides: FxHashMap<String, String>,
+  /// The version of Next.js, e.g. `"14.2.3"`, selecting the rules of its major: 14 or 15, the default.
+  pub next_version: Option<String>,
+  /// The layer groups of the Next.js release, defaulting to the ones of `nextVersion`.
+  pub layers: Option<NapiWebpackLayers>,
+  /// Resolve packages with the Yarn Plug'n'Play manifest (`.pnp.cjs`) of the project, for projects without `node_modules` directories.
+  pub pnp: Option<bool>,
+  /// Directories packages are resolved from at runtime, such as `dir`, the workspace root or the root of a deployment. A package is only externalized if it resolves to the same file from one of them. Relative ones are relative to `dir`, defaults to `[dir]`.
+  pub runtime_roots: Option<Vec<String>>,
+  /// Emit a `next-externals-trace-<compilerType>.json` asset recording the outcome of each request, and whether a user rule, the builtin modules, the built-in externals or the fallback decided it.
+  pub trace: Option<bool>,
+  /// Log the externals counters of `getNextExternalsStats` after each compilation, shown in the stats output.
+  pub log_stats: Option<bool>,
+  /// Polyfills, stubs and diagnostics of the edge runtime, used when `compilerType` is `"edge-server"`.
+  pub edge_runtime: Option<NapiEdgeRuntimeOptions>,
+  /// Plugins created with the same id, `dir` and configuration, and applied to compilers with the same `resolve` options, share resolved packages and externals decisions, e.g. the server compilers of one build. Only server compilers benefit, as the client and edge compilers don't resolve externals.
+  pub shared_context_id: Option<String>,
+  /// Evaluated in order before the built-in externals, without calling back into JavaScript. The first matching rule decides the request.
+  pub rules: Option<Vec<NapiExternalsRule>>,
+  /// Called for requests matching its prefilter that no rule matched, for logic that can't be expressed as rules.
+  pub fallback: Option<NapiExternalsFallback>,
 }
 
-impl From<NapiNextExternalsPluginOptions> for NextExternalsPluginOptions {
-  fn from(value: NapiNextExternalsPluginOptions) -> Self {
+impl TryFrom<NapiNextExternalsPluginOptions> for NextExternalsPluginOptions {
+  type Error = napi::Error;
+
+  fn try_from(value: NapiNextExternalsPluginOptions) -> Result<Self> {
     let NapiNextExternalsPluginOptions {
       compiler_type,
       config,
       builtin_modules,
       opt_out_bundling_package_regex,
+      default_server_external_packages,
       final_transpile_packages,
       dir,
       default_overrides,
+      next_version,
+      layers,
+      pnp,
+      runtime_roots,
+      trace,
+      log_stats,
+      edge_runtime,
+      shared_context_id,
+      rules,
+      fallback,
     } = value;
-    NextExternalsPluginOptions {
-      compiler_type,
-      config: config.into(),
+    let next_version = next_version
+      .map(|next_version| next_version.parse::<NextVersion>())
+      .transpose()
+      .map_err(|e| napi::Error::new(Status::InvalidArg, e))?
+      .unwrap_or_default();
+    Ok(NextExternalsPluginOptions {
+      compiler_type: compiler_type
+        .parse::<CompilerType>()
+        .map_err(|e| napi::Error::new(Status::InvalidArg, e))?,
+      config: config.try_into().map_err(to_napi_error)?,
       builtin_modules,
       opt_out_bundling_package_regex,
+      default_server_external_packages: default_server_external_packages
+        .unwrap_or_else(handle_externals::default_server_external_packages),
       final_transpile_packages,
       dir,
       default_overrides,
+      next_version,
+      layers: match layers {
+        Some(layers) => layers.into_webpack_layers(next_version)?,
+        None => WebpackLayers::for_version(next_version),
+      },
+      pnp: pnp.unwrap_or(false),
+      runtime_roots: runtime_roots.unwrap_or_default(),
+      trace: trace.unwrap_or(false),
+      log_stats: log_stats.unwrap_or(false),
+      edge_runtime: edge_runtime.map(Into::into).unwrap_or_default(),
+      shared_context_id,
+      rules: rules
+        .unwrap_or_default()
+        .into_iter()
+        .map(TryInto::try_into)
+        .collect::<Result<_>>()?,
+      fallback: fallback.map(Into::into),
+    })
+  }
+}
+
+#[derive(Debug)]
+#[napi(object, object_from_js = false)]
+pub struct NapiNextExternalsStats {
+  /// Calls of the externals function.
+  pub calls: u32,
+  /// Calls per issuer layer, with `""` for modules without a layer.
+  #[napi(ts_type = "Record<string, number>")]
+  pub calls_per_layer: FxHashMap<String, u32>,
+  /// Calls per rule that decided the request, including cached decisions.
+  #[napi(ts_type = "Record<string, number>")]
+  pub decisions_per_rule: FxHashMap<String, u32>,
+  /// Requests passed to the resolver.
+  pub resolver_calls: u32,
+  /// Requests bundled because they resolve to something else from the project `dir`.
+  pub base_resolve_mismatches: u32,
+  /// Requests passed to the `fallback` callback.
+  pub fallback_calls: u32,
+  pub total_time_ms: f64,
+  /// Upper bound of the 99th percentile of the time spent per call, within 12.5%.
+  pub p99_time_ms: f64,
+}
+
+impl From<ExternalsStatsSnapshot> for NapiNextExternalsStats {
+  fn from(value: ExternalsStatsSnapshot) -> Self {
+    let count = |count: u64| count.try_into().unwrap_or(u32::MAX);
+    NapiNextExternalsStats {
+      calls: count(value.calls),
+      calls_per_layer: value
+        .calls_per_layer
+        .into_iter()
+        .map(|(layer, calls)| (layer.unwrap_or_default(), count(calls)))
+        .collect(),
+      decisions_per_rule: value
+        .decisions_per_rule
+        .into_iter()
+        .map(|(rule, decisions)| (rule.to_string(), count(decisions)))
+        .collect(),
+      resolver_calls: count(value.resolver_calls),
+      base_resolve_mismatches: count(value.base_resolve_mismatches),
+      fallback_calls: count(value.fallback_calls),
+      total_time_ms: value.total_time.as_secs_f64() * 1000.0,
+      p99_time_ms: value.p99_time.as_secs_f64() * 1000.0,
     }
   }
 }
 
+/// Returns the externals counters of the compiler with the given name, or the
+/// compiler type if it has no name, accumulated since its plugin was created.
+#[napi]
+pub fn get_next_externals_stats(compiler_name: String) -> Option<NapiNextExternalsStats> {
+  get_externals_stats(&compiler_name).map(Into::into)
+}
+
+fn to_napi_error(error: rspack_error::Error) -> napi::Error {
+  let diagnostic = Diagnostic::from(error);
+  let reason = diagnostic
+    .render_report(false)
+    .unwrap_or_else(|_| diagnostic.message());
+  napi::Error::new(Status::InvalidArg, reason)
+}
+
 register_plugin!("NextExternalsPlugin", |env: Env, object: Unknown<'_>| {
   let napi_options: NapiNextExternalsPluginOptions =
     unsafe { FromNapiValue::from_napi_value(env.raw(), object.raw())? };
-  Ok(Box::new(NextExternalsPlugin::new(napi_options.into())) as BoxPlugin)
+  Ok(Box::new(NextExternalsPlugin::new(napi_options.try_into()?)) as BoxPlugin)
 });
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,60 @@
+//! Detects native Node.js addons: `.node` files, and the packages whose
+//! modules load them. Neither can be bundled, and they only run in Node.js.
+
+use serde_json::Value;
+
+const NATIVE_ADDON_EXTENSION: &str = ".node";
+
+/// Packages locating and loading the `.node` file of the package depending
+/// on them, or building it on install. Header only dependencies such as `nan`
+/// and `node-addon-api` aren't loaders, as they are also depended on by
+/// packages whose addons are optional or live in other packages; the ones
+/// built by node-gyp are found by their `gypfile` instead.
+const NATIVE_ADDON_LOADERS: &[&str] = &[
+  "bindings",
+  "node-gyp-build",
+  "node-gyp-build-optional-packages",
+  "@mapbox/node-pre-gyp",
+  "node-pre-gyp",
+  "prebuild-install",
+  "@neon-rs/load",
+];
+
+/// Whether a resolved path is a native addon.
+pub fn is_native_addon(path: &str) -> bool {
+  path.ends_with(NATIVE_ADDON_EXTENSION)
+}
+
+/// Whether a `package.json` describes a package loading native addons: it is
+/// built by node-gyp (`gypfile`), downloads prebuilt binaries with
+/// node-pre-gyp (`binary`), is built by napi-rs (`napi`), or depends on an
+/// addon loader.
+pub fn loads_native_addons(package_json: &Value) -> bool {
+  let Some(package_json) = package_json.as_object() else {
+    return false;
+  };
+  package_json.get("gypfile").and_then(Value::as_bool) == Some(true)
+    || package_json.get("binary").is_some_and(Value::is_object)
+    || package_json.get("napi").is_some_and(Value::is_object)
+    || ["dependencies", "optionalDependencies"]
+      .iter()
+      .any(|field| {
+        package_json
+          .get(*field)
+          .and_then(Value::as_object)
+          .is_some_and(|dependencies| {
+            NATIVE_ADDON_LOADERS
+              .iter()
+              .any(|loader| dependencies.contains_key(*loader))
+          })
+      })
+}
+
+/// Whether a resolved path is a native addon, or a module of a package
+/// loading them, given the `package.json` nearest to it.
+pub fn is_native(path: &str, package_json: Option<&Value>) -> bool {
+  is_native_addon(path) || package_json.is_some_and(loads_native_addons)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,48 @@
+use serde_json::json;
+
+use super::*;
+
+#[test]
+fn native_addon_files() {
+  assert!(is_native_addon(
+    "/app/node_modules/pkg/build/Release/pkg.node"
+  ));
+  assert!(!is_native_addon("/app/node_modules/pkg/index.js"));
+  assert!(!is_native_addon("/app/node_modules/node/index.js"));
+}
+
+#[test]
+fn packages_loading_native_addons() {
+  for package_json in [
+    json!({ "name": "gyp", "gypfile": true }),
+    json!({ "name": "pre-gyp", "binary": { "module_name": "pre_gyp" } }),
+    json!({ "name": "napi", "napi": { "name": "napi" } }),
+    json!({ "name": "bindings-user", "dependencies": { "bindings": "^1.5.0" } }),
+    json!({ "name": "prebuilt", "optionalDependencies": { "node-gyp-build": "^4.0.0" } }),
+    json!({ "name": "nan-gyp", "gypfile": true, "dependencies": { "nan": "^2.0.0" } }),
+  ] {
+    assert!(loads_native_addons(&package_json), "{package_json}");
+  }
+
+  for package_json in [
+    json!({ "name": "js", "main": "index.js" }),
+    json!({ "name": "no-gyp", "gypfile": false }),
+    json!({ "name": "dev-only", "devDependencies": { "node-gyp-build": "^4.0.0" } }),
+    json!({ "name": "headers", "dependencies": { "nan": "^2.0.0" } }),
+    json!({ "name": "napi-headers", "dependencies": { "node-addon-api": "^8.0.0" } }),
+    json!("not an object"),
+  ] {
+    assert!(!loads_native_addons(&package_json), "{package_json}");
+  }
+}
+
+#[test]
+fn native_paths() {
+  let package_json = json!({ "name": "gyp", "gypfile": true });
+  assert!(is_native(
+    "/app/node_modules/gyp/index.js",
+    Some(&package_json)
+  ));
+  assert!(is_native("/app/addon.node", None));
+  assert!(!is_native("/app/index.js", Some(&json!({ "name": "app" }))));
+}
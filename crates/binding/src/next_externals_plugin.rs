@@ -1,29 +1,85 @@
-use std::{
-  path::Path,
-  sync::{Arc, LazyLock},
-};
+use std::{collections::BTreeMap, path::Path, sync::Arc, time::Instant};
 
+use dashmap::DashSet;
 use rspack_core::{
-  ApplyContext, CompilerOptions, DependencyCategory, ExternalItem, ExternalItemFnCtx,
-  ExternalItemFnResult, ExternalItemObject, ExternalItemValue, Plugin, PluginContext,
+  ApplyContext, AssetInfo, Compilation, CompilationAsset, CompilationFinishModules,
+  CompilationParams, CompilationProcessAssets, CompilerOptions, CompilerThisCompilation,
+  DependencyCategory, ExternalItem, ExternalItemFnCtx, ExternalItemFnResult, ExternalItemObject,
+  ExternalItemValue, LogType, Logger, ModuleGraph, ModuleIdentifier, Plugin, PluginContext,
   ResolveOptionsWithDependencyType, ResolveResult,
 };
-use rspack_error::ToStringResultToRspackResultExt;
-use rspack_hook::plugin;
+use rspack_error::{Diagnostic, ToStringResultToRspackResultExt};
+use rspack_hook::{plugin, plugin_hook};
 use rspack_plugin_externals::ExternalsPlugin;
 use rspack_regex::RspackRegex;
-use rustc_hash::{FxHashMap, FxHashSet};
+use rspack_sources::{RawStringSource, SourceExt};
+use rustc_hash::{FxBuildHasher, FxHashMap, FxHashSet};
+
+use crate::{
+  builtin_modules::BuiltinModules,
+  config_shared::NextConfigComplete,
+  esm_externals_error::EsmExternalsError,
+  externals_fallback::ExternalsFallback,
+  externals_rules::{ExternalsRule, ExternalsRules},
+  externals_stats::{register_externals_stats, ExternalsStats, ExternalsStatsSnapshot},
+  externals_trace::{external_json, ExternalsTrace, ExternalsTraceEntry, TraceSource},
+  handle_externals::{ExternalHandler, ResolvedRequest},
+  native_addons,
+  next_version::NextVersion,
+  package_identity::PackageIdentity,
+  persistent_decisions::PersistentDecisions,
+  webpack_layers::WebpackLayers,
+};
 
-use crate::{config_shared::NextConfigComplete, handle_externals::ExternalHandler};
+static DEFAULT_EDGE_POLYFILLED_MODULES: &[&str] =
+  &["buffer", "events", "assert", "util", "async_hooks"];
 
-static SUPPORTED_NATIVE_MODULES: &[&str] = &["buffer", "events", "assert", "util", "async_hooks"];
+static DEFAULT_EDGE_STUBS: &[(&str, &str)] = &[
+  ("@builder.io/partytown", "{}"),
+  ("next/dist/compiled/etag", "{}"),
+];
 
-static SUPPORTED_EDGE_POLYFILLS: LazyLock<FxHashSet<&'static str>> =
-  LazyLock::new(|| SUPPORTED_NATIVE_MODULES.iter().copied().collect());
+/// Node.js modules provided by the edge runtime, and packages replaced with a
+/// stub expression in edge bundles.
+#[derive(Debug, Clone)]
+pub struct EdgeRuntimeOptions {
+  pub polyfilled_modules: Vec<String>,
+  pub stubs: FxHashMap<String, String>,
+  /// Report imports of unsupported Node.js modules as errors instead of
+  /// warnings.
+  pub strict_unsupported_modules: bool,
+}
+
+impl Default for EdgeRuntimeOptions {
+  fn default() -> Self {
+    Self {
+      polyfilled_modules: DEFAULT_EDGE_POLYFILLED_MODULES
+        .iter()
+        .map(|module| module.to_string())
+        .collect(),
+      stubs: DEFAULT_EDGE_STUBS
+        .iter()
+        .map(|(request, stub)| (request.to_string(), stub.to_string()))
+        .collect(),
+      strict_unsupported_modules: false,
+    }
+  }
+}
+
+impl EdgeRuntimeOptions {
+  fn is_polyfilled(&self, module_name: &str) -> bool {
+    let module_name = module_name.strip_prefix("node:").unwrap_or(module_name);
+    self
+      .polyfilled_modules
+      .iter()
+      .any(|module| module == module_name)
+  }
+}
 
-fn get_edge_polyfilled_modules() -> ExternalItem {
+fn get_edge_polyfilled_modules(polyfilled_modules: &[String]) -> ExternalItem {
   let mut externals = ExternalItemObject::default();
-  for &module in SUPPORTED_NATIVE_MODULES {
+  for module in polyfilled_modules {
+    let module = module.strip_prefix("node:").unwrap_or(module);
     externals.insert(
       module.to_string(),
       ExternalItemValue::String(format!("commonjs node:{module}")),
@@ -36,46 +92,76 @@ fn get_edge_polyfilled_modules() -> ExternalItem {
   ExternalItem::Object(externals)
 }
 
-fn is_node_js_module(module_name: &str, builtin_modules: &[String]) -> bool {
-  builtin_modules
-    .iter()
-    .any(|builtin_module| builtin_module == module_name)
+/// A Node.js module request that was rewritten to `__import_unsupported`
+/// because the edge runtime doesn't provide it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct UnsupportedEdgeModule {
+  issuer: String,
+  issuer_layer: Option<String>,
+  request: String,
 }
 
-fn is_supported_edge_polyfill(module_name: &str) -> bool {
-  SUPPORTED_EDGE_POLYFILLS.contains(module_name)
-}
+type UnsupportedEdgeModules = DashSet<UnsupportedEdgeModule, FxBuildHasher>;
+
+/// The rules of the edge runtime externals function in stats.
+const EDGE_BUNDLED_RULE: &str = "edge-bundled";
+const EDGE_USER_POLYFILL_RULE: &str = "edge-user-polyfill";
+const EDGE_UNSUPPORTED_MODULE_RULE: &str = "edge-unsupported-module";
 
 async fn handle_webpack_external_for_edge_runtime(
   ctx: ExternalItemFnCtx,
-  builtin_modules: Arc<Vec<String>>,
+  builtin_modules: Arc<BuiltinModules>,
+  edge_runtime: Arc<EdgeRuntimeOptions>,
+  unsupported_modules: Arc<UnsupportedEdgeModules>,
+  stats: Arc<ExternalsStats>,
 ) -> rspack_error::Result<ExternalItemFnResult> {
+  let start = Instant::now();
   let is_middleware_or_api_edge = match &ctx.context_info.issuer_layer {
     Some(layer) => layer == "middleware" || layer == "api-edge",
     None => false,
   };
 
-  let result = if is_middleware_or_api_edge
-    && is_node_js_module(&ctx.request, &builtin_modules)
-    && !is_supported_edge_polyfill(&ctx.request)
+  let (rule, result) = if is_middleware_or_api_edge
+    && builtin_modules
+      .canonical_name(&ctx.request)
+      .is_some_and(|module_name| !edge_runtime.is_polyfilled(module_name))
   {
     let resolver = ctx
       .resolver_factory
       .get(ctx.resolve_options_with_dependency_type);
+    stats.record_resolver_call();
     // Allow user to provide and use their polyfills, as we do with buffer.
     match resolver
       .resolve(Path::new(&ctx.context), &ctx.request)
       .await
     {
-      Ok(_) => None,
-      Err(_) => Some(ExternalItemValue::String(format!(
-        "root globalThis.__import_unsupported('{}')",
-        ctx.request
-      ))),
+      Ok(_) => (EDGE_USER_POLYFILL_RULE, None),
+      Err(_) => {
+        // Entries have no issuer to report the request against.
+        if !ctx.context_info.issuer.is_empty() {
+          unsupported_modules.insert(UnsupportedEdgeModule {
+            issuer: ctx.context_info.issuer.clone(),
+            issuer_layer: ctx.context_info.issuer_layer.clone(),
+            request: ctx.request.clone(),
+          });
+        }
+        (
+          EDGE_UNSUPPORTED_MODULE_RULE,
+          Some(ExternalItemValue::String(format!(
+            "root globalThis.__import_unsupported('{}')",
+            ctx.request
+          ))),
+        )
+      }
     }
   } else {
-    None
+    (EDGE_BUNDLED_RULE, None)
   };
+  stats.record_call(
+    ctx.context_info.issuer_layer.as_deref(),
+    rule,
+    start.elapsed(),
+  );
 
   Ok(ExternalItemFnResult {
     external_type: None,
@@ -83,23 +169,98 @@ async fn handle_webpack_external_for_edge_runtime(
   })
 }
 
+#[napi(string_enum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompilerType {
+  #[napi(value = "client")]
+  Client,
+  #[napi(value = "server")]
+  Server,
+  #[napi(value = "edge-server")]
+  EdgeServer,
+}
+
+impl CompilerType {
+  pub const VALUES: &[CompilerType] = &[Self::Client, Self::Server, Self::EdgeServer];
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Client => "client",
+      Self::Server => "server",
+      Self::EdgeServer => "edge-server",
+    }
+  }
+}
+
+impl std::fmt::Display for CompilerType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+impl std::str::FromStr for CompilerType {
+  type Err = String;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    Self::VALUES
+      .iter()
+      .find(|compiler_type| compiler_type.as_str() == value)
+      .copied()
+      .ok_or_else(|| {
+        let expected = Self::VALUES
+          .iter()
+          .map(|compiler_type| format!("\"{compiler_type}\""))
+          .collect::<Vec<_>>()
+          .join(", ");
+        format!("Invalid compilerType \"{value}\", expected one of {expected}")
+      })
+  }
+}
+
 #[derive(Debug)]
 pub struct NextExternalsPluginOptions {
-  pub compiler_type: String,
+  pub compiler_type: CompilerType,
   pub config: NextConfigComplete,
   pub builtin_modules: Vec<String>,
-  pub opt_out_bundling_package_regex: RspackRegex,
+  pub opt_out_bundling_package_regex: Option<RspackRegex>,
+  pub default_server_external_packages: Vec<String>,
   pub final_transpile_packages: Vec<String>,
   pub dir: String,
   pub default_overrides: FxHashMap<String, String>,
+  pub next_version: NextVersion,
+  pub layers: WebpackLayers,
+  /// Resolve packages with the Yarn Plug'n'Play manifest of the project.
+  pub pnp: bool,
+  /// Directories packages are resolved from at runtime, `dir` if empty.
+  pub runtime_roots: Vec<String>,
+  pub trace: bool,
+  /// Log the externals counters after each compilation.
+  pub log_stats: bool,
+  pub edge_runtime: EdgeRuntimeOptions,
+  /// Shares resolved packages and externals decisions with the other plugins
+  /// created with the same id, `dir` and configuration, and applied to
+  /// compilers with the same `resolve` options. Only server compilers
+  /// benefit, as the client and edge compilers don't resolve externals.
+  pub shared_context_id: Option<String>,
+  /// Evaluated in order before the built-in externals, the first matching
+  /// rule decides the request.
+  pub rules: Vec<ExternalsRule>,
+  /// Overrides the built-in decision of requests no rule matched.
+  pub fallback: Option<ExternalsFallback>,
 }
 
 #[derive(Debug)]
 #[plugin]
 pub struct NextExternalsPlugin {
-  compiler_type: String,
-  builtin_modules: Arc<Vec<String>>,
+  compiler_type: CompilerType,
+  builtin_modules: Arc<BuiltinModules>,
   external_handler: Arc<ExternalHandler>,
+  externals_trace: Option<Arc<ExternalsTrace>>,
+  log_stats: bool,
+  edge_runtime: Arc<EdgeRuntimeOptions>,
+  unsupported_edge_modules: Arc<UnsupportedEdgeModules>,
+  externals_rules: Arc<ExternalsRules>,
+  fallback: Option<Arc<ExternalsFallback>>,
 }
 
 impl NextExternalsPlugin {
@@ -110,25 +271,326 @@ impl NextExternalsPlugin {
       config,
       builtin_modules,
       opt_out_bundling_package_regex,
+      default_server_external_packages,
       final_transpile_packages,
       dir,
       default_overrides,
+      next_version,
+      layers,
+      pnp,
+      runtime_roots,
+      trace,
+      log_stats,
+      edge_runtime,
+      shared_context_id,
+      rules,
+      fallback,
     } = options;
 
-    let external_handler = ExternalHandler::new(
+    let mut external_handler = ExternalHandler::new(
       config.clone(),
       opt_out_bundling_package_regex,
+      default_server_external_packages,
       final_transpile_packages,
       dir,
       default_overrides,
-    );
+    )
+    .with_next_version(next_version)
+    .with_layers(layers)
+    .with_pnp(pnp)
+    .with_runtime_roots(runtime_roots);
+    if let Some(id) = &shared_context_id {
+      external_handler = external_handler.with_shared_context(id);
+    }
 
     Self::new_inner(
       compiler_type,
-      Arc::new(builtin_modules),
+      Arc::new(BuiltinModules::new(builtin_modules)),
       Arc::new(external_handler),
+      trace.then(Default::default),
+      log_stats,
+      Arc::new(edge_runtime),
+      Default::default(),
+      Arc::new(ExternalsRules::new(&rules, compiler_type)),
+      fallback.map(Arc::new),
+    )
+  }
+
+  /// Returns where the externals decisions are persisted, if the persistent
+  /// cache is enabled. Only the server compiler makes such decisions.
+  fn persistent_decisions(&self, options: &CompilerOptions) -> Option<PersistentDecisions> {
+    if self.compiler_type != CompilerType::Server {
+      return None;
+    }
+    PersistentDecisions::new(
+      options,
+      self.compiler_type,
+      &self.external_handler,
+      self.builtin_modules.as_slice(),
     )
   }
+
+  /// Reports each unsupported Node.js module import once per issuing module,
+  /// along with the import trace back to the entry.
+  fn unsupported_edge_module_diagnostics(&self, compilation: &Compilation) -> Vec<Diagnostic> {
+    if self.unsupported_edge_modules.is_empty() {
+      return vec![];
+    }
+
+    let module_graph = compilation.get_module_graph();
+    let issuers: FxHashMap<(Box<str>, Option<&str>), ModuleIdentifier> = module_graph
+      .modules()
+      .into_iter()
+      .filter_map(|(identifier, module)| {
+        let resource = module.name_for_condition()?;
+        Some((
+          (resource, module.get_layer().map(String::as_str)),
+          identifier,
+        ))
+      })
+      .collect();
+
+    let mut unsupported_modules = self
+      .unsupported_edge_modules
+      .iter()
+      .map(|module| module.clone())
+      .collect::<Vec<_>>();
+    unsupported_modules.sort_unstable();
+
+    unsupported_modules
+      .into_iter()
+      .filter_map(|unsupported| {
+        // The issuer may have been removed from the graph since the request
+        // was recorded.
+        let identifier = *issuers.get(&(
+          unsupported.issuer.as_str().into(),
+          unsupported.issuer_layer.as_deref(),
+        ))?;
+        let message = format!(
+          "A Node.js module is loaded ('{}') which is not supported in the Edge Runtime.\n\
+           Learn More: https://nextjs.org/docs/messages/node-module-in-edge-runtime\n\n\
+           Import trace for requested module:\n{}",
+          unsupported.request,
+          import_trace(&module_graph, identifier, compilation).join("\n")
+        );
+        let diagnostic = if self.edge_runtime.strict_unsupported_modules {
+          Diagnostic::error("NextExternalsPlugin".to_string(), message)
+        } else {
+          Diagnostic::warn("NextExternalsPlugin".to_string(), message)
+        };
+        Some(diagnostic.with_module_identifier(Some(identifier)))
+      })
+      .collect()
+  }
+
+  /// Reports each package loading native addons in edge bundles once, along
+  /// with the import trace of one of its modules, as the Edge Runtime can't
+  /// load them.
+  fn native_addon_edge_diagnostics(&self, compilation: &Compilation) -> Vec<Diagnostic> {
+    if self.compiler_type != CompilerType::EdgeServer {
+      return vec![];
+    }
+
+    let module_graph = compilation.get_module_graph();
+    let mut packages: BTreeMap<String, ModuleIdentifier> = BTreeMap::new();
+    for (identifier, module) in module_graph.modules() {
+      let Some(resource_data) = module
+        .as_normal_module()
+        .map(|module| module.resource_resolved_data())
+      else {
+        continue;
+      };
+      let Some(path) = &resource_data.resource_path else {
+        continue;
+      };
+      let description = resource_data.resource_description.as_ref();
+      if !native_addons::is_native(
+        path.as_str(),
+        description.map(|description| description.json()),
+      ) {
+        continue;
+      }
+      let package = description
+        .and_then(|description| {
+          description
+            .json()
+            .get("name")
+            .and_then(|name| name.as_str())
+            .map(str::to_string)
+        })
+        .unwrap_or_else(|| path.to_string());
+      // Pick the same module in every build.
+      packages
+        .entry(package)
+        .and_modify(|reported| *reported = (*reported).min(identifier))
+        .or_insert(identifier);
+    }
+
+    packages
+      .into_iter()
+      .map(|(package, identifier)| {
+        let message = format!(
+          "The package '{package}' loads native Node.js addons ('.node' files) which are not supported in the Edge Runtime.\n\
+           Import it from the Node.js runtime instead.\n\n\
+           Import trace for requested module:\n{}",
+          import_trace(&module_graph, identifier, compilation).join("\n")
+        );
+        Diagnostic::error("NextExternalsPlugin".to_string(), message)
+          .with_module_identifier(Some(identifier))
+      })
+      .collect()
+  }
+}
+
+/// Follows the issuers of `identifier` back to the entry module.
+fn import_trace(
+  module_graph: &ModuleGraph,
+  identifier: ModuleIdentifier,
+  compilation: &Compilation,
+) -> Vec<String> {
+  let mut trace = vec![];
+  let mut visited = FxHashSet::default();
+  let mut current = module_graph.module_by_identifier(&identifier);
+  while let Some(module) = current {
+    if !visited.insert(module.identifier()) {
+      break;
+    }
+    trace.push(
+      module
+        .readable_identifier(&compilation.options.context)
+        .into_owned(),
+    );
+    current = module_graph.get_issuer(&module.identifier());
+  }
+  trace
+}
+
+#[plugin_hook(CompilerThisCompilation for NextExternalsPlugin)]
+async fn this_compilation(
+  &self,
+  compilation: &mut Compilation,
+  _params: &mut CompilationParams,
+) -> rspack_error::Result<()> {
+  self.external_handler.start_build();
+  if let Some(externals_trace) = &self.externals_trace {
+    externals_trace.clear();
+  }
+  if compilation.is_rebuild {
+    // Installing or linking a package changes which directory it resolves to.
+    self.external_handler.invalidate_transpile_package_dirs(
+      compilation
+        .modified_files
+        .iter()
+        .chain(compilation.removed_files.iter())
+        .map(|file| file.as_ref()),
+    );
+    // Changed modules are rebuilt and record their unsupported requests again.
+    self.unsupported_edge_modules.retain(|unsupported| {
+      let issuer = Path::new(&unsupported.issuer);
+      !compilation
+        .modified_files
+        .iter()
+        .chain(compilation.removed_files.iter())
+        .any(|file| file.as_ref() == issuer)
+    });
+  } else if let Some(persistent_decisions) = self.persistent_decisions(&compilation.options) {
+    // Decisions made by an earlier process, whose files are unchanged.
+    persistent_decisions.restore(&self.external_handler);
+  }
+  Ok(())
+}
+
+#[plugin_hook(CompilationFinishModules for NextExternalsPlugin)]
+async fn finish_modules(&self, compilation: &mut Compilation) -> rspack_error::Result<()> {
+  let stats = self.external_handler.take_decision_cache_stats();
+  let total = stats.hits + stats.misses;
+  if total != 0 {
+    let logger = compilation.get_logger("NextExternalsPlugin");
+    logger.raw(LogType::Cache {
+      label: "externals decision cache",
+      hit: stats.hits.try_into().unwrap_or(u32::MAX),
+      total: total.try_into().unwrap_or(u32::MAX),
+    });
+  }
+
+  // Watch what the `transpilePackages` directories were resolved from, so
+  // they are invalidated when packages are installed or linked.
+  if let Some(transpile_package_dirs) = self.external_handler.transpile_package_dirs() {
+    compilation.file_dependencies.extend(
+      transpile_package_dirs
+        .package_jsons()
+        .iter()
+        .map(|file| Path::new(file).into()),
+    );
+    compilation.missing_dependencies.extend(
+      transpile_package_dirs
+        .missing_package_jsons()
+        .iter()
+        .map(|file| Path::new(file).into()),
+    );
+  }
+
+  if let Some(persistent_decisions) = self.persistent_decisions(&compilation.options) {
+    if let Err(error) = persistent_decisions.save(&self.external_handler) {
+      compilation.push_diagnostic(Diagnostic::warn(
+        "NextExternalsPlugin".to_string(),
+        format!("Failed to persist externals decisions: {error}"),
+      ));
+    }
+  }
+
+  if self.log_stats {
+    log_externals_stats(
+      &compilation.get_logger("NextExternalsPlugin"),
+      &self.external_handler.stats().snapshot(),
+    );
+  }
+
+  let mut diagnostics = self.unsupported_edge_module_diagnostics(compilation);
+  diagnostics.extend(self.native_addon_edge_diagnostics(compilation));
+  compilation.extend_diagnostics(diagnostics);
+  Ok(())
+}
+
+fn log_externals_stats(logger: &impl Logger, stats: &ExternalsStatsSnapshot) {
+  logger.log(format!(
+    "externals: {} calls in {:.1?} (p99 {:.1?}), {} resolver calls, {} base resolve mismatches, {} fallback calls",
+    stats.calls,
+    stats.total_time,
+    stats.p99_time,
+    stats.resolver_calls,
+    stats.base_resolve_mismatches,
+    stats.fallback_calls
+  ));
+  let mut calls_per_layer = stats.calls_per_layer.iter().collect::<Vec<_>>();
+  calls_per_layer.sort_unstable();
+  for (layer, calls) in calls_per_layer {
+    logger.log(format!(
+      "externals layer {}: {calls} calls",
+      layer.as_deref().unwrap_or("(none)")
+    ));
+  }
+  let mut decisions_per_rule = stats.decisions_per_rule.iter().collect::<Vec<_>>();
+  decisions_per_rule.sort_unstable();
+  for (rule, decisions) in decisions_per_rule {
+    logger.log(format!("externals rule {rule}: {decisions} decisions"));
+  }
+}
+
+#[plugin_hook(CompilationProcessAssets for NextExternalsPlugin, stage = Compilation::PROCESS_ASSETS_STAGE_REPORT)]
+async fn process_assets(&self, compilation: &mut Compilation) -> rspack_error::Result<()> {
+  let Some(externals_trace) = &self.externals_trace else {
+    return Ok(());
+  };
+  let trace = serde_json::to_string_pretty(&externals_trace.entries()).to_rspack_result()?;
+  compilation.emit_asset(
+    format!("next-externals-trace-{}.json", self.compiler_type),
+    CompilationAsset::new(
+      Some(RawStringSource::from(trace).boxed()),
+      AssetInfo::default(),
+    ),
+  );
+  Ok(())
 }
 
 impl Plugin for NextExternalsPlugin {
@@ -141,8 +603,35 @@ impl Plugin for NextExternalsPlugin {
     ctx: PluginContext<&mut ApplyContext>,
     options: &CompilerOptions,
   ) -> rspack_error::Result<()> {
-    let is_client = self.compiler_type == "client";
-    let is_edge_server = self.compiler_type == "edge-server";
+    self
+      .external_handler
+      .set_compiler_resolve_options(&options.resolve);
+    register_externals_stats(
+      options
+        .name
+        .as_deref()
+        .unwrap_or(self.compiler_type.as_str()),
+      self.external_handler.stats(),
+    );
+
+    ctx
+      .context
+      .compiler_hooks
+      .this_compilation
+      .tap(this_compilation::new(self));
+    ctx
+      .context
+      .compilation_hooks
+      .finish_modules
+      .tap(finish_modules::new(self));
+    ctx
+      .context
+      .compilation_hooks
+      .process_assets
+      .tap(process_assets::new(self));
+
+    let is_client = self.compiler_type == CompilerType::Client;
+    let is_edge_server = self.compiler_type == CompilerType::EdgeServer;
 
     let external_type = if is_client || is_edge_server {
       "assign".to_string()
@@ -151,110 +640,224 @@ impl Plugin for NextExternalsPlugin {
     };
 
     let builtin_modules = self.builtin_modules.clone();
-    let externals = if is_client || is_edge_server {
+    let edge_runtime = self.edge_runtime.clone();
+    let unsupported_edge_modules = self.unsupported_edge_modules.clone();
+    let stats = self.external_handler.stats().clone();
+    let externals_trace = self.externals_trace.clone();
+    let traced = |item: ExternalItem, source: TraceSource| match &externals_trace {
+      Some(externals_trace) => externals_trace.wrap(item, source),
+      None => item,
+    };
+    let user_rules = (!self.externals_rules.is_empty()).then(|| {
+      traced(
+        self.externals_rules.clone().external_item(stats.clone()),
+        TraceSource::UserRule,
+      )
+    });
+    let mut externals = if is_client || is_edge_server {
       if is_edge_server {
         vec![
           ExternalItem::String("next".to_string()),
-          ExternalItem::Object(FxHashMap::from_iter([
-            (
-              "@builder.io/partytown".to_string(),
-              ExternalItemValue::String("{}".to_string()),
-            ),
-            (
-              "next/dist/compiled/etag".to_string(),
-              ExternalItemValue::String("{}".to_string()),
-            ),
-          ])),
-          get_edge_polyfilled_modules(),
-          ExternalItem::Fn(Box::new(move |ctx| {
-            let builtin_modules = builtin_modules.clone();
-            Box::pin(
-              async move { handle_webpack_external_for_edge_runtime(ctx, builtin_modules).await },
-            )
-          })),
+          ExternalItem::Object(
+            self
+              .edge_runtime
+              .stubs
+              .iter()
+              .map(|(request, stub)| (request.clone(), ExternalItemValue::String(stub.clone())))
+              .collect(),
+          ),
+          get_edge_polyfilled_modules(&self.edge_runtime.polyfilled_modules),
+          traced(
+            ExternalItem::Fn(Box::new(move |ctx| {
+              let builtin_modules = builtin_modules.clone();
+              let edge_runtime = edge_runtime.clone();
+              let unsupported_edge_modules = unsupported_edge_modules.clone();
+              let stats = stats.clone();
+              Box::pin(async move {
+                handle_webpack_external_for_edge_runtime(
+                  ctx,
+                  builtin_modules,
+                  edge_runtime,
+                  unsupported_edge_modules,
+                  stats,
+                )
+                .await
+              })
+            })),
+            TraceSource::EdgeRuntime,
+          ),
         ]
       } else {
         vec![ExternalItem::String("next".to_string())]
       }
     } else {
       let external_handler = self.external_handler.clone();
-      self
-        .builtin_modules
-        .iter()
-        .map(|module| ExternalItem::String(module.to_string()))
-        .chain([ExternalItem::Fn(Box::new(move |ctx| {
-          let external_handler = external_handler.clone();
-          let result = Box::pin(async move {
-            let external_result = external_handler
-              .handle_externals(
-                ctx.context,
-                ctx.request,
-                &ctx.dependency_type,
-                ctx.context_info.issuer_layer.as_deref(),
-                Arc::new(move |options: Option<ResolveOptionsWithDependencyType>| {
-                  let first = ctx.resolve_options_with_dependency_type.clone();
-                  let second = options.unwrap_or(ResolveOptionsWithDependencyType {
-                    resolve_options: None,
-                    resolve_to_context: false,
-                    dependency_category: DependencyCategory::Unknown,
-                  });
-
-                  let merged_resolve_options = match second.resolve_options.as_ref() {
-                    Some(second_resolve_options) => match first.resolve_options.as_ref() {
-                      Some(first_resolve_options) => Some(Box::new(
-                        first_resolve_options
-                          .clone()
-                          .merge(*second_resolve_options.clone()),
-                      )),
-                      None => Some(second_resolve_options.clone()),
-                    },
-                    None => first.resolve_options.clone(),
-                  };
-                  let merged_options = ResolveOptionsWithDependencyType {
-                    resolve_options: merged_resolve_options,
-                    resolve_to_context: first.resolve_to_context,
-                    dependency_category: first.dependency_category,
-                  };
-                  let resolver = ctx.resolver_factory.get(merged_options);
-
-                  Box::new(move |resolve_context: String, request_to_resolve: String| {
-                    let resolver = resolver.clone();
-                    Box::pin(async move {
-                      let resolve_result = resolver
-                        .resolve(Path::new(&resolve_context), &request_to_resolve)
-                        .await
-                        .to_rspack_result()?;
-                      Ok(match resolve_result {
-                        ResolveResult::Resource(resource) => {
-                          let is_esm = if resource.path.as_str().ends_with(".js") {
-                            resource.description_data.as_ref().is_some_and(|description_data| {
+      let fallback = self.fallback.clone();
+      let externals_trace = externals_trace.clone();
+      let stats = stats.clone();
+      [traced(
+        self.builtin_modules.clone().external_item(),
+        TraceSource::Builtin,
+      )]
+      .into_iter()
+      .chain([ExternalItem::Fn(Box::new(move |ctx| {
+        let external_handler = external_handler.clone();
+        let fallback = fallback.clone();
+        let externals_trace = externals_trace.clone();
+        let stats = stats.clone();
+        let result = Box::pin(async move {
+          let issuer = ctx.context_info.issuer.clone();
+          let context = ctx.context.clone();
+          let request = ctx.request.clone();
+          let dependency_type = ctx.dependency_type.clone();
+          let layer = ctx.context_info.issuer_layer.clone();
+          let decision = external_handler
+            .decide(
+              ctx.context,
+              ctx.request,
+              &ctx.dependency_type,
+              ctx.context_info.issuer_layer.as_deref(),
+              Arc::new(move |options: Option<ResolveOptionsWithDependencyType>| {
+                let first = ctx.resolve_options_with_dependency_type.clone();
+                let second = options.unwrap_or(ResolveOptionsWithDependencyType {
+                  resolve_options: None,
+                  resolve_to_context: false,
+                  dependency_category: DependencyCategory::Unknown,
+                });
+
+                let merged_resolve_options = match second.resolve_options.as_ref() {
+                  Some(second_resolve_options) => match first.resolve_options.as_ref() {
+                    Some(first_resolve_options) => Some(Box::new(
+                      first_resolve_options
+                        .clone()
+                        .merge(*second_resolve_options.clone()),
+                    )),
+                    None => Some(second_resolve_options.clone()),
+                  },
+                  None => first.resolve_options.clone(),
+                };
+                let merged_options = ResolveOptionsWithDependencyType {
+                  resolve_options: merged_resolve_options,
+                  resolve_to_context: first.resolve_to_context,
+                  dependency_category: first.dependency_category,
+                };
+                let resolver = ctx.resolver_factory.get(merged_options);
+
+                Box::new(move |resolve_context: String, request_to_resolve: String| {
+                  let resolver = resolver.clone();
+                  Box::pin(async move {
+                    let resolve_result = resolver
+                      .resolve(Path::new(&resolve_context), &request_to_resolve)
+                      .await
+                      .to_rspack_result()?;
+                    Ok(match resolve_result {
+                      ResolveResult::Resource(resource) => {
+                        let is_esm = if resource.path.as_str().ends_with(".js") {
+                          resource
+                            .description_data
+                            .as_ref()
+                            .is_some_and(|description_data| {
                               if let Some(object) = description_data.json().as_object() {
-                                object.get("type").is_some_and(|v| v.as_str() == Some("module"))
+                                object
+                                  .get("type")
+                                  .is_some_and(|v| v.as_str() == Some("module"))
                               } else {
                                 false
                               }
                             })
-                          } else {
-                            resource.path.as_str().ends_with(".mjs")
-                          };
-                          (Some(resource.full_path()), is_esm)
+                        } else {
+                          resource.path.as_str().ends_with(".mjs")
+                        };
+                        let is_native = native_addons::is_native(
+                          resource.path.as_str(),
+                          resource
+                            .description_data
+                            .as_ref()
+                            .map(|description_data| description_data.json()),
+                        );
+                        let package =
+                          resource
+                            .description_data
+                            .as_ref()
+                            .and_then(|description_data| {
+                              PackageIdentity::new(
+                                resource.path.as_str(),
+                                &description_data.path().to_string_lossy(),
+                                description_data.json(),
+                              )
+                            });
+                        ResolvedRequest {
+                          path: Some(resource.full_path()),
+                          is_esm,
+                          is_native,
+                          package,
                         }
-                        ResolveResult::Ignored => (None, false),
-                      })
+                      }
+                      ResolveResult::Ignored => ResolvedRequest::default(),
                     })
                   })
-                }),
-              )
-              .await?;
-            Ok(ExternalItemFnResult {
-              external_type: None,
-              result: external_result.map(ExternalItemValue::String),
-            })
-          });
-          result
-        }))])
-        .collect::<Vec<_>>()
+                })
+              }),
+            )
+            .await
+            .map_err(|error| match error.downcast::<EsmExternalsError>() {
+              Ok(error) => error.with_issuer(&issuer).with_package_json().into(),
+              Err(error) => error,
+            })?;
+          let overridden = match fallback {
+            Some(fallback) => {
+              fallback
+                .override_decision(
+                  &context,
+                  &request,
+                  layer.as_deref(),
+                  decision.external.as_deref(),
+                  &stats,
+                )
+                .await?
+            }
+            None => None,
+          };
+          if let Some(externals_trace) = externals_trace {
+            // Bundled requests are recorded too, along with the rule that
+            // bundled them.
+            externals_trace.record(ExternalsTraceEntry {
+              source: if overridden.is_some() {
+                TraceSource::Fallback
+              } else {
+                TraceSource::Handler
+              },
+              external: match &overridden {
+                Some(overridden) => external_json(Some(overridden)),
+                None => decision.external.as_deref().map(serde_json::Value::from),
+              },
+              request,
+              context,
+              dependency_type,
+              layer,
+              rule: Some(decision.rule),
+              resolved: decision.resolved,
+            });
+          }
+          let result = overridden.or(decision.external.map(ExternalItemValue::String));
+          Ok(ExternalItemFnResult {
+            external_type: None,
+            result,
+          })
+        });
+        result
+      }))])
+      .collect::<Vec<_>>()
     };
+    if is_client || is_edge_server {
+      externals.extend(self.fallback.clone().map(|fallback| {
+        traced(
+          fallback.external_item(self.external_handler.stats().clone()),
+          TraceSource::Fallback,
+        )
+      }));
+    }
+    let externals = user_rules.into_iter().chain(externals).collect();
 
     ExternalsPlugin::new(external_type, externals)
       .apply(PluginContext::with_context(ctx.context), options)?;
@@ -262,3 +865,6 @@ impl Plugin for NextExternalsPlugin {
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1293 @@
+//! End-to-end tests that run `NextExternalsPlugin` inside a real rspack
+//! compilation on fixture projects written to a temporary directory.
+
+use std::{fs, path::Path};
+
+use rspack_core::{
+  incremental::IncrementalOptions, BoxPlugin, CacheOptions, ChunkLoading, ChunkLoadingType,
+  CleanOptions, CompilerOptions, CrossOriginLoading, DynamicImportMode, EntryOptions, Environment,
+  ExperimentCacheOptions, Experiments, JavascriptParserOptions, JavascriptParserOrder, Mode,
+  ModuleOptions, Optimization, OutputOptions, ParserOptions, ParserOptionsMap, PathInfo, PluginExt,
+  PublicPath, Resolve, RspackFuture, StatsOptions, WasmLoading,
+};
+use rspack_ids::{NamedChunkIdsPlugin, NamedModuleIdsPlugin};
+use rspack_plugin_entry::EntryPlugin;
+use rspack_plugin_javascript::{InferAsyncModulesPlugin, JsPlugin};
+use rspack_plugin_runtime::{
+  enable_chunk_loading_plugin, CommonJsChunkFormatPlugin, RuntimePlugin,
+};
+use rspack_regex::RspackRegex;
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+use super::*;
+use crate::{
+  config_shared::{EsmExternalsConfig, ExperimentalConfig, NextConfigComplete},
+  externals_rules::{RequestMatcher, RuleExternal},
+};
+
+const BUILTIN_MODULES: &[&str] = &[
+  "fs",
+  "fs/promises",
+  "path",
+  "path/posix",
+  "util",
+  "util/types",
+];
+
+/// A project on disk. The root is canonicalized, because the resolver returns
+/// real paths and the temporary directory may itself be behind a symlink.
+struct Project {
+  _dir: TempDir,
+  root: String,
+}
+
+impl Project {
+  fn new() -> Self {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    Self {
+      _dir: dir,
+      root: root.to_string_lossy().replace('\\', "/"),
+    }
+  }
+
+  fn path(&self, path: &str) -> String {
+    format!("{}/{path}", self.root)
+  }
+
+  fn file(&self, path: &str, contents: &str) -> &Self {
+    let path = self.path(path);
+    fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+    self
+  }
+
+  fn package(&self, dir: &str, package_json: Value, files: &[(&str, &str)]) -> &Self {
+    self.file(&format!("{dir}/package.json"), &package_json.to_string());
+    for (path, contents) in files {
+      self.file(&format!("{dir}/{path}"), contents);
+    }
+    self
+  }
+
+  #[cfg(unix)]
+  fn symlink(&self, target: &str, link: &str) -> &Self {
+    let link = self.path(link);
+    fs::create_dir_all(Path::new(&link).parent().unwrap()).unwrap();
+    std::os::unix::fs::symlink(self.path(target), link).unwrap();
+    self
+  }
+
+  fn plugin_options(&self, transpile_packages: &[&str]) -> NextExternalsPluginOptions {
+    NextExternalsPluginOptions {
+      compiler_type: CompilerType::Server,
+      // `experimental.esmExternals` defaults to `true` in Next.js.
+      config: NextConfigComplete {
+        experimental: ExperimentalConfig {
+          esm_externals: EsmExternalsConfig::Strict,
+          ..Default::default()
+        },
+        ..Default::default()
+      },
+      builtin_modules: BUILTIN_MODULES.iter().map(|m| m.to_string()).collect(),
+      opt_out_bundling_package_regex: None,
+      default_server_external_packages: vec!["opt-out".to_string()],
+      final_transpile_packages: transpile_packages.iter().map(|p| p.to_string()).collect(),
+      dir: self.root.clone(),
+      default_overrides: FxHashMap::default(),
+      next_version: NextVersion::default(),
+      layers: WebpackLayers::default(),
+      pnp: false,
+      runtime_roots: vec![],
+      trace: false,
+      log_stats: false,
+      edge_runtime: EdgeRuntimeOptions::default(),
+      shared_context_id: None,
+      rules: vec![],
+      fallback: None,
+    }
+  }
+
+  /// Bundles `pages/index.js` and returns the emitted `main.js`.
+  async fn build(&self, options: NextExternalsPluginOptions) -> String {
+    let compiler = self.compile(options, None).await;
+
+    let errors = compiler
+      .compilation
+      .get_errors()
+      .map(|error| error.render_report(false).unwrap())
+      .collect::<Vec<_>>();
+    assert!(
+      errors.is_empty(),
+      "compilation failed:\n{}",
+      errors.join("\n")
+    );
+
+    fs::read_to_string(self.path(".next/server/main.js")).unwrap()
+  }
+
+  /// Bundles `pages/index.js` into the given layer, leaving the diagnostics to
+  /// the caller.
+  async fn compile(
+    &self,
+    options: NextExternalsPluginOptions,
+    layer: Option<&str>,
+  ) -> rspack_core::Compiler {
+    let output_path = self.path(".next/server");
+    let pnp = options.pnp;
+    let mut plugins: Vec<BoxPlugin> = vec![
+      EntryPlugin::new(
+        self.root.clone().into(),
+        "./pages/index.js".to_string(),
+        EntryOptions {
+          name: Some("main".to_string()),
+          layer: layer.map(str::to_string),
+          ..Default::default()
+        },
+      )
+      .boxed(),
+      JsPlugin::default().boxed(),
+      RuntimePlugin::default().boxed(),
+      InferAsyncModulesPlugin::default().boxed(),
+      CommonJsChunkFormatPlugin::default().boxed(),
+      NamedModuleIdsPlugin::default().boxed(),
+      NamedChunkIdsPlugin::new(None, None).boxed(),
+      NextExternalsPlugin::new(options).boxed(),
+    ];
+    enable_chunk_loading_plugin(ChunkLoadingType::Require, &mut plugins);
+
+    let mut compiler_options = compiler_options(&self.root, &output_path);
+    compiler_options.experiments.layers = layer.is_some();
+    // Reads the files of zip-backed packages too.
+    compiler_options.resolve.pnp = Some(pnp);
+    let mut compiler = rspack_core::Compiler::new(
+      self.root.clone(),
+      compiler_options,
+      plugins,
+      vec![],
+      None,
+      None,
+      None,
+      None,
+      None,
+      None,
+    );
+    compiler.build().await.unwrap();
+    compiler
+  }
+}
+
+fn compiler_options(context: &str, output_path: &str) -> CompilerOptions {
+  let mut parser = ParserOptionsMap::default();
+  parser.insert(
+    "javascript".to_string(),
+    ParserOptions::Javascript(JavascriptParserOptions {
+      dynamic_import_mode: Some(DynamicImportMode::Lazy),
+      dynamic_import_preload: Some(JavascriptParserOrder::Disable),
+      dynamic_import_prefetch: Some(JavascriptParserOrder::Disable),
+      wrapped_context_reg_exp: Some(RspackRegex::new(".*").unwrap()),
+      worker: Some(vec!["...".to_string()]),
+      ..Default::default()
+    }),
+  );
+
+  CompilerOptions {
+    name: Some("server".to_string()),
+    context: context.into(),
+    output: OutputOptions {
+      path: output_path.into(),
+      pathinfo: PathInfo::Bool(false),
+      clean: CleanOptions::CleanAll(false),
+      public_path: PublicPath::Auto,
+      asset_module_filename: "[hash][ext][query]".to_string().into(),
+      wasm_loading: WasmLoading::Disable,
+      webassembly_module_filename: "[hash].module.wasm".to_string().into(),
+      unique_name: "server".to_string(),
+      chunk_loading: ChunkLoading::Enable(ChunkLoadingType::Require),
+      chunk_loading_global: "webpackChunkserver".to_string(),
+      chunk_load_timeout: 120_000,
+      charset: false,
+      filename: "[name].js".to_string().into(),
+      chunk_filename: "[id].js".to_string().into(),
+      cross_origin_loading: CrossOriginLoading::Disable,
+      css_filename: "[name].css".to_string().into(),
+      css_chunk_filename: "[id].css".to_string().into(),
+      hot_update_main_filename: "[runtime].[fullhash].hot-update.json".to_string().into(),
+      hot_update_chunk_filename: "[id].[fullhash].hot-update.js".to_string().into(),
+      hot_update_global: "webpackHotUpdateserver".to_string(),
+      library: None,
+      enabled_library_types: None,
+      strict_module_error_handling: false,
+      global_object: "globalThis".to_string(),
+      import_function_name: "import".to_string(),
+      import_meta_name: "import.meta".to_string(),
+      iife: false,
+      module: false,
+      trusted_types: None,
+      source_map_filename: "[file].map[query]".to_string().into(),
+      hash_function: "xxhash64".into(),
+      hash_digest: "hex".into(),
+      hash_digest_length: 16,
+      hash_salt: None.into(),
+      async_chunks: true,
+      worker_chunk_loading: ChunkLoading::Disable,
+      worker_wasm_loading: WasmLoading::Disable,
+      worker_public_path: String::new(),
+      script_type: "false".to_string(),
+      environment: Environment {
+        r#const: Some(true),
+        arrow_function: Some(true),
+        node_prefix_for_core_modules: Some(true),
+        async_function: Some(true),
+        big_int_literal: Some(true),
+        destructuring: Some(true),
+        document: Some(false),
+        dynamic_import: Some(true),
+        for_of: Some(true),
+        global_this: Some(true),
+        module: Some(false),
+        optional_chaining: Some(true),
+        template_literal: Some(true),
+        dynamic_import_in_worker: Some(false),
+      },
+      compare_before_emit: false,
+    },
+    mode: Mode::Development,
+    resolve: Resolve {
+      extensions: Some(vec![".js".to_string(), ".json".to_string()]),
+      condition_names: Some(vec!["node".to_string(), "require".to_string()]),
+      ..Default::default()
+    },
+    resolve_loader: Resolve::default(),
+    module: ModuleOptions {
+      rules: vec![],
+      parser: Some(parser),
+      generator: None,
+      no_parse: None,
+    },
+    stats: StatsOptions { colors: false },
+    cache: CacheOptions::Disabled,
+    experiments: Experiments {
+      layers: false,
+      incremental: IncrementalOptions::empty_passes(),
+      parallel_code_splitting: false,
+      top_level_await: true,
+      rspack_future: RspackFuture {},
+      cache: ExperimentCacheOptions::Disabled,
+      inline_const: false,
+      inline_enum: false,
+      type_reexports_presence: false,
+    },
+    node: None,
+    optimization: Optimization {
+      remove_available_modules: false,
+      side_effects: Default::default(),
+      provided_exports: false,
+      used_exports: Default::default(),
+      inner_graph: false,
+      mangle_exports: Default::default(),
+      concatenate_modules: false,
+      avoid_entry_iife: false,
+      real_content_hash: false,
+    },
+    profile: false,
+    amd: None,
+    bail: false,
+    __references: Default::default(),
+  }
+}
+
+fn is_bundled(bundle: &str, module_path: &str) -> bool {
+  bundle.contains(&format!("\"./{module_path}\""))
+}
+
+fn is_required_external(bundle: &str, request: &str) -> bool {
+  bundle.contains(&format!("module.exports = require(\"{request}\")"))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hoisted_layout() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "const fs = require('fs');\nconst pkg = require('cjs-pkg');\nmodule.exports = [fs, pkg];\n",
+    )
+    .package(
+      "node_modules/cjs-pkg",
+      json!({ "name": "cjs-pkg", "main": "index.js" }),
+      &[("index.js", "module.exports = 'cjs-pkg';")],
+    );
+
+  let bundle = project.build(project.plugin_options(&[])).await;
+
+  assert!(is_required_external(&bundle, "fs"), "{bundle}");
+  assert!(is_required_external(&bundle, "cjs-pkg"), "{bundle}");
+  assert!(
+    !is_bundled(&bundle, "node_modules/cjs-pkg/index.js"),
+    "{bundle}"
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn duplicated_package_versions() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('dup'), require('parent')];\n",
+    )
+    .package(
+      "node_modules/dup",
+      json!({ "name": "dup", "version": "1.0.0" }),
+      &[("index.js", "module.exports = 'dup@1';")],
+    )
+    .package(
+      "node_modules/parent",
+      json!({ "name": "parent", "main": "index.js" }),
+      &[("index.js", "module.exports = require('dup');")],
+    )
+    .package(
+      "node_modules/parent/node_modules/dup",
+      json!({ "name": "dup", "version": "2.0.0" }),
+      &[("index.js", "module.exports = 'dup@2';")],
+    );
+
+  // `parent` is transpiled, so its own `dup` request goes through the plugin.
+  let bundle = project.build(project.plugin_options(&["parent"])).await;
+
+  assert!(is_required_external(&bundle, "dup"), "{bundle}");
+  assert!(
+    is_bundled(&bundle, "node_modules/parent/index.js"),
+    "{bundle}"
+  );
+  // The nested version is not what `require('dup')` resolves to from the
+  // project root at runtime, so it has to be bundled.
+  assert!(
+    is_bundled(&bundle, "node_modules/parent/node_modules/dup/index.js"),
+    "{bundle}"
+  );
+  assert!(bundle.contains("dup@2"), "{bundle}");
+  assert!(!bundle.contains("dup@1"), "{bundle}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn esm_only_package() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "import esm from 'esm-only';\nexport default esm;\n",
+    )
+    .package(
+      "node_modules/esm-only",
+      json!({ "name": "esm-only", "type": "module", "exports": { "import": "./index.js" } }),
+      &[("index.js", "export default 'esm-only';")],
+    );
+
+  let bundle = project.build(project.plugin_options(&[])).await;
+
+  assert!(bundle.contains("import(\"esm-only\")"), "{bundle}");
+  assert!(
+    !is_bundled(&bundle, "node_modules/esm-only/index.js"),
+    "{bundle}"
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transpile_packages() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('ui'), require('cjs-pkg')];\n",
+    )
+    .package(
+      "node_modules/ui",
+      json!({ "name": "ui", "main": "index.js" }),
+      &[
+        ("index.js", "module.exports = require('./button');"),
+        ("button.js", "module.exports = 'button';"),
+      ],
+    )
+    .package(
+      "node_modules/cjs-pkg",
+      json!({ "name": "cjs-pkg", "main": "index.js" }),
+      &[("index.js", "module.exports = 'cjs-pkg';")],
+    );
+
+  let bundle = project.build(project.plugin_options(&["ui"])).await;
+
+  assert!(is_bundled(&bundle, "node_modules/ui/index.js"), "{bundle}");
+  assert!(is_bundled(&bundle, "node_modules/ui/button.js"), "{bundle}");
+  assert!(is_required_external(&bundle, "cjs-pkg"), "{bundle}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn user_rules() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('@company/ui'), require('@company/db'), require('./local'), require('fs')];\n",
+    )
+    .file("local.js", "module.exports = 'local';")
+    .package(
+      "node_modules/@company/ui",
+      json!({ "name": "@company/ui", "main": "index.js" }),
+      &[("index.js", "module.exports = 'ui';")],
+    )
+    .package(
+      "node_modules/@company/db",
+      json!({ "name": "@company/db", "main": "index.js" }),
+      &[("index.js", "module.exports = 'db';")],
+    );
+
+  let mut options = project.plugin_options(&[]);
+  options.rules = vec![
+    ExternalsRule {
+      request: RequestMatcher::glob("@company/ui"),
+      issuer_layer: None,
+      compiler_types: None,
+      dependency_types: None,
+      external: RuleExternal::Bundle,
+    },
+    ExternalsRule {
+      request: RequestMatcher::glob("@company/*"),
+      issuer_layer: None,
+      compiler_types: Some(vec![CompilerType::Server]),
+      dependency_types: Some(vec!["commonjs".to_string()]),
+      external: RuleExternal::Custom("commonjs @company/db/server".to_string()),
+    },
+    ExternalsRule {
+      request: RequestMatcher::glob("./local"),
+      issuer_layer: None,
+      compiler_types: None,
+      dependency_types: None,
+      external: RuleExternal::Commonjs,
+    },
+    // Rules of other compilers are ignored.
+    ExternalsRule {
+      request: RequestMatcher::glob("fs"),
+      issuer_layer: None,
+      compiler_types: Some(vec![CompilerType::EdgeServer]),
+      dependency_types: None,
+      external: RuleExternal::Bundle,
+    },
+  ];
+  let bundle = project.build(options).await;
+
+  // Bundled although node_modules packages are external by default.
+  assert!(
+    is_bundled(&bundle, "node_modules/@company/ui/index.js"),
+    "{bundle}"
+  );
+  assert!(
+    is_required_external(&bundle, "@company/db/server"),
+    "{bundle}"
+  );
+  assert!(is_required_external(&bundle, "./local"), "{bundle}");
+  assert!(is_required_external(&bundle, "fs"), "{bundle}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fallback_after_rules() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('@company/ui'), require('@company/db'), require('cjs-pkg')];\n",
+    )
+    .package(
+      "node_modules/@company/ui",
+      json!({ "name": "@company/ui", "main": "index.js" }),
+      &[("index.js", "module.exports = 'ui';")],
+    )
+    .package(
+      "node_modules/@company/db",
+      json!({ "name": "@company/db", "main": "index.js" }),
+      &[("index.js", "module.exports = 'db';")],
+    )
+    .package(
+      "node_modules/cjs-pkg",
+      json!({ "name": "cjs-pkg", "main": "index.js" }),
+      &[("index.js", "module.exports = 'cjs-pkg';")],
+    );
+
+  let requests = Arc::new(std::sync::Mutex::new(vec![]));
+  let recorded = requests.clone();
+  let mut options = project.plugin_options(&[]);
+  options.rules = vec![ExternalsRule {
+    request: RequestMatcher::glob("@company/ui"),
+    issuer_layer: None,
+    compiler_types: None,
+    dependency_types: None,
+    external: RuleExternal::Bundle,
+  }];
+  options.fallback = Some(ExternalsFallback::new(
+    vec![RequestMatcher::glob("@company/*")],
+    Arc::new(move |request| {
+      recorded
+        .lock()
+        .unwrap()
+        .push((request.request, request.decision));
+      Box::pin(async { Ok(Some(RuleExternal::Bundle)) })
+    }),
+  ));
+  let bundle = project.build(options).await;
+
+  // Requests decided by a rule or not matching the prefilter stay in Rust.
+  assert_eq!(
+    *requests.lock().unwrap(),
+    [(
+      "@company/db".to_string(),
+      Some("commonjs @company/db".to_string())
+    )]
+  );
+  assert!(
+    is_bundled(&bundle, "node_modules/@company/ui/index.js"),
+    "{bundle}"
+  );
+  assert!(
+    is_bundled(&bundle, "node_modules/@company/db/index.js"),
+    "{bundle}"
+  );
+  assert!(is_required_external(&bundle, "cjs-pkg"), "{bundle}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn trace_records_final_outcome() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('@company/ui'), require('@company/db'), require('cjs-pkg'), require('fs'), require('./local')];\n",
+    )
+    .file("pages/local.js", "module.exports = 'local';")
+    .package(
+      "node_modules/@company/ui",
+      json!({ "name": "@company/ui", "main": "index.js" }),
+      &[("index.js", "module.exports = 'ui';")],
+    )
+    .package(
+      "node_modules/@company/db",
+      json!({ "name": "@company/db", "main": "index.js" }),
+      &[("index.js", "module.exports = 'db';")],
+    )
+    .package(
+      "node_modules/cjs-pkg",
+      json!({ "name": "cjs-pkg", "main": "index.js" }),
+      &[("index.js", "module.exports = 'cjs-pkg';")],
+    );
+
+  let mut options = project.plugin_options(&[]);
+  options.trace = true;
+  options.rules = vec![ExternalsRule {
+    request: RequestMatcher::glob("@company/ui"),
+    issuer_layer: None,
+    compiler_types: None,
+    dependency_types: None,
+    external: RuleExternal::Bundle,
+  }];
+  options.fallback = Some(ExternalsFallback::new(
+    vec![RequestMatcher::glob("@company/*")],
+    Arc::new(|_| Box::pin(async { Ok(Some(RuleExternal::Bundle)) })),
+  ));
+  project.build(options).await;
+
+  let trace: Vec<Value> = serde_json::from_str(
+    &fs::read_to_string(project.path(".next/server/next-externals-trace-server.json")).unwrap(),
+  )
+  .unwrap();
+  let outcome = |request: &str| {
+    let entry = trace
+      .iter()
+      .find(|entry| entry["request"] == request)
+      .unwrap_or_else(|| panic!("{request} is not traced: {trace:#?}"));
+    (
+      entry["source"].clone(),
+      entry["rule"].clone(),
+      entry["external"].clone(),
+    )
+  };
+  assert_eq!(
+    outcome("@company/ui"),
+    (json!("user-rule"), Value::Null, Value::Null)
+  );
+  // The fallback bundles what the handler made external.
+  assert_eq!(
+    outcome("@company/db"),
+    (
+      json!("fallback"),
+      json!("node-modules-external"),
+      Value::Null
+    )
+  );
+  assert_eq!(
+    outcome("cjs-pkg"),
+    (
+      json!("handler"),
+      json!("node-modules-external"),
+      json!("commonjs cjs-pkg")
+    )
+  );
+  assert_eq!(outcome("fs"), (json!("builtin"), Value::Null, json!(true)));
+  assert_eq!(
+    outcome("./local"),
+    (json!("handler"), json!("local-file"), Value::Null)
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn builtin_modules_with_node_scheme() {
+  let project = Project::new();
+  project.file(
+    "pages/index.js",
+    "module.exports = [require('node:fs'), require('fs/promises'), require('node:path/posix'), require('node:test')];\n",
+  );
+
+  let bundle = project.build(project.plugin_options(&[])).await;
+
+  for request in ["node:fs", "fs/promises", "node:path/posix", "node:test"] {
+    assert!(
+      is_required_external(&bundle, request),
+      "{request}\n{bundle}"
+    );
+  }
+}
+
+/// The pnpm layout: top-level packages are symlinks into the `.pnpm` store,
+/// and transitive dependencies are only reachable from their dependents.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn pnpm_symlinked_store() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('cjs-pkg'), require('ui'), require('styled-jsx/style')];\n",
+    )
+    .package(
+      "node_modules/.pnpm/cjs-pkg@1.0.0/node_modules/cjs-pkg",
+      json!({ "name": "cjs-pkg", "main": "index.js" }),
+      &[("index.js", "module.exports = 'cjs-pkg';")],
+    )
+    .symlink(
+      "node_modules/.pnpm/cjs-pkg@1.0.0/node_modules/cjs-pkg",
+      "node_modules/cjs-pkg",
+    )
+    .package(
+      "node_modules/.pnpm/helper@1.0.0/node_modules/helper",
+      json!({ "name": "helper", "main": "index.js" }),
+      &[("index.js", "module.exports = 'helper';")],
+    )
+    .package(
+      "node_modules/.pnpm/ui@1.0.0/node_modules/ui",
+      json!({ "name": "ui", "main": "index.js" }),
+      &[("index.js", "module.exports = require('helper');")],
+    )
+    .symlink(
+      "node_modules/.pnpm/helper@1.0.0/node_modules/helper",
+      "node_modules/.pnpm/ui@1.0.0/node_modules/helper",
+    )
+    .symlink(
+      "node_modules/.pnpm/ui@1.0.0/node_modules/ui",
+      "node_modules/ui",
+    )
+    .package(
+      "node_modules/.pnpm/styled-jsx@5.1.6/node_modules/styled-jsx",
+      json!({ "name": "styled-jsx", "main": "index.js" }),
+      &[("index.js", ""), ("style.js", "module.exports = 'style';")],
+    )
+    .package(
+      "node_modules/.pnpm/next@15.0.0/node_modules/next",
+      json!({ "name": "next", "main": "index.js" }),
+      &[("index.js", "")],
+    )
+    .symlink(
+      "node_modules/.pnpm/styled-jsx@5.1.6/node_modules/styled-jsx",
+      "node_modules/.pnpm/next@15.0.0/node_modules/styled-jsx",
+    )
+    .symlink(
+      "node_modules/.pnpm/next@15.0.0/node_modules/next",
+      "node_modules/next",
+    );
+
+  let mut options = project.plugin_options(&["ui"]);
+  // Next.js resolves `styled-jsx/style` from its own installation.
+  options.default_overrides.insert(
+    "styled-jsx/style".to_string(),
+    project.path("node_modules/.pnpm/styled-jsx@5.1.6/node_modules/styled-jsx/style.js"),
+  );
+  let bundle = project.build(options).await;
+
+  assert!(is_required_external(&bundle, "cjs-pkg"), "{bundle}");
+  assert!(
+    is_required_external(&bundle, "styled-jsx/style"),
+    "{bundle}"
+  );
+  // `helper` can't be required from the project root, so it's bundled.
+  assert!(!is_required_external(&bundle, "helper"), "{bundle}");
+  assert!(
+    is_bundled(
+      &bundle,
+      "node_modules/.pnpm/helper@1.0.0/node_modules/helper/index.js"
+    ),
+    "{bundle}"
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn edge_runtime_options() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('crypto'), require('node:buffer'), require('analytics')];\n",
+    )
+    .package(
+      "node_modules/analytics",
+      json!({ "name": "analytics", "main": "index.js" }),
+      &[("index.js", "module.exports = 'analytics';")],
+    );
+
+  let mut options = project.plugin_options(&[]);
+  options.compiler_type = CompilerType::EdgeServer;
+  options
+    .edge_runtime
+    .polyfilled_modules
+    .push("crypto".to_string());
+  options.edge_runtime.stubs = FxHashMap::from_iter([(
+    "analytics".to_string(),
+    "globalThis.__analytics".to_string(),
+  )]);
+  let bundle = project.build(options).await;
+
+  assert!(is_required_external(&bundle, "node:crypto"), "{bundle}");
+  assert!(is_required_external(&bundle, "node:buffer"), "{bundle}");
+  assert!(bundle.contains("globalThis.__analytics"), "{bundle}");
+  assert!(
+    !is_bundled(&bundle, "node_modules/analytics/index.js"),
+    "{bundle}"
+  );
+}
+
+fn messages<'a>(diagnostics: impl Iterator<Item = &'a rspack_error::Diagnostic>) -> Vec<String> {
+  diagnostics.map(|diagnostic| diagnostic.message()).collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn edge_runtime_unsupported_modules() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('../lib/db'), require('../lib/cache')];\n",
+    )
+    .file(
+      "lib/db.js",
+      "const fs = require('fs');\nconst { readFileSync } = require('fs');\nmodule.exports = [fs, readFileSync];\n",
+    )
+    .file("lib/cache.js", "module.exports = require('./db');\n");
+
+  let mut options = project.plugin_options(&[]);
+  options.compiler_type = CompilerType::EdgeServer;
+  let compiler = project.compile(options, Some("middleware")).await;
+
+  let errors = messages(compiler.compilation.get_errors());
+  assert!(errors.is_empty(), "{errors:#?}");
+  let warnings = messages(compiler.compilation.get_warnings());
+  assert_eq!(warnings.len(), 1, "{warnings:#?}");
+  assert_eq!(
+    warnings[0],
+    "A Node.js module is loaded ('fs') which is not supported in the Edge Runtime.\n\
+     Learn More: https://nextjs.org/docs/messages/node-module-in-edge-runtime\n\n\
+     Import trace for requested module:\n\
+     ./lib/db.js\n\
+     ./pages/index.js"
+  );
+  let bundle = fs::read_to_string(project.path(".next/server/main.js")).unwrap();
+  assert!(
+    bundle.contains("globalThis.__import_unsupported('fs')"),
+    "{bundle}"
+  );
+
+  let mut options = project.plugin_options(&[]);
+  options.compiler_type = CompilerType::EdgeServer;
+  options.edge_runtime.strict_unsupported_modules = true;
+  let compiler = project.compile(options, Some("middleware")).await;
+
+  assert!(compiler.compilation.get_warnings().next().is_none());
+  let errors = messages(compiler.compilation.get_errors());
+  assert_eq!(errors.len(), 1, "{errors:#?}");
+  assert!(errors[0].contains("('fs')"), "{errors:#?}");
+
+  // Outside of middleware and edge API routes, builtins are left alone.
+  let mut options = project.plugin_options(&[]);
+  options.compiler_type = CompilerType::EdgeServer;
+  let compiler = project.compile(options, None).await;
+
+  assert!(compiler.compilation.get_warnings().next().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn edge_runtime_node_scheme() {
+  let project = Project::new();
+  project.file(
+    "pages/index.js",
+    "module.exports = [require('node:fs/promises'), require('util/types'), require('node:util'), require('node:test')];\n",
+  );
+
+  let mut options = project.plugin_options(&[]);
+  options.compiler_type = CompilerType::EdgeServer;
+  let compiler = project.compile(options, Some("middleware")).await;
+
+  let warnings = messages(compiler.compilation.get_warnings());
+  let unsupported = warnings
+    .iter()
+    .map(|warning| warning.lines().next().unwrap())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    unsupported,
+    [
+      "A Node.js module is loaded ('node:fs/promises') which is not supported in the Edge Runtime.",
+      "A Node.js module is loaded ('node:test') which is not supported in the Edge Runtime.",
+      "A Node.js module is loaded ('util/types') which is not supported in the Edge Runtime.",
+    ],
+    "{warnings:#?}"
+  );
+  let bundle = fs::read_to_string(project.path(".next/server/main.js")).unwrap();
+  assert!(is_required_external(&bundle, "node:util"), "{bundle}");
+}
+
+/// A package built by node-gyp, and one whose entry is a prebuilt addon.
+fn native_addon_packages(project: &Project) {
+  project
+    .file(
+      "pages/index.js",
+      "module.exports = [require('gyp-addon'), require('prebuilt'), require('local-addon')];\n",
+    )
+    .package(
+      "node_modules/gyp-addon",
+      json!({ "name": "gyp-addon", "main": "index.js", "gypfile": true }),
+      &[(
+        "index.js",
+        "module.exports = require('bindings')('gyp_addon');",
+      )],
+    )
+    .package(
+      "node_modules/bindings",
+      json!({ "name": "bindings", "main": "bindings.js" }),
+      &[("bindings.js", "module.exports = (name) => require(name);")],
+    )
+    .package(
+      "node_modules/prebuilt",
+      json!({ "name": "prebuilt", "main": "prebuilt.node" }),
+      &[("prebuilt.node", "")],
+    )
+    // Requires its addon by a relative path, without a known loader.
+    .package(
+      "node_modules/local-addon",
+      json!({ "name": "local-addon", "main": "index.js" }),
+      &[
+        (
+          "index.js",
+          "module.exports = require('./build/Release/addon.node');",
+        ),
+        ("build/Release/addon.node", ""),
+      ],
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn native_addons() {
+  let project = Project::new();
+  native_addon_packages(&project);
+
+  // Even the bundled layers keep native addons external.
+  let compiler = project
+    .compile(project.plugin_options(&[]), Some("rsc"))
+    .await;
+  let errors = messages(compiler.compilation.get_errors());
+  assert!(errors.is_empty(), "{errors:#?}");
+  let bundle = fs::read_to_string(project.path(".next/server/main.js")).unwrap();
+  assert!(is_required_external(&bundle, "gyp-addon"), "{bundle}");
+  assert!(is_required_external(&bundle, "prebuilt"), "{bundle}");
+  assert!(
+    !bundle.contains("./node_modules/gyp-addon/index.js"),
+    "{bundle}"
+  );
+  assert!(
+    bundle.contains("\"(rsc)/./node_modules/local-addon/index.js\""),
+    "{bundle}"
+  );
+  assert!(
+    is_required_external(
+      &bundle,
+      &project.path("node_modules/local-addon/build/Release/addon.node")
+    ),
+    "{bundle}"
+  );
+
+  // Transpiled packages are bundled along with their loaders, while `.node`
+  // files stay external.
+  project.file(
+    "pages/index.js",
+    "module.exports = [require('gyp-addon/index.js'), require('prebuilt')];\n",
+  );
+  let compiler = project
+    .compile(
+      project.plugin_options(&["gyp-addon", "prebuilt"]),
+      Some("rsc"),
+    )
+    .await;
+  let bundle = fs::read_to_string(project.path(".next/server/main.js")).unwrap();
+  let errors = messages(compiler.compilation.get_errors());
+  assert!(errors.is_empty(), "{errors:#?}");
+  assert!(
+    !is_required_external(&bundle, "gyp-addon/index.js"),
+    "{bundle}"
+  );
+  assert!(
+    bundle.contains("\"(rsc)/./node_modules/gyp-addon/index.js\""),
+    "{bundle}"
+  );
+  assert!(
+    bundle.contains("\"(rsc)/./node_modules/bindings/bindings.js\""),
+    "{bundle}"
+  );
+  assert!(is_required_external(&bundle, "prebuilt"), "{bundle}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn edge_runtime_native_addons() {
+  let project = Project::new();
+  native_addon_packages(&project);
+
+  let mut options = project.plugin_options(&[]);
+  options.compiler_type = CompilerType::EdgeServer;
+  let compiler = project.compile(options, None).await;
+
+  let errors = messages(compiler.compilation.get_errors());
+  assert_eq!(
+    errors,
+    [
+      "The package 'gyp-addon' loads native Node.js addons ('.node' files) which are not supported in the Edge Runtime.\n\
+       Import it from the Node.js runtime instead.\n\n\
+       Import trace for requested module:\n\
+       ./node_modules/gyp-addon/index.js\n\
+       ./pages/index.js",
+      "The package 'local-addon' loads native Node.js addons ('.node' files) which are not supported in the Edge Runtime.\n\
+       Import it from the Node.js runtime instead.\n\n\
+       Import trace for requested module:\n\
+       ./node_modules/local-addon/build/Release/addon.node\n\
+       ./node_modules/local-addon/index.js\n\
+       ./pages/index.js",
+      "The package 'prebuilt' loads native Node.js addons ('.node' files) which are not supported in the Edge Runtime.\n\
+       Import it from the Node.js runtime instead.\n\n\
+       Import trace for requested module:\n\
+       ./node_modules/prebuilt/prebuilt.node\n\
+       ./pages/index.js",
+    ],
+    "{errors:#?}"
+  );
+}
+
+/// Writes a zip archive of uncompressed files and directories, whose names
+/// end with `/`, as in the Yarn cache. The checksums are left out, they
+/// aren't verified when reading from the cache.
+fn write_zip(path: &str, files: &[(&str, &str)]) {
+  let mut zip = vec![];
+  let mut central_directory = vec![];
+  for (name, contents) in files {
+    let offset = zip.len() as u32;
+    let sizes = [contents.len() as u32; 2];
+    zip.extend(0x04034b50u32.to_le_bytes());
+    zip.extend([20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    sizes.iter().for_each(|size| zip.extend(size.to_le_bytes()));
+    zip.extend((name.len() as u16).to_le_bytes());
+    zip.extend([0, 0]);
+    zip.extend(name.as_bytes());
+    zip.extend(contents.as_bytes());
+
+    central_directory.extend(0x02014b50u32.to_le_bytes());
+    central_directory.extend([20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    sizes
+      .iter()
+      .for_each(|size| central_directory.extend(size.to_le_bytes()));
+    central_directory.extend((name.len() as u16).to_le_bytes());
+    central_directory.extend([0; 12]);
+    central_directory.extend(offset.to_le_bytes());
+    central_directory.extend(name.as_bytes());
+  }
+  let central_directory_offset = zip.len() as u32;
+  zip.extend(&central_directory);
+  zip.extend(0x06054b50u32.to_le_bytes());
+  zip.extend([0, 0, 0, 0]);
+  zip.extend([(files.len() as u16).to_le_bytes(); 2].concat());
+  zip.extend((central_directory.len() as u32).to_le_bytes());
+  zip.extend(central_directory_offset.to_le_bytes());
+  zip.extend([0, 0]);
+  fs::create_dir_all(Path::new(path).parent().unwrap()).unwrap();
+  fs::write(path, zip).unwrap();
+}
+
+/// A `.pnp.cjs` manifest of the `app` workspace in the project root, with
+/// the locations of the other packages.
+fn pnp_manifest(packages: Value) -> String {
+  let app = json!({
+    "packageLocation": "./",
+    "packageDependencies": [
+      ["app", "workspace:."],
+      ["consumer", "npm:1.0.0"],
+      ["lib", "virtual:0123#npm:1.0.0"],
+      ["zipped", "npm:1.0.0"],
+    ],
+    "linkType": "SOFT",
+  });
+  let mut registry = vec![
+    json!([null, [[null, app.clone()]]]),
+    json!(["app", [["workspace:.", app]]]),
+  ];
+  registry.extend(packages.as_array().unwrap().iter().cloned());
+  let state = json!({
+    "__info": [],
+    "dependencyTreeRoots": [{ "name": "app", "reference": "workspace:." }],
+    "enableTopLevelFallback": false,
+    "ignorePatternData": null,
+    "fallbackExclusionList": [],
+    "fallbackPool": [],
+    "packageRegistryData": registry,
+  });
+  format!("\"use strict\";\n\nconst RAW_RUNTIME_STATE =\n'{state}';\n")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn yarn_pnp() {
+  let project = Project::new();
+  let package = |name: &str, reference: &str, location: &str, dependencies: Value| {
+    json!([name, [[reference, {
+      "packageLocation": location,
+      "packageDependencies": dependencies,
+      "linkType": "HARD",
+    }]]])
+  };
+  let lib_instance = |hash: &str| {
+    json!([
+      format!("virtual:{hash}#npm:1.0.0"),
+      {
+        "packageLocation": format!("./.yarn/__virtual__/lib-virtual-{hash}/0/cache/lib-npm-1.0.0/node_modules/lib/"),
+        "packageDependencies": [["lib", format!("virtual:{hash}#npm:1.0.0")]],
+        "linkType": "HARD",
+      }
+    ])
+  };
+  project
+    .file(
+      ".pnp.cjs",
+      &pnp_manifest(json!([
+        package(
+          "consumer",
+          "npm:1.0.0",
+          "./.yarn/cache/consumer-npm-1.0.0/node_modules/consumer/",
+          json!([["consumer", "npm:1.0.0"], ["lib", "virtual:4567#npm:1.0.0"]]),
+        ),
+        ["lib", [lib_instance("0123"), lib_instance("4567")]],
+        package(
+          "zipped",
+          "npm:1.0.0",
+          "./.yarn/cache/zipped-npm-1.0.0-89ab.zip/node_modules/zipped/",
+          json!([["zipped", "npm:1.0.0"]]),
+        ),
+      ])),
+    )
+    .file(
+      "pages/index.js",
+      "module.exports = [require('zipped'), require('lib'), require('consumer')];\n",
+    )
+    // Transpiled, so its request of `lib` goes through the externals.
+    .package(
+      ".yarn/cache/consumer-npm-1.0.0/node_modules/consumer",
+      json!({ "name": "consumer", "main": "index.js" }),
+      &[("index.js", "module.exports = require('lib');")],
+    )
+    .package(
+      ".yarn/cache/lib-npm-1.0.0/node_modules/lib",
+      json!({ "name": "lib", "main": "index.js" }),
+      &[("index.js", "module.exports = 'lib';")],
+    );
+  write_zip(
+    &project.path(".yarn/cache/zipped-npm-1.0.0-89ab.zip"),
+    &[
+      ("node_modules/", ""),
+      ("node_modules/zipped/", ""),
+      (
+        "node_modules/zipped/package.json",
+        r#"{ "name": "zipped", "main": "index.js" }"#,
+      ),
+      ("node_modules/zipped/index.js", "module.exports = 'zipped';"),
+    ],
+  );
+
+  let mut options = project.plugin_options(&["consumer"]);
+  options.pnp = true;
+  let compiler = project.compile(options, None).await;
+
+  let errors = messages(compiler.compilation.get_errors());
+  assert!(errors.is_empty(), "{errors:#?}");
+  let bundle = fs::read_to_string(project.path(".next/server/main.js")).unwrap();
+  assert!(is_required_external(&bundle, "zipped"), "{bundle}");
+  assert!(
+    is_bundled(
+      &bundle,
+      ".yarn/cache/consumer-npm-1.0.0/node_modules/consumer/index.js"
+    ),
+    "{bundle}"
+  );
+  // `consumer` gets another virtual instance of `lib` than the app, which is
+  // the same package nonetheless.
+  assert!(is_required_external(&bundle, "lib"), "{bundle}");
+  assert!(!bundle.contains("node_modules/lib/index.js"), "{bundle}");
+  // Installing packages updates the manifest.
+  assert!(
+    compiler
+      .compilation
+      .file_dependencies
+      .contains(Path::new(&project.path(".pnp.cjs"))),
+    "{:?}",
+    compiler.compilation.file_dependencies
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn esm_package_required() {
+  let project = Project::new();
+  project
+    .file(
+      "pages/index.js",
+      "const esm = require('esm-only');\nmodule.exports = esm;\n",
+    )
+    .package(
+      "node_modules/esm-only",
+      json!({ "name": "esm-only", "type": "module", "exports": "./index.js" }),
+      &[("index.js", "export default 'esm-only';")],
+    );
+
+  let compiler = project.compile(project.plugin_options(&[]), None).await;
+
+  let errors = compiler
+    .compilation
+    .get_errors()
+    .map(|error| error.render_report(false).unwrap())
+    .collect::<Vec<_>>();
+  assert_eq!(errors.len(), 1, "{errors:#?}");
+  let error = &errors[0];
+  assert!(
+    error.contains(&format!("Required by: {}", project.path("pages/index.js"))),
+    "{error}"
+  );
+  assert!(
+    error.contains("ESM packages (esm-only) need to be imported"),
+    "{error}"
+  );
+  assert!(
+    error.contains("const esm = require('esm-only');"),
+    "{error}"
+  );
+  assert!(error.contains("required here"), "{error}");
+  assert!(
+    error.contains(&format!(
+      "Resolved to: {}",
+      project.path("node_modules/esm-only/index.js")
+    )),
+    "{error}"
+  );
+  assert!(
+    error.contains(&format!(
+      "Package:     {}",
+      project.path("node_modules/esm-only/package.json")
+    )),
+    "{error}"
+  );
+  assert!(error.contains("\"type\": \"module\""), "{error}");
+  assert!(error.contains("\"exports\": \"./index.js\""), "{error}");
+  assert!(
+    error.contains("experimental.esmExternals` to \"loose\""),
+    "{error}"
+  );
+}
+
+/// Relinking a transpiled package in watch mode, as `pnpm install` does when
+/// the package is upgraded, has to be picked up without a restart.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn transpile_packages_relinked() {
+  let project = Project::new();
+  project
+    .file("pages/index.js", "module.exports = require('ui');\n")
+    .package(
+      "node_modules/.pnpm/ui@1.0.0/node_modules/ui",
+      json!({ "name": "ui", "main": "index.js" }),
+      &[("index.js", "module.exports = 'ui@1';")],
+    )
+    .package(
+      "node_modules/.pnpm/ui@2.0.0/node_modules/ui",
+      json!({ "name": "ui", "main": "index.js" }),
+      &[("index.js", "module.exports = 'ui@2';")],
+    )
+    .symlink(
+      "node_modules/.pnpm/ui@1.0.0/node_modules/ui",
+      "node_modules/ui",
+    );
+
+  let mut compiler = project.compile(project.plugin_options(&["ui"]), None).await;
+  let bundle = fs::read_to_string(project.path(".next/server/main.js")).unwrap();
+  assert!(
+    is_bundled(
+      &bundle,
+      "node_modules/.pnpm/ui@1.0.0/node_modules/ui/index.js"
+    ),
+    "{bundle}"
+  );
+  assert!(compiler
+    .compilation
+    .missing_dependencies
+    .iter()
+    .any(|file| file.as_ref() == Path::new(&project.path("node_modules/ui/package.json"))),);
+
+  fs::remove_file(project.path("node_modules/ui")).unwrap();
+  project.symlink(
+    "node_modules/.pnpm/ui@2.0.0/node_modules/ui",
+    "node_modules/ui",
+  );
+  project.file(
+    "pages/index.js",
+    "module.exports = require('ui');\n// relinked\n",
+  );
+  compiler
+    .rebuild(
+      [
+        project.path("node_modules/ui/package.json"),
+        project.path("pages/index.js"),
+      ]
+      .into_iter()
+      .collect(),
+      Default::default(),
+    )
+    .await
+    .unwrap();
+
+  let errors = messages(compiler.compilation.get_errors());
+  assert!(errors.is_empty(), "{errors:#?}");
+  let bundle = fs::read_to_string(project.path(".next/server/main.js")).unwrap();
+  assert!(!is_required_external(&bundle, "ui"), "{bundle}");
+  assert!(
+    is_bundled(
+      &bundle,
+      "node_modules/.pnpm/ui@2.0.0/node_modules/ui/index.js"
+    ),
+    "{bundle}"
+  );
+}
@@ -0,0 +1,97 @@
+//! The Next.js majors whose `next/dist` layout the externals rules know.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::webpack_layers::{
+  NEXT_14_BUNDLED_LAYERS, NEXT_14_SERVER_ONLY_LAYERS, WEBPACK_BUNDLED_LAYERS,
+  WEBPACK_SERVER_ONLY_LAYERS,
+};
+
+static NEXT_14_NOT_EXTERNAL_MODULES_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+  Regex::new(
+    r"^(?:private-next-pages/|next/(?:dist/pages/|(?:app|document|link|image|legacy/image|constants|dynamic|script|navigation|headers|router)$)|string-hash|private-next-rsc-action-validate|private-next-rsc-action-client-wrapper|private-next-rsc-server-reference)",
+  )
+  .unwrap()
+});
+
+static NEXT_15_NOT_EXTERNAL_MODULES_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+  Regex::new(
+    r"^(?:private-next-pages/|next/(?:dist/pages/|(?:app|cache|document|link|form|head|image|legacy/image|constants|dynamic|script|navigation|headers|router|compat/router|server)$)|string-hash|private-next-rsc-action-validate|private-next-rsc-action-client-wrapper|private-next-rsc-server-reference|private-next-rsc-cache-wrapper|private-next-rsc-track-dynamic-import$)",
+  )
+  .unwrap()
+});
+
+/// Selects the rules matching the `next/dist` layout of a Next.js major.
+/// The shared, compiled and `.external` files are laid out the same way in
+/// both, only the modules that must be bundled and the layers differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum NextVersion {
+  /// Next.js 14.2.
+  V14,
+  #[default]
+  V15,
+}
+
+impl NextVersion {
+  pub const VALUES: &[NextVersion] = &[Self::V14, Self::V15];
+
+  pub fn major(&self) -> u32 {
+    match self {
+      Self::V14 => 14,
+      Self::V15 => 15,
+    }
+  }
+
+  /// Requests of Next.js internals and app entries that are always bundled.
+  pub fn not_external_modules_regex(&self) -> &'static Regex {
+    match self {
+      Self::V14 => &NEXT_14_NOT_EXTERNAL_MODULES_REGEX,
+      Self::V15 => &NEXT_15_NOT_EXTERNAL_MODULES_REGEX,
+    }
+  }
+
+  pub fn bundled_layers(&self) -> &'static [&'static str] {
+    match self {
+      Self::V14 => NEXT_14_BUNDLED_LAYERS,
+      Self::V15 => WEBPACK_BUNDLED_LAYERS,
+    }
+  }
+
+  pub fn server_only_layers(&self) -> &'static [&'static str] {
+    match self {
+      Self::V14 => NEXT_14_SERVER_ONLY_LAYERS,
+      Self::V15 => WEBPACK_SERVER_ONLY_LAYERS,
+    }
+  }
+}
+
+impl std::str::FromStr for NextVersion {
+  type Err = String;
+
+  /// Parses a version such as `"15"` or `"14.2.3"` by its major, ignoring
+  /// the minor, patch and pre-release parts.
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let major = value
+      .trim_start_matches('v')
+      .split(['.', '-'])
+      .next()
+      .and_then(|major| major.parse::<u32>().ok());
+    Self::VALUES
+      .iter()
+      .find(|version| Some(version.major()) == major)
+      .copied()
+      .ok_or_else(|| {
+        let expected = Self::VALUES
+          .iter()
+          .map(|version| version.major().to_string())
+          .collect::<Vec<_>>()
+          .join(", ");
+        format!("Unsupported nextVersion \"{value}\", expected a version of Next.js {expected}")
+      })
+  }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,64 @@
+use super::*;
+
+#[test]
+fn parse() {
+  for (value, expected) in [
+    ("14", NextVersion::V14),
+    ("14.2.3", NextVersion::V14),
+    ("v14.2.30", NextVersion::V14),
+    ("15.0.0-canary.12", NextVersion::V15),
+    ("15.3.1", NextVersion::V15),
+  ] {
+    assert_eq!(value.parse::<NextVersion>(), Ok(expected), "{value}");
+  }
+  assert_eq!(
+    "13.5.6".parse::<NextVersion>(),
+    Err("Unsupported nextVersion \"13.5.6\", expected a version of Next.js 14, 15".to_string())
+  );
+  assert!("latest".parse::<NextVersion>().is_err());
+}
+
+#[test]
+fn not_external_modules() {
+  let cases: &[(&str, bool, bool)] = &[
+    // request, bundled by Next.js 14, bundled by Next.js 15
+    ("next/link", true, true),
+    ("next/dist/pages/_app", true, true),
+    ("private-next-pages/index", true, true),
+    ("private-next-rsc-action-validate", true, true),
+    ("next/cache", false, true),
+    ("next/form", false, true),
+    ("next/head", false, true),
+    ("next/compat/router", false, true),
+    ("next/server", false, true),
+    ("private-next-rsc-cache-wrapper", false, true),
+    ("private-next-rsc-track-dynamic-import", false, true),
+    ("next/dist/server/app-render", false, false),
+  ];
+  for (request, next_14, next_15) in cases {
+    assert_eq!(
+      NextVersion::V14
+        .not_external_modules_regex()
+        .is_match(request),
+      *next_14,
+      "{request} in Next.js 14"
+    );
+    assert_eq!(
+      NextVersion::V15
+        .not_external_modules_regex()
+        .is_match(request),
+      *next_15,
+      "{request} in Next.js 15"
+    );
+  }
+}
+
+#[test]
+fn layers_are_valid() {
+  for version in NextVersion::VALUES {
+    assert!(
+      crate::webpack_layers::WebpackLayers::new(*version, None, None).is_ok(),
+      "{version:?}"
+    );
+  }
+}
@@ -0,0 +1,79 @@
+//! Decides whether two resolutions of a request load the same module at
+//! runtime when their paths differ. This happens with symlinks, such as the
+//! ones of pnpm or `resolve.symlinks: false`, with virtual package instances
+//! of Yarn Plug'n'Play, and with copies of one package version installed in
+//! several places, e.g. with pnpm's `node-linker=hoisted`.
+
+use std::{borrow::Cow, fmt, fs};
+
+use serde_json::Value;
+
+use crate::{handle_externals::normalize_path_sep, yarn_pnp};
+
+/// A file of a package version, as described by the nearest `package.json`
+/// of a resolved path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageIdentity {
+  pub name: String,
+  pub version: String,
+  /// The path of the file in the package, `/`-separated.
+  pub subpath: String,
+}
+
+impl PackageIdentity {
+  /// Returns the identity of a resolved `path` in `package_dir`, or `None`
+  /// if its `package.json` lacks a name or version, as the ones of nested
+  /// directories setting only a `type` do.
+  pub fn new(path: &str, package_dir: &str, package_json: &Value) -> Option<Self> {
+    let name = package_json.get("name")?.as_str()?;
+    let version = package_json.get("version")?.as_str()?;
+    let path = normalize_path_sep(path);
+    let package_dir = normalize_path_sep(package_dir);
+    let subpath = path
+      .strip_prefix(package_dir.trim_end_matches('/'))?
+      .strip_prefix('/')?;
+    Some(Self {
+      name: name.to_string(),
+      version: version.to_string(),
+      subpath: subpath.to_string(),
+    })
+  }
+
+  /// Whether both are versions of the same package, which differ.
+  pub fn is_other_version(&self, other: &Self) -> bool {
+    self.name == other.name && self.version != other.version
+  }
+}
+
+impl fmt::Display for PackageIdentity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}@{}", self.name, self.version)
+  }
+}
+
+/// Returns the real path of a resolved `/`-separated path, following
+/// symlinks and virtual package instances. Paths that can't be read, such as
+/// the ones in zip archives, are only devirtualized.
+pub fn canonicalize(path: &str) -> Cow<'_, str> {
+  let path = yarn_pnp::devirtualize(path);
+  match fs::canonicalize(path.as_ref()) {
+    Ok(real_path) => Cow::Owned(normalize_path_sep(&real_path.to_string_lossy())),
+    Err(_) => path,
+  }
+}
+
+/// Whether two resolved paths load the same module: they are the same file of
+/// the same package version, or the same path once canonicalized.
+pub fn is_same_module(
+  a: &str,
+  a_package: Option<&PackageIdentity>,
+  b: &str,
+  b_package: Option<&PackageIdentity>,
+) -> bool {
+  yarn_pnp::is_same_file(a, b)
+    || a_package.is_some_and(|a_package| b_package == Some(a_package))
+    || canonicalize(a) == canonicalize(b)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,102 @@
+use std::fs;
+
+use serde_json::json;
+
+use super::*;
+
+fn identity(name: &str, version: &str, subpath: &str) -> PackageIdentity {
+  PackageIdentity {
+    name: name.to_string(),
+    version: version.to_string(),
+    subpath: subpath.to_string(),
+  }
+}
+
+#[test]
+fn identities_of_resolved_paths() {
+  let package_json = json!({ "name": "lib", "version": "1.0.0" });
+  assert_eq!(
+    PackageIdentity::new(
+      "/app/node_modules/lib/dist/index.js",
+      "/app/node_modules/lib",
+      &package_json
+    ),
+    Some(identity("lib", "1.0.0", "dist/index.js"))
+  );
+  assert_eq!(
+    PackageIdentity::new(
+      r"C:\app\node_modules\lib\index.js",
+      r"C:\app\node_modules\lib",
+      &package_json
+    ),
+    Some(identity("lib", "1.0.0", "index.js"))
+  );
+  // Nested `package.json`s only setting the module type.
+  assert_eq!(
+    PackageIdentity::new(
+      "/app/node_modules/lib/esm/index.js",
+      "/app/node_modules/lib/esm",
+      &json!({ "type": "module" })
+    ),
+    None
+  );
+  assert_eq!(
+    identity("@scope/lib", "2.0.0", "index.js").to_string(),
+    "@scope/lib@2.0.0"
+  );
+}
+
+#[test]
+fn other_versions() {
+  let lib = identity("lib", "1.0.0", "index.js");
+  assert!(lib.is_other_version(&identity("lib", "2.0.0", "index.js")));
+  assert!(!lib.is_other_version(&identity("lib", "1.0.0", "other.js")));
+  assert!(!lib.is_other_version(&identity("other", "2.0.0", "index.js")));
+}
+
+#[test]
+fn copies_of_a_package_version() {
+  let lib = identity("lib", "1.0.0", "index.js");
+  assert!(is_same_module(
+    "/app/node_modules/.pnpm/node_modules/lib/index.js",
+    Some(&lib),
+    "/app/node_modules/lib/index.js",
+    Some(&lib),
+  ));
+  assert!(!is_same_module(
+    "/app/node_modules/parent/node_modules/lib/index.js",
+    Some(&identity("lib", "2.0.0", "index.js")),
+    "/app/node_modules/lib/index.js",
+    Some(&lib),
+  ));
+  assert!(!is_same_module(
+    "/app/node_modules/parent/node_modules/lib/index.js",
+    None,
+    "/app/node_modules/lib/index.js",
+    None,
+  ));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_paths() {
+  let dir = tempfile::tempdir().unwrap();
+  let root = normalize_path_sep(&fs::canonicalize(dir.path()).unwrap().to_string_lossy());
+  let store = format!("{root}/node_modules/.pnpm/lib@1.0.0/node_modules/lib");
+  fs::create_dir_all(&store).unwrap();
+  fs::write(format!("{store}/index.js"), "").unwrap();
+  std::os::unix::fs::symlink(&store, format!("{root}/node_modules/lib")).unwrap();
+
+  let linked = format!("{root}/node_modules/lib/index.js");
+  assert_eq!(canonicalize(&linked), format!("{store}/index.js"));
+  assert!(is_same_module(
+    &linked,
+    None,
+    &format!("{store}/index.js"),
+    None
+  ));
+  assert_eq!(
+    canonicalize("/missing/.yarn/__virtual__/lib-virtual-0123/0/cache/lib.zip/index.js"),
+    "/missing/.yarn/cache/lib.zip/index.js"
+  );
+}
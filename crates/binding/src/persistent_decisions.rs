@@ -0,0 +1,298 @@
+//! Persists externals decisions next to rspack's persistent cache, so warm
+//! builds reuse them instead of resolving every request again.
+//!
+//! Decisions are stored per compiler in
+//! `next-externals-<compilerType>-<hash>.json` in the cache directory, where
+//! the hash identifies the project `dir` and the shared context id, as other
+//! projects may use the same cache directory. rspack's storage isn't
+//! reachable from plugins, and
+//! its cleanup removes the directories it doesn't know while keeping files.
+//! The file is discarded when the configuration, the resolve options or the
+//! lockfiles change, and each decision is discarded when the file it
+//! resolved to or its `package.json` changed. Decisions to bundle a missing
+//! package, or one that isn't the package available at runtime, aren't
+//! persisted: they depend on files that don't exist yet or aren't
+//! snapshotted, such as a package installed later in a nested
+//! `node_modules`.
+
+use std::{
+  fs,
+  hash::{Hash, Hasher},
+  io,
+  path::{Path, PathBuf},
+  time::UNIX_EPOCH,
+};
+
+use rspack_core::{
+  cache::persistent::storage::StorageOptions, CompilerOptions, ExperimentCacheOptions, Resolve,
+};
+use rustc_hash::{FxHashMap, FxHasher};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  handle_externals::{DecisionRule, ExternalHandler, TraceEntry, TranspilePackageDirs},
+  next_externals_plugin::CompilerType,
+};
+
+/// Bumped whenever the file format or the meaning of a decision changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// Files in the runtime roots whose changes may change any resolution.
+const LOCKFILES: &[&str] = &[
+  "package.json",
+  "package-lock.json",
+  "npm-shrinkwrap.json",
+  "pnpm-lock.yaml",
+  "yarn.lock",
+  "bun.lock",
+  "bun.lockb",
+];
+
+/// The modification time and size of a file, or `None` if it doesn't exist.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct FileSnapshot {
+  path: String,
+  state: Option<(u64, u64)>,
+}
+
+impl FileSnapshot {
+  fn take(path: &str) -> Self {
+    let state = fs::metadata(path).ok().map(|metadata| {
+      let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_millis() as u64);
+      (modified, metadata.len())
+    });
+    Self {
+      path: path.to_string(),
+      state,
+    }
+  }
+}
+
+/// Checks snapshots, looking at each file once.
+#[derive(Default)]
+struct SnapshotValidator {
+  checked: FxHashMap<String, bool>,
+}
+
+impl SnapshotValidator {
+  fn is_valid(&mut self, snapshots: &[FileSnapshot]) -> bool {
+    snapshots.iter().all(|snapshot| {
+      *self
+        .checked
+        .entry(snapshot.path.clone())
+        .or_insert_with(|| FileSnapshot::take(&snapshot.path) == *snapshot)
+    })
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedDecision {
+  #[serde(flatten)]
+  entry: TraceEntry,
+  snapshot: Vec<FileSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedTranspilePackageDirs {
+  #[serde(flatten)]
+  dirs: TranspilePackageDirs,
+  snapshot: Vec<FileSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedExternals {
+  version: u32,
+  fingerprint: u64,
+  transpile_package_dirs: Option<PersistedTranspilePackageDirs>,
+  decisions: Vec<PersistedDecision>,
+}
+
+/// The persisted decisions of one compiler.
+#[derive(Debug)]
+pub struct PersistentDecisions {
+  path: PathBuf,
+  fingerprint: u64,
+}
+
+impl PersistentDecisions {
+  /// Returns where the decisions of the compiler are persisted, or `None`
+  /// when the persistent cache is disabled.
+  pub fn new(
+    options: &CompilerOptions,
+    compiler_type: CompilerType,
+    external_handler: &ExternalHandler,
+    builtin_modules: &[String],
+  ) -> Option<Self> {
+    let ExperimentCacheOptions::Persistent(cache) = &options.experiments.cache else {
+      return None;
+    };
+    let StorageOptions::FileSystem { directory } = &cache.storage;
+    let directory = options.context.as_path().as_std_path().join(directory);
+
+    Some(Self::new_at(
+      directory.join(file_name(compiler_type, external_handler)),
+      fingerprint(
+        compiler_type,
+        external_handler,
+        builtin_modules,
+        &options.resolve,
+      ),
+    ))
+  }
+
+  fn new_at(path: PathBuf, fingerprint: u64) -> Self {
+    Self { path, fingerprint }
+  }
+
+  /// Restores the decisions whose files haven't changed into the handler,
+  /// and returns how many were restored.
+  pub fn restore(&self, external_handler: &ExternalHandler) -> usize {
+    let Some(persisted) = fs::read(&self.path)
+      .ok()
+      .and_then(|content| serde_json::from_slice::<PersistedExternals>(&content).ok())
+      .filter(|persisted| {
+        persisted.version == FORMAT_VERSION && persisted.fingerprint == self.fingerprint
+      })
+    else {
+      return 0;
+    };
+
+    let mut validator = SnapshotValidator::default();
+    if let Some(transpile_package_dirs) = persisted.transpile_package_dirs {
+      if validator.is_valid(&transpile_package_dirs.snapshot) {
+        external_handler.restore_transpile_package_dirs(transpile_package_dirs.dirs);
+      }
+    }
+    let decisions = persisted
+      .decisions
+      .into_iter()
+      .filter(|decision| validator.is_valid(&decision.snapshot))
+      .map(|decision| decision.entry)
+      .collect::<Vec<_>>();
+    let restored = decisions.len();
+    external_handler.restore_decisions(decisions);
+    restored
+  }
+
+  /// Writes the decisions made so far, replacing the persisted ones.
+  pub fn save(&self, external_handler: &ExternalHandler) -> io::Result<()> {
+    let mut package_jsons = FxHashMap::default();
+    let decisions = external_handler
+      .trace_entries()
+      .into_iter()
+      .filter(|entry| {
+        !matches!(
+          entry.rule,
+          DecisionRule::Unresolved | DecisionRule::BaseResolveMismatch
+        )
+      })
+      .map(|entry| {
+        let snapshot = match &entry.resolved {
+          Some(resolved) => [
+            Some(resolved.clone()),
+            nearest_package_json(resolved, &mut package_jsons),
+          ]
+          .into_iter()
+          .flatten()
+          .map(|path| FileSnapshot::take(&path))
+          .collect(),
+          None => vec![],
+        };
+        PersistedDecision { entry, snapshot }
+      })
+      .collect();
+    let transpile_package_dirs =
+      external_handler
+        .transpile_package_dirs()
+        .map(|dirs| PersistedTranspilePackageDirs {
+          snapshot: dirs
+            .package_jsons()
+            .iter()
+            .chain(dirs.missing_package_jsons())
+            .map(|path| FileSnapshot::take(path))
+            .collect(),
+          dirs: (*dirs).clone(),
+        });
+    let content = serde_json::to_vec(&PersistedExternals {
+      version: FORMAT_VERSION,
+      fingerprint: self.fingerprint,
+      transpile_package_dirs,
+      decisions,
+    })?;
+
+    // Write to a temporary file first, so a concurrent build never reads a
+    // partially written file.
+    if let Some(parent) = self.path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let temp_path = self.path.with_extension("json.tmp");
+    fs::write(&temp_path, content)?;
+    fs::rename(temp_path, &self.path)
+  }
+}
+
+/// Hashes everything a persisted file is only valid for.
+/// The name of the file the decisions of a compiler are persisted to.
+fn file_name(compiler_type: CompilerType, external_handler: &ExternalHandler) -> String {
+  let mut hasher = FxHasher::default();
+  external_handler.dir().hash(&mut hasher);
+  external_handler.shared_context_id().hash(&mut hasher);
+  format!(
+    "next-externals-{compiler_type}-{:016x}.json",
+    hasher.finish()
+  )
+}
+
+fn fingerprint(
+  compiler_type: CompilerType,
+  external_handler: &ExternalHandler,
+  builtin_modules: &[String],
+  resolve: &Resolve,
+) -> u64 {
+  let dir = external_handler.dir();
+  let mut hasher = FxHasher::default();
+  FORMAT_VERSION.hash(&mut hasher);
+  compiler_type.as_str().hash(&mut hasher);
+  dir.hash(&mut hasher);
+  external_handler.config_fingerprint().hash(&mut hasher);
+  builtin_modules.hash(&mut hasher);
+  resolve.hash(&mut hasher);
+  for root in external_handler.runtime_roots() {
+    for lockfile in LOCKFILES {
+      FileSnapshot::take(&format!("{root}/{lockfile}"))
+        .state
+        .hash(&mut hasher);
+    }
+  }
+  hasher.finish()
+}
+
+/// Returns the `package.json` closest to a resolved file, which decides
+/// whether a `.js` file is an ES module.
+fn nearest_package_json(
+  resolved: &str,
+  package_jsons: &mut FxHashMap<PathBuf, Option<String>>,
+) -> Option<String> {
+  let dir = Path::new(resolved).parent()?;
+  if let Some(package_json) = package_jsons.get(dir) {
+    return package_json.clone();
+  }
+  let package_json = dir.ancestors().find_map(|ancestor| {
+    let package_json = ancestor.join("package.json");
+    package_json
+      .is_file()
+      .then(|| package_json.to_string_lossy().replace('\\', "/"))
+  });
+  package_jsons.insert(dir.to_path_buf(), package_json.clone());
+  package_json
+}
+
+#[cfg(test)]
+mod tests;
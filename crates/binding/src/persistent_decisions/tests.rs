@@ -0,0 +1,318 @@
+use std::sync::{
+  atomic::{AtomicUsize, Ordering},
+  Arc,
+};
+
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+use super::*;
+use crate::{
+  config_shared::{EsmExternalsConfig, ExperimentalConfig, NextConfigComplete},
+  handle_externals::{DecisionRule, GetResolveFn, ResolveFn},
+  test_utils::PackageTree,
+};
+
+/// A project written both to a temporary directory, for the snapshots, and
+/// to a `PackageTree`, for resolution.
+struct Project {
+  _dir: TempDir,
+  root: String,
+  tree: PackageTree,
+}
+
+impl Project {
+  fn new() -> Self {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    Self {
+      _dir: dir,
+      root: root.to_string_lossy().replace('\\', "/"),
+      tree: PackageTree::new(),
+    }
+    .with_package("", json!({ "name": "app" }), &[])
+    .with_package(
+      "node_modules/cjs-pkg",
+      json!({ "main": "index.js" }),
+      &[("index.js", "")],
+    )
+    .with_package(
+      "node_modules/esm-pkg",
+      json!({ "type": "module", "main": "index.js" }),
+      &[("index.js", "")],
+    )
+    .with_package(
+      "node_modules/transpiled-pkg",
+      json!({ "main": "index.js" }),
+      &[("index.js", "")],
+    )
+    .with_file("pages/index.js", "")
+  }
+
+  fn path(&self, path: &str) -> String {
+    if path.is_empty() {
+      self.root.clone()
+    } else {
+      format!("{}/{path}", self.root)
+    }
+  }
+
+  fn with_file(mut self, path: &str, contents: &str) -> Self {
+    let path = self.path(path);
+    fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+    fs::write(&path, contents).unwrap();
+    self.tree = self.tree.with_file(&path, contents);
+    self
+  }
+
+  fn with_package(self, dir: &str, package_json: Value, files: &[(&str, &str)]) -> Self {
+    let package_json_path = if dir.is_empty() {
+      "package.json".to_string()
+    } else {
+      format!("{dir}/package.json")
+    };
+    let mut project = self.with_file(&package_json_path, &package_json.to_string());
+    for (path, contents) in files {
+      project = project.with_file(&format!("{dir}/{path}"), contents);
+    }
+    project
+  }
+
+  fn handler(&self) -> ExternalHandler {
+    ExternalHandler::new(
+      NextConfigComplete {
+        experimental: ExperimentalConfig {
+          esm_externals: EsmExternalsConfig::Loose,
+          ..Default::default()
+        },
+        ..Default::default()
+      },
+      None,
+      vec![],
+      vec!["transpiled-pkg".to_string()],
+      self.root.clone(),
+      FxHashMap::default(),
+    )
+  }
+
+  /// Returns a resolver for the project, and the number of requests it has
+  /// resolved.
+  fn get_resolve(&self) -> (GetResolveFn, Arc<AtomicUsize>) {
+    let get_resolve = self.tree.clone().into_get_resolve();
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let get_resolve: GetResolveFn = Arc::new(move |options| {
+      let resolve = get_resolve(options);
+      let counter = counter.clone();
+      let resolve: ResolveFn = Box::new(move |context, request| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        resolve(context, request)
+      });
+      resolve
+    });
+    (get_resolve, count)
+  }
+
+  fn persistent_decisions(&self, handler: &ExternalHandler) -> PersistentDecisions {
+    PersistentDecisions::new_at(
+      Path::new(&self.path(".next/cache/rspack")).join(file_name(CompilerType::Server, handler)),
+      fingerprint(CompilerType::Server, handler, &[], &Resolve::default()),
+    )
+  }
+
+  async fn decide(
+    &self,
+    handler: &ExternalHandler,
+    request: &str,
+    get_resolve: &GetResolveFn,
+  ) -> DecisionRule {
+    handler
+      .decide(
+        self.path("pages"),
+        request.to_string(),
+        "cjs",
+        None,
+        get_resolve.clone(),
+      )
+      .await
+      .unwrap()
+      .rule
+  }
+}
+
+const REQUESTS: &[(&str, DecisionRule)] = &[
+  ("cjs-pkg", DecisionRule::NodeModulesExternal),
+  ("esm-pkg", DecisionRule::NodeModulesExternal),
+  ("transpiled-pkg", DecisionRule::TranspilePackage),
+];
+
+/// Makes the decisions of `REQUESTS` with a new handler, and persists them.
+async fn build(project: &Project) {
+  let handler = project.handler();
+  let (get_resolve, _) = project.get_resolve();
+  for (request, expected) in REQUESTS {
+    assert_eq!(
+      project.decide(&handler, request, &get_resolve).await,
+      *expected,
+      "{request}"
+    );
+  }
+  project
+    .persistent_decisions(&handler)
+    .save(&handler)
+    .unwrap();
+}
+
+#[tokio::test]
+async fn warm_build_skips_resolution() {
+  let project = Project::new();
+  build(&project).await;
+
+  let handler = project.handler();
+  let persistent_decisions = project.persistent_decisions(&handler);
+  assert_eq!(persistent_decisions.restore(&handler), REQUESTS.len());
+  assert!(handler.transpile_package_dirs().is_some());
+
+  let (get_resolve, resolves) = project.get_resolve();
+  for (request, expected) in REQUESTS {
+    assert_eq!(
+      project.decide(&handler, request, &get_resolve).await,
+      *expected,
+      "{request}"
+    );
+  }
+  assert_eq!(resolves.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn changed_files_invalidate_their_decisions() {
+  let project = Project::new();
+  build(&project).await;
+
+  // A package rewritten with a different main file, and a package changed to
+  // CommonJS without touching the lockfile, e.g. a linked workspace package.
+  let project = project
+    .with_file("node_modules/cjs-pkg/index.js", "module.exports = {}")
+    .with_package("node_modules/esm-pkg", json!({ "main": "index.js" }), &[]);
+
+  let handler = project.handler();
+  assert_eq!(project.persistent_decisions(&handler).restore(&handler), 1);
+  let (get_resolve, resolves) = project.get_resolve();
+  project
+    .decide(&handler, "transpiled-pkg", &get_resolve)
+    .await;
+  assert_eq!(resolves.load(Ordering::Relaxed), 0);
+  project.decide(&handler, "cjs-pkg", &get_resolve).await;
+  project.decide(&handler, "esm-pkg", &get_resolve).await;
+  assert_ne!(resolves.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn missing_packages_are_decided_again() {
+  let project = Project::new();
+  let handler = project.handler();
+  let (get_resolve, _) = project.get_resolve();
+  assert_eq!(
+    project.decide(&handler, "missing-pkg", &get_resolve).await,
+    DecisionRule::Unresolved
+  );
+  project
+    .persistent_decisions(&handler)
+    .save(&handler)
+    .unwrap();
+
+  // Installed without touching any lockfile, e.g. in a nested workspace.
+  let project = project.with_package(
+    "node_modules/missing-pkg",
+    json!({ "main": "index.js" }),
+    &[("index.js", "")],
+  );
+  let handler = project.handler();
+  assert_eq!(project.persistent_decisions(&handler).restore(&handler), 0);
+  let (get_resolve, _) = project.get_resolve();
+  assert_eq!(
+    project.decide(&handler, "missing-pkg", &get_resolve).await,
+    DecisionRule::NodeModulesExternal
+  );
+}
+
+#[tokio::test]
+async fn changed_transpile_package_invalidates_dirs() {
+  let project = Project::new();
+  build(&project).await;
+
+  let project = project.with_package(
+    "node_modules/transpiled-pkg",
+    json!({ "main": "lib/index.js" }),
+    &[("lib/index.js", "")],
+  );
+  let handler = project.handler();
+  project.persistent_decisions(&handler).restore(&handler);
+  assert!(handler.transpile_package_dirs().is_none());
+}
+
+#[tokio::test]
+async fn lockfile_changes_discard_everything() {
+  let project = Project::new();
+  build(&project).await;
+
+  let handler = project.handler();
+  let before = project.persistent_decisions(&handler).fingerprint;
+  let project = project.with_file("pnpm-lock.yaml", "lockfileVersion: '9.0'");
+  let after = project.persistent_decisions(&handler);
+  assert_ne!(after.fingerprint, before);
+  assert_eq!(after.restore(&handler), 0);
+  assert!(handler.transpile_package_dirs().is_none());
+}
+
+#[test]
+fn fingerprint_covers_config_and_builtins() {
+  let project = Project::new();
+  let handler = project.handler();
+  let fingerprint_of = |handler: &ExternalHandler, builtin_modules: &[String]| {
+    fingerprint(
+      CompilerType::Server,
+      handler,
+      builtin_modules,
+      &Resolve::default(),
+    )
+  };
+  let base = fingerprint_of(&handler, &[]);
+  assert_eq!(fingerprint_of(&project.handler(), &[]), base);
+  assert_ne!(fingerprint_of(&handler, &["fs".to_string()]), base);
+
+  let bundled_pages = ExternalHandler::new(
+    NextConfigComplete {
+      bundle_pages_router_dependencies: Some(true),
+      ..Default::default()
+    },
+    None,
+    vec![],
+    vec!["transpiled-pkg".to_string()],
+    project.root.clone(),
+    FxHashMap::default(),
+  );
+  assert_ne!(fingerprint_of(&bundled_pages, &[]), base);
+}
+
+#[test]
+fn file_names_differ_per_project_and_context() {
+  let project = Project::new();
+  let other_project = Project::new();
+  let file_name_of = |handler: &ExternalHandler| file_name(CompilerType::Server, handler);
+
+  let base = file_name_of(&project.handler());
+  assert!(base.starts_with("next-externals-server-"), "{base}");
+  assert!(base.ends_with(".json"), "{base}");
+  assert_eq!(file_name_of(&project.handler()), base);
+  assert_ne!(file_name_of(&other_project.handler()), base);
+  assert_ne!(
+    file_name_of(&project.handler().with_shared_context("build")),
+    base
+  );
+  assert_ne!(
+    file_name(CompilerType::EdgeServer, &project.handler()),
+    base
+  );
+}
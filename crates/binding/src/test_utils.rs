@@ -0,0 +1,318 @@
+//! An in-memory package tree that implements the `GetResolveFn` contract, so
+//! that `handle_externals` and `resolve_external` can be tested without
+//! touching the file system.
+
+use std::sync::Arc;
+
+use rspack_core::{Alias, Resolve, ResolveOptionsWithDependencyType};
+use rustc_hash::FxHashMap;
+use serde_json::Value;
+
+use crate::{
+  handle_externals::{GetResolveFn, ResolveFn, ResolvedRequest},
+  native_addons,
+  package_identity::PackageIdentity,
+};
+
+/// A set of files keyed by absolute, `/`-separated paths.
+///
+/// Resolution follows the subset of the node resolution algorithm the
+/// externals handling relies on: `node_modules` lookup, `exports` (string,
+/// conditions and subpath maps), main fields, main files, extensions and
+/// `fully_specified`. As in Node.js, the first condition of an `exports`
+/// object that is in `condition_names`, or is `default`, is matched.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PackageTree {
+  files: FxHashMap<String, String>,
+  aliases: Vec<(String, String)>,
+}
+
+impl PackageTree {
+  pub(crate) fn new() -> Self {
+    Self::default()
+  }
+
+  pub(crate) fn with_file(mut self, path: &str, contents: &str) -> Self {
+    self.files.insert(path.to_string(), contents.to_string());
+    self
+  }
+
+  pub(crate) fn with_package_json(self, dir: &str, package_json: Value) -> Self {
+    self.with_file(&format!("{dir}/package.json"), &package_json.to_string())
+  }
+
+  /// Adds a compiler-level alias, which only applies when the resolve options
+  /// don't overwrite the alias with `Alias::OverwriteToNoAlias`.
+  pub(crate) fn with_alias(mut self, from: &str, to: &str) -> Self {
+    self.aliases.push((from.to_string(), to.to_string()));
+    self
+  }
+
+  pub(crate) fn into_get_resolve(self) -> GetResolveFn {
+    let tree = Arc::new(self);
+    Arc::new(move |options: Option<ResolveOptionsWithDependencyType>| {
+      let tree = tree.clone();
+      let options = options
+        .and_then(|options| options.resolve_options)
+        .map(|options| *options)
+        .unwrap_or_default();
+      let resolve: ResolveFn = Box::new(move |context: String, request: String| {
+        let result = tree.resolve(&options, &context, &request);
+        Box::pin(async move { result })
+      });
+      resolve
+    })
+  }
+
+  pub(crate) fn resolve(
+    &self,
+    options: &Resolve,
+    context: &str,
+    request: &str,
+  ) -> rspack_error::Result<ResolvedRequest> {
+    let request = self.apply_alias(options, request);
+    let resolved = if request.starts_with('/') || request.starts_with('.') {
+      self.resolve_path(
+        options,
+        &join(context, &request),
+        options.fully_specified.unwrap_or(false),
+      )
+    } else {
+      self.resolve_module(options, context, &request)
+    };
+    match resolved {
+      Some(path) => Ok(ResolvedRequest {
+        is_esm: self.is_esm(&path),
+        is_native: native_addons::is_native(&path, self.nearest_package_json(&path).as_ref()),
+        package: self
+          .nearest_package(&path)
+          .and_then(|(dir, package_json)| PackageIdentity::new(&path, dir, &package_json)),
+        path: Some(path),
+      }),
+      None => Err(rspack_error::error!(
+        "Can't resolve '{}' in '{}'",
+        request,
+        context
+      )),
+    }
+  }
+
+  fn apply_alias(&self, options: &Resolve, request: &str) -> String {
+    if matches!(options.alias, Some(Alias::OverwriteToNoAlias)) {
+      return request.to_string();
+    }
+    self
+      .aliases
+      .iter()
+      .find_map(|(from, to)| {
+        let rest = request.strip_prefix(from.as_str())?;
+        (rest.is_empty() || rest.starts_with('/')).then(|| format!("{to}{rest}"))
+      })
+      .unwrap_or_else(|| request.to_string())
+  }
+
+  fn resolve_module(&self, options: &Resolve, context: &str, request: &str) -> Option<String> {
+    let package_name_len = match request.split_once('/') {
+      Some((scope, rest)) if scope.starts_with('@') => {
+        scope.len() + 1 + rest.split('/').next().unwrap_or_default().len()
+      }
+      Some((name, _)) => name.len(),
+      None => request.len(),
+    };
+    let (package_name, subpath) = request.split_at(package_name_len);
+
+    let mut dir = Some(context);
+    while let Some(current) = dir {
+      let package_dir = join(current, &format!("node_modules/{package_name}"));
+      if self.is_dir(&package_dir) {
+        return self.resolve_package(options, &package_dir, subpath);
+      }
+      dir = parent(current);
+    }
+    None
+  }
+
+  fn resolve_package(&self, options: &Resolve, package_dir: &str, subpath: &str) -> Option<String> {
+    let package_json = self.package_json(package_dir);
+    let uses_exports = options
+      .exports_fields
+      .as_ref()
+      .is_some_and(|fields| fields.iter().any(|field| field == &["exports"]));
+    if let Some(exports) = package_json
+      .as_ref()
+      .and_then(|package_json| package_json.get("exports"))
+      .filter(|_| uses_exports)
+    {
+      let conditions = options.condition_names.clone().unwrap_or_default();
+      let target = match_exports(exports, &format!(".{subpath}"), &conditions)?;
+      return self.resolve_file(options, &join(package_dir, &target), true);
+    }
+    if subpath.is_empty() {
+      self.resolve_dir(options, package_dir)
+    } else {
+      self.resolve_path(
+        options,
+        &format!("{package_dir}{subpath}"),
+        options.fully_specified.unwrap_or(false),
+      )
+    }
+  }
+
+  fn resolve_path(&self, options: &Resolve, path: &str, fully_specified: bool) -> Option<String> {
+    self
+      .resolve_file(options, path, fully_specified)
+      .or_else(|| (!fully_specified).then(|| self.resolve_dir(options, path))?)
+  }
+
+  fn resolve_file(&self, options: &Resolve, path: &str, fully_specified: bool) -> Option<String> {
+    if self.files.contains_key(path) {
+      return Some(path.to_string());
+    }
+    if fully_specified {
+      return None;
+    }
+    options
+      .extensions
+      .iter()
+      .flatten()
+      .map(|extension| format!("{path}{extension}"))
+      .find(|candidate| self.files.contains_key(candidate))
+  }
+
+  fn resolve_dir(&self, options: &Resolve, dir: &str) -> Option<String> {
+    if let Some(package_json) = self.package_json(dir) {
+      let main_fields = options
+        .main_fields
+        .clone()
+        .unwrap_or_else(|| vec!["main".to_string()]);
+      for field in main_fields {
+        if let Some(main) = package_json.get(&field).and_then(Value::as_str) {
+          let main = join(dir, main);
+          if let Some(resolved) = self
+            .resolve_file(options, &main, false)
+            .or_else(|| self.resolve_index(options, &main))
+          {
+            return Some(resolved);
+          }
+        }
+      }
+    }
+    self.resolve_index(options, dir)
+  }
+
+  fn resolve_index(&self, options: &Resolve, dir: &str) -> Option<String> {
+    let main_files = options
+      .main_files
+      .clone()
+      .unwrap_or_else(|| vec!["index".to_string()]);
+    main_files
+      .iter()
+      .find_map(|main_file| self.resolve_file(options, &format!("{dir}/{main_file}"), false))
+  }
+
+  /// Mirrors the `is_esm` detection of the plugin's resolver: `.mjs` files, and
+  /// `.js` files whose nearest `package.json` has `"type": "module"`.
+  fn is_esm(&self, path: &str) -> bool {
+    if path.ends_with(".mjs") {
+      return true;
+    }
+    path.ends_with(".js")
+      && self.nearest_package_json(path).is_some_and(|package_json| {
+        package_json.get("type").and_then(Value::as_str) == Some("module")
+      })
+  }
+
+  /// The `package.json` describing a file, as in the description data of the
+  /// plugin's resolver.
+  fn nearest_package_json(&self, path: &str) -> Option<Value> {
+    self
+      .nearest_package(path)
+      .map(|(_, package_json)| package_json)
+  }
+
+  /// The directory and `package.json` of the nearest package of a file.
+  fn nearest_package<'a>(&self, path: &'a str) -> Option<(&'a str, Value)> {
+    let mut dir = parent(path);
+    while let Some(current) = dir {
+      if let Some(package_json) = self.package_json(current) {
+        return Some((current, package_json));
+      }
+      dir = parent(current);
+    }
+    None
+  }
+
+  fn package_json(&self, dir: &str) -> Option<Value> {
+    let contents = self.files.get(&join(dir, "package.json"))?;
+    Some(serde_json::from_str(contents).expect("fixture package.json should be valid JSON"))
+  }
+
+  fn is_dir(&self, dir: &str) -> bool {
+    let prefix = format!("{dir}/");
+    self.files.keys().any(|path| path.starts_with(&prefix))
+  }
+}
+
+fn match_exports(exports: &Value, subpath: &str, conditions: &[String]) -> Option<String> {
+  match exports {
+    Value::Object(map) if map.keys().all(|key| key.starts_with('.')) => {
+      if let Some(target) = map.get(subpath) {
+        return match_conditions(target, conditions);
+      }
+      map.iter().find_map(|(key, target)| {
+        let (prefix, suffix) = key.split_once('*')?;
+        let matched = subpath.strip_prefix(prefix)?.strip_suffix(suffix)?;
+        match_conditions(target, conditions).map(|target| target.replace('*', matched))
+      })
+    }
+    _ if subpath == "." => match_conditions(exports, conditions),
+    _ => None,
+  }
+}
+
+fn match_conditions(target: &Value, conditions: &[String]) -> Option<String> {
+  match target {
+    Value::String(target) => Some(target.clone()),
+    Value::Array(targets) => targets
+      .iter()
+      .find_map(|target| match_conditions(target, conditions)),
+    Value::Object(map) => map.iter().find_map(|(condition, target)| {
+      (condition == "default" || conditions.contains(condition))
+        .then(|| match_conditions(target, conditions))
+        .flatten()
+    }),
+    _ => None,
+  }
+}
+
+fn join(base: &str, request: &str) -> String {
+  let mut segments: Vec<&str> = if request.starts_with('/') {
+    Vec::new()
+  } else {
+    base
+      .split('/')
+      .filter(|segment| !segment.is_empty())
+      .collect()
+  };
+  for segment in request.split('/') {
+    match segment {
+      "" | "." => {}
+      ".." => {
+        segments.pop();
+      }
+      segment => segments.push(segment),
+    }
+  }
+  format!("/{}", segments.join("/"))
+}
+
+fn parent(path: &str) -> Option<&str> {
+  match path.rsplit_once('/')? {
+    ("", "") => None,
+    ("", _) => Some("/"),
+    (parent, _) => Some(parent),
+  }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,58 @@
+use serde_json::json;
+
+use super::*;
+
+fn resolve(tree: &PackageTree, conditions: &[&str], request: &str) -> Option<String> {
+  let options = Resolve {
+    condition_names: Some(conditions.iter().map(|c| c.to_string()).collect()),
+    exports_fields: Some(vec![vec!["exports".to_string()]]),
+    ..Default::default()
+  };
+  tree.resolve(&options, "/app", request).unwrap().path
+}
+
+#[test]
+fn conditions_in_exports_order() {
+  let tree = PackageTree::new()
+    .with_package_json(
+      "/app/node_modules/import-first",
+      json!({ "exports": { "import": "./index.mjs", "require": "./index.cjs" } }),
+    )
+    .with_file("/app/node_modules/import-first/index.mjs", "")
+    .with_file("/app/node_modules/import-first/index.cjs", "")
+    .with_package_json(
+      "/app/node_modules/default-first",
+      json!({ "exports": { "default": "./index.js", "react-server": "./server.js" } }),
+    )
+    .with_file("/app/node_modules/default-first/index.js", "")
+    .with_file("/app/node_modules/default-first/server.js", "")
+    .with_package_json(
+      "/app/node_modules/nested",
+      json!({ "exports": { "node": { "import": "./node.mjs" }, "default": "./index.js" } }),
+    )
+    .with_file("/app/node_modules/nested/node.mjs", "")
+    .with_file("/app/node_modules/nested/index.js", "");
+
+  // The order of the exports object decides, not the one of the conditions.
+  assert_eq!(
+    resolve(&tree, &["require", "import"], "import-first").as_deref(),
+    Some("/app/node_modules/import-first/index.mjs")
+  );
+  assert_eq!(
+    resolve(&tree, &["require"], "import-first").as_deref(),
+    Some("/app/node_modules/import-first/index.cjs")
+  );
+  assert_eq!(
+    resolve(&tree, &["react-server", "node"], "default-first").as_deref(),
+    Some("/app/node_modules/default-first/index.js")
+  );
+  // Nested conditions that don't match fall through to the next key.
+  assert_eq!(
+    resolve(&tree, &["node", "require"], "nested").as_deref(),
+    Some("/app/node_modules/nested/index.js")
+  );
+  assert_eq!(
+    resolve(&tree, &["node", "import"], "nested").as_deref(),
+    Some("/app/node_modules/nested/node.mjs")
+  );
+}
@@ -0,0 +1,97 @@
+use super::*;
+
+fn napi_options(
+  compiler_type: &str,
+  esm_externals: Option<Either<String, bool>>,
+  next_version: Option<&str>,
+) -> NapiNextExternalsPluginOptions {
+  NapiNextExternalsPluginOptions {
+    compiler_type: compiler_type.to_string(),
+    config: NapiNextConfigComplete {
+      experimental: NapiExperimentalConfig {
+        esm_externals,
+        loose_esm_externals_packages: None,
+        strict_esm_externals_packages: None,
+      },
+      bundle_pages_router_dependencies: None,
+      server_external_packages: None,
+    },
+    builtin_modules: vec![],
+    opt_out_bundling_package_regex: None,
+    default_server_external_packages: None,
+    final_transpile_packages: vec![],
+    dir: "/app".to_string(),
+    default_overrides: FxHashMap::default(),
+    next_version: next_version.map(str::to_string),
+    layers: None,
+    pnp: None,
+    runtime_roots: None,
+    trace: None,
+    log_stats: None,
+    edge_runtime: None,
+    shared_context_id: None,
+    rules: None,
+    fallback: None,
+  }
+}
+
+fn error_of(options: NapiNextExternalsPluginOptions) -> napi::Error {
+  NextExternalsPluginOptions::try_from(options).expect_err("options should be invalid")
+}
+
+#[test]
+fn valid_options() {
+  let options = NextExternalsPluginOptions::try_from(napi_options(
+    "server",
+    Some(Either::A("loose".to_string())),
+    Some("14.2.3"),
+  ))
+  .unwrap();
+  assert_eq!(options.compiler_type, CompilerType::Server);
+  assert!(options.config.experimental.esm_externals.is_loose());
+  assert_eq!(options.next_version, "14".parse().unwrap());
+}
+
+#[test]
+fn unknown_compiler_type() {
+  let error = error_of(napi_options("node", None, None));
+  assert_eq!(error.status, Status::InvalidArg);
+  assert_eq!(
+    error.reason,
+    r#"Invalid compilerType "node", expected one of "client", "server", "edge-server""#
+  );
+}
+
+#[test]
+fn invalid_esm_externals() {
+  let error = error_of(napi_options(
+    "server",
+    Some(Either::A("strict".to_string())),
+    None,
+  ));
+  assert_eq!(error.status, Status::InvalidArg);
+  assert!(
+    error
+      .reason
+      .contains(r#"Invalid value "strict" for experimental.esmExternals"#),
+    "{}",
+    error.reason
+  );
+  assert!(
+    error
+      .reason
+      .contains(r#"Valid options are: true, false, "loose""#),
+    "{}",
+    error.reason
+  );
+}
+
+#[test]
+fn unsupported_next_version() {
+  let error = error_of(napi_options("server", None, Some("13.5.6")));
+  assert_eq!(error.status, Status::InvalidArg);
+  assert_eq!(
+    error.reason,
+    r#"Unsupported nextVersion "13.5.6", expected a version of Next.js 14, 15"#
+  );
+}
@@ -0,0 +1,130 @@
+//! The groups of Next.js layers the externals depend on, mirroring
+//! `WEBPACK_LAYERS.GROUP`, so newer Next.js releases can pass their own.
+
+use rustc_hash::FxHashSet;
+
+use crate::next_version::NextVersion;
+
+/// Layers of the app directory, whose React packages are bundled, in
+/// Next.js 15.
+pub const WEBPACK_BUNDLED_LAYERS: &[&str] = &[
+  "rsc",
+  "action-browser",
+  "ssr",
+  "app-pages-browser",
+  "shared",
+  "instrument",
+  "middleware",
+];
+
+/// Layers resolved with the `react-server` condition in Next.js 15.
+pub const WEBPACK_SERVER_ONLY_LAYERS: &[&str] =
+  &["rsc", "action-browser", "instrument", "middleware"];
+
+/// `WEBPACK_LAYERS.GROUP.app` of Next.js 14.2, which had no middleware layer
+/// in the app directory yet.
+pub const NEXT_14_BUNDLED_LAYERS: &[&str] = &[
+  "rsc",
+  "action-browser",
+  "app-metadata-route",
+  "app-route-handler",
+  "ssr",
+  "app-pages-browser",
+  "shared",
+  "instrument",
+];
+
+/// `WEBPACK_LAYERS.GROUP.server` of Next.js 14.2.
+pub const NEXT_14_SERVER_ONLY_LAYERS: &[&str] = &[
+  "rsc",
+  "action-browser",
+  "app-metadata-route",
+  "app-route-handler",
+  "instrument",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WebpackLayers {
+  bundled: Vec<String>,
+  server_only: Vec<String>,
+}
+
+impl Default for WebpackLayers {
+  fn default() -> Self {
+    Self::for_version(NextVersion::default())
+  }
+}
+
+impl WebpackLayers {
+  /// The layers of a Next.js release.
+  pub fn for_version(version: NextVersion) -> Self {
+    let to_strings = |layers: &[&str]| layers.iter().map(|layer| layer.to_string()).collect();
+    Self {
+      bundled: to_strings(version.bundled_layers()),
+      server_only: to_strings(version.server_only_layers()),
+    }
+  }
+
+  /// Validates the groups, each of them defaulting to the layers of the
+  /// Next.js release.
+  pub fn new(
+    version: NextVersion,
+    bundled: Option<Vec<String>>,
+    server_only: Option<Vec<String>>,
+  ) -> Result<Self, String> {
+    let defaults = Self::for_version(version);
+    let layers = Self {
+      bundled: bundled.unwrap_or(defaults.bundled),
+      server_only: server_only.unwrap_or(defaults.server_only),
+    };
+    validate_group("bundled", &layers.bundled)?;
+    validate_group("serverOnly", &layers.server_only)?;
+    if let Some(layer) = layers
+      .server_only
+      .iter()
+      .find(|layer| !layers.bundled.contains(layer))
+    {
+      return Err(format!(
+        "Invalid layers, the serverOnly layer \"{layer}\" is not a bundled layer"
+      ));
+    }
+    Ok(layers)
+  }
+
+  pub fn is_bundled(&self, layer: Option<&str>) -> bool {
+    layer.is_some_and(|layer| self.bundled.iter().any(|bundled| bundled == layer))
+  }
+
+  pub fn is_server_only(&self, layer: Option<&str>) -> bool {
+    layer.is_some_and(|layer| {
+      self
+        .server_only
+        .iter()
+        .any(|server_only| server_only == layer)
+    })
+  }
+}
+
+/// Layer names are non-empty and made of lowercase letters, digits and `-`,
+/// like the ones of Next.js.
+fn validate_group(group: &str, layers: &[String]) -> Result<(), String> {
+  let mut seen = FxHashSet::default();
+  for layer in layers {
+    let is_valid = layer.starts_with(|c: char| c.is_ascii_lowercase())
+      && layer
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !is_valid {
+      return Err(format!(
+        "Invalid layer name \"{layer}\" in layers.{group}, expected lowercase letters, digits and \"-\""
+      ));
+    }
+    if !seen.insert(layer.as_str()) {
+      return Err(format!("Duplicate layer \"{layer}\" in layers.{group}"));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,58 @@
+use super::*;
+
+fn layers(layers: &[&str]) -> Option<Vec<String>> {
+  Some(layers.iter().map(|layer| layer.to_string()).collect())
+}
+
+#[test]
+fn defaults() {
+  let layers = WebpackLayers::new(NextVersion::V15, None, None).unwrap();
+  assert_eq!(layers, WebpackLayers::default());
+  assert!(layers.is_bundled(Some("ssr")));
+  assert!(!layers.is_server_only(Some("ssr")));
+  assert!(layers.is_server_only(Some("rsc")));
+  assert!(!layers.is_bundled(Some("api-node")));
+  assert!(!layers.is_bundled(None));
+  assert!(!layers.is_server_only(None));
+}
+
+#[test]
+fn groups_default_separately() {
+  let layers = WebpackLayers::new(
+    NextVersion::V15,
+    layers(&[
+      "rsc",
+      "action-browser",
+      "ssr",
+      "app-pages-browser",
+      "shared",
+      "instrument",
+      "middleware",
+      "app-edge-ssr",
+    ]),
+    None,
+  )
+  .unwrap();
+  assert!(layers.is_bundled(Some("app-edge-ssr")));
+  assert!(layers.is_server_only(Some("middleware")));
+}
+
+#[test]
+fn validation() {
+  assert_eq!(
+    WebpackLayers::new(NextVersion::V15, layers(&["rsc", "Rsc"]), layers(&[])),
+    Err(
+      "Invalid layer name \"Rsc\" in layers.bundled, expected lowercase letters, digits and \"-\""
+        .to_string()
+    )
+  );
+  assert!(WebpackLayers::new(NextVersion::V15, layers(&[""]), layers(&[])).is_err());
+  assert_eq!(
+    WebpackLayers::new(NextVersion::V15, layers(&["rsc", "rsc"]), layers(&[])),
+    Err("Duplicate layer \"rsc\" in layers.bundled".to_string())
+  );
+  assert_eq!(
+    WebpackLayers::new(NextVersion::V15, layers(&["ssr"]), None),
+    Err("Invalid layers, the serverOnly layer \"rsc\" is not a bundled layer".to_string())
+  );
+}
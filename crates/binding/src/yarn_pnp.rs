@@ -0,0 +1,62 @@
+//! Yarn Plug'n'Play projects have no `node_modules` directories. Packages
+//! are located with the `.pnp.cjs` manifest, in zip archives of the cache,
+//! `unplugged` directories or workspaces, and a package with peer
+//! dependencies gets a virtual path for each set of peers it is used with.
+
+use std::{
+  borrow::Cow,
+  path::{Path, PathBuf},
+};
+
+pub const PNP_MANIFEST: &str = ".pnp.cjs";
+
+/// The folders of virtual package instances, `$$virtual` before Yarn 3.
+const VIRTUAL_FOLDERS: &[&str] = &["/__virtual__/", "/$$virtual/"];
+
+/// Returns the manifest of the project `dir` belongs to, in `dir` or one of
+/// its ancestors.
+pub fn find_manifest(dir: &Path) -> Option<PathBuf> {
+  dir
+    .ancestors()
+    .map(|dir| dir.join(PNP_MANIFEST))
+    .find(|manifest| manifest.is_file())
+}
+
+/// Maps a `/`-separated path in a virtual package instance to the path it
+/// is an instance of, as Yarn's VirtualFS does: the `<hash>/<depth>` after
+/// the virtual folder go up `<depth>` directories from its parent, e.g.
+/// `.yarn/__virtual__/lib-virtual-0123/0/cache/lib-npm-1.0.0-4567.zip/node_modules/lib`
+/// is `.yarn/cache/lib-npm-1.0.0-4567.zip/node_modules/lib`.
+pub fn devirtualize(path: &str) -> Cow<'_, str> {
+  let Some((index, folder)) = VIRTUAL_FOLDERS
+    .iter()
+    .find_map(|folder| Some((path.find(folder)?, folder)))
+  else {
+    return Cow::Borrowed(path);
+  };
+  let mut segments = path[index + folder.len()..].splitn(3, '/');
+  let (Some(_hash), Some(Ok(depth))) = (
+    segments.next(),
+    segments.next().map(|depth| depth.parse::<usize>()),
+  ) else {
+    return Cow::Borrowed(path);
+  };
+  let mut target = &path[..index];
+  for _ in 0..depth {
+    target = target.rsplit_once('/').map_or("", |(parent, _)| parent);
+  }
+  let physical = match segments.next().filter(|subpath| !subpath.is_empty()) {
+    Some(subpath) => format!("{target}/{subpath}"),
+    None => target.to_string(),
+  };
+  Cow::Owned(devirtualize(&physical).into_owned())
+}
+
+/// Whether two resolved paths are the same file of a package, even when
+/// resolved through different virtual instances of it.
+pub fn is_same_file(a: &str, b: &str) -> bool {
+  a == b || devirtualize(a) == devirtualize(b)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,64 @@
+use std::fs;
+
+use super::*;
+
+#[test]
+fn virtual_paths() {
+  assert_eq!(
+    devirtualize(
+      "/repo/.yarn/__virtual__/lib-virtual-0123/0/cache/lib-npm-1.0.0-4567.zip/node_modules/lib/index.js"
+    ),
+    "/repo/.yarn/cache/lib-npm-1.0.0-4567.zip/node_modules/lib/index.js"
+  );
+  // Workspaces are instantiated from the project root.
+  assert_eq!(
+    devirtualize("/repo/.yarn/__virtual__/ui-virtual-89ab/1/packages/ui/index.js"),
+    "/repo/packages/ui/index.js"
+  );
+  assert_eq!(
+    devirtualize("/repo/.yarn/$$virtual/lib-virtual-0123/0/unplugged/lib-npm-1.0.0-4567"),
+    "/repo/.yarn/unplugged/lib-npm-1.0.0-4567"
+  );
+  assert_eq!(
+    devirtualize("/repo/.yarn/__virtual__/lib-virtual-0123/1"),
+    "/repo"
+  );
+}
+
+#[test]
+fn physical_paths() {
+  for path in [
+    "/repo/.yarn/cache/lib-npm-1.0.0-4567.zip/node_modules/lib/index.js",
+    "/repo/node_modules/lib/index.js",
+    "/repo/.yarn/__virtual__/lib-virtual-0123/index.js",
+  ] {
+    assert!(matches!(devirtualize(path), Cow::Borrowed(_)), "{path}");
+  }
+}
+
+#[test]
+fn same_file_of_virtual_instances() {
+  assert!(is_same_file(
+    "/repo/.yarn/__virtual__/lib-virtual-0123/0/cache/lib.zip/node_modules/lib/index.js",
+    "/repo/.yarn/__virtual__/lib-virtual-4567/0/cache/lib.zip/node_modules/lib/index.js",
+  ));
+  assert!(is_same_file(
+    "/repo/.yarn/__virtual__/lib-virtual-0123/0/cache/lib.zip/node_modules/lib/index.js",
+    "/repo/.yarn/cache/lib.zip/node_modules/lib/index.js",
+  ));
+  assert!(!is_same_file(
+    "/repo/.yarn/__virtual__/lib-virtual-0123/0/cache/lib-1.zip/node_modules/lib/index.js",
+    "/repo/.yarn/__virtual__/lib-virtual-0123/0/cache/lib-2.zip/node_modules/lib/index.js",
+  ));
+}
+
+#[test]
+fn manifest_of_ancestors() {
+  let dir = tempfile::tempdir().unwrap();
+  let app = dir.path().join("apps/web");
+  fs::create_dir_all(&app).unwrap();
+  assert_eq!(find_manifest(&app), None);
+
+  fs::write(dir.path().join(PNP_MANIFEST), "").unwrap();
+  assert_eq!(find_manifest(&app), Some(dir.path().join(PNP_MANIFEST)));
+}
@@ -1,5 +1,8 @@
 import * as RspackCore from '@rspack/core';
-import { NapiNextExternalsPluginOptions } from '@next-rspack/binding';
+import {
+  NapiNextExternalsPluginOptions,
+  NapiNextExternalsStats,
+} from '@next-rspack/binding';
 
 declare class NextExternalsPlugin {
   /**
@@ -10,6 +13,11 @@ declare class NextExternalsPlugin {
 
 declare const core: typeof RspackCore & {
   NextExternalsPlugin: typeof NextExternalsPlugin;
+  /**
+   * The externals counters of the compiler with the given name, or the
+   * compiler type if it has no name.
+   */
+  getNextExternalsStats(compilerName: string): NapiNextExternalsStats | null;
 };
 
 export = core;
@@ -20,4 +20,8 @@ Object.defineProperty(core, 'NextExternalsPlugin', {
   value: NextExternalsPlugin,
 });
 
+Object.defineProperty(core, 'getNextExternalsStats', {
+  value: binding.getNextExternalsStats,
+});
+
 module.exports = core;